@@ -1,20 +1,31 @@
+use crate::archive::Archive;
+use crate::download::{self, Download};
+use crate::html;
 use crate::model::MetadataEntry;
-use crate::state::AppState;
+use crate::search::{self, AttachmentMatch, Facets, SortField, SortOrder};
+use crate::query::{Expr, QueryError};
+use crate::metadata_db::{self, Filter, Selection, Sort};
+use crate::state::{AppData, AppState, Metadata};
 use axum::{
     extract::{Path as AxumPath, State, Query},
-    http::{header, StatusCode},
+    http::{HeaderMap, StatusCode},
     response::{IntoResponse, Json, Response},
 } ;
 use std::sync::atomic::Ordering;
 use base64::Engine;
-use mail_parser::{MessageParser, MimeHeaders};
+use mail_parser::{Message, MessageParser, MessagePart, MimeHeaders};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use rfd::FileDialog;
+use rusqlite::Connection;
 use serde::Deserialize;
-use std::io::Read;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::fs;
+use std::fs::File;
+use std::io::Write;
+use zip::result::ZipError;
 
 static RE_RFC2047: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"(?i)=\?([^?]+)\?([QB])\?([^?]*)\?=").unwrap());
@@ -24,8 +35,10 @@ static RE_MALFORMED: Lazy<Regex> =
 
 // --- DTOs ---
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 pub struct SearchQuery {
+    /// Gmail-style search string, e.g. `from:alice has:attachment -label:Spam`.
+    pub q: Option<String>,
     pub any: Option<String>,
     pub sender: Option<String>,
     pub subject: Option<String>,
@@ -33,6 +46,14 @@ pub struct SearchQuery {
     pub has_attachment: Option<bool>,
     pub date_from: Option<String>,
     pub date_to: Option<String>,
+    /// Defaults to date. `relevance` orders text searches by BM25 rank.
+    pub sort_by: Option<SortField>,
+    /// Defaults to descending for date and relevance, ascending otherwise.
+    pub order: Option<SortOrder>,
+    /// Return only the first matching message per conversation.
+    pub group_by_thread: Option<bool>,
+    /// Include label/sender/date/attachment counts of the whole result set.
+    pub facets: Option<bool>,
     pub limit: Option<usize>,
     pub offset: Option<usize>,
 }
@@ -41,6 +62,20 @@ pub struct SearchQuery {
 pub struct SearchResult {
     total: usize,
     messages: Vec<MetadataEntry>,
+    /// Highlighted match fragments (HTML) by message id for text searches.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    highlights: HashMap<String, String>,
+    /// Attachments whose text matched, by message id, for text searches.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    attachment_matches: HashMap<String, Vec<AttachmentMatch>>,
+    /// Number of messages per thread id of the returned rows, with `group_by_thread`.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    thread_counts: HashMap<String, usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    facets: Option<Facets>,
+    /// Text filters were matched as substrings of subject and sender because
+    /// the FTS index was not available, see `text_fallback`.
+    text_fallback: bool,
 }
 
 // --- Handlers ---
@@ -49,20 +84,19 @@ pub async fn get_labels(State(state): State<AppState>) -> impl IntoResponse {
     if state.is_loading.load(Ordering::SeqCst) {
         return StatusCode::SERVICE_UNAVAILABLE.into_response();
     }
-    let data = state.data.lock().unwrap();
+    let data = state.snapshot();
     let filter_labels = data.settings.filter_labels.as_ref();
 
     let mut filtered_labels: Vec<String> = data
-        .labels
-        .iter()
-        .filter(|&label| {
+        .labels()
+        .into_iter()
+        .filter(|label| {
             if let Some(filters) = filter_labels {
                 !filters.contains(label)
             } else {
                 true
             }
         })
-        .cloned()
         .collect();
 
     if !filtered_labels.iter().any(|l| l == "Alle Mails") {
@@ -72,164 +106,338 @@ pub async fn get_labels(State(state): State<AppState>) -> impl IntoResponse {
     Json(filtered_labels).into_response()
 }
 
-pub async fn search_messages(
-    State(state): State<AppState>,
-    Json(query): Json<SearchQuery>,
-) -> impl IntoResponse {
-    if state.is_loading.load(Ordering::SeqCst) {
-        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+/// Parses the Gmail-style search string (`q`) of a query.
+pub(crate) fn parse_q(query: &SearchQuery) -> Result<Option<Expr>, QueryError> {
+    query
+        .q
+        .as_deref()
+        .map(str::trim)
+        .filter(|q| !q.is_empty())
+        .map(crate::query::parse)
+        .transpose()
+}
+
+/// Whether special labels (Spam, ...) are searched explicitly.
+fn searching_special(data: &AppData, query: &SearchQuery, expr: Option<&Expr>) -> bool {
+    let special_labels = data.settings.special_labels.as_deref().unwrap_or(&[]);
+    search::searching_special(query, &data.settings)
+        || expr
+            .map(|e| e.selects_label(special_labels))
+            .unwrap_or(false)
+}
+
+/// Whether text filters of `query` or text terms of the resolved `expr` were not
+/// answered by the FTS index and fell back to substring matching on the metadata.
+fn text_fallback(data: &AppData, query: &SearchQuery, expr: Option<&Expr>) -> bool {
+    if let Metadata::Database { .. } = data.metadata {
+        return false;
     }
-    let limit = query.limit.unwrap_or(50);
-    let offset = query.offset.unwrap_or(0);
+    expr.is_some_and(Expr::scans_text)
+        || (search::build_fts_query(query).is_some() && data.db().is_none())
+}
 
-    let data = state.data.lock().unwrap();
+/// `query` and `expr` as a selection on metadata.db, for metadata that is read
+/// from the database (`Metadata::Database`).
+pub(crate) fn sql_selection(
+    conn: &Connection,
+    data: &AppData,
+    query: &SearchQuery,
+    expr: Option<&Expr>,
+    group_by_thread: bool,
+) -> Selection {
+    let sort_by = query.sort_by.unwrap_or(SortField::Date);
+    let order = query.order.unwrap_or(sort_by.default_order());
+    let attachment_index = search::has_attachment_index(conn);
+
+    let mut filter = Filter::default();
+    let special = searching_special(data, query, expr);
+    search::filters_sql(query, &data.settings, special, attachment_index, &mut filter);
+    if let Some(expr) = expr {
+        let condition = expr.sql(&mut filter, attachment_index);
+        filter.push(condition);
+    }
 
-    // In-Memory Search (Fallback)
-    // Filter metadata
-    let filtered: Vec<&MetadataEntry> = data
-        .metadata
-        .iter()
-        .filter(|entry| {
-            // Basis-Filter für spezielle Labels (Spam, Papierkorb, Gesendet)
-            // Diese werden grundsätzlich ausgeschlossen, außer der User hat explizit
-            // eines dieser Labels in der Sidebar oder im Label-Dropdown ausgewählt.
-            let searching_special = query
-                .label
-                .as_ref()
-                .map(|l| {
-                    data.settings
-                        .special_labels
-                        .as_ref()
-                        .map(|s| s.contains(l))
-                        .unwrap_or(false)
-                })
-                .unwrap_or(false);
+    // Same rank as in `select_messages`
+    let rank_query = if sort_by == SortField::Relevance {
+        expr.and_then(Expr::rank_query)
+            .or_else(|| search::build_fts_query(query))
+    } else {
+        None
+    };
+    Selection {
+        filter,
+        sort: match rank_query {
+            Some(rank_query) => Sort::Rank(rank_query, order),
+            None => Sort::Field(sort_by, order),
+        },
+        group_by_thread,
+    }
+}
 
-            if !searching_special {
-                if let Some(entry_labels) = &entry.gmail_labels {
-                    if let Some(special) = &data.settings.special_labels {
-                        if entry_labels.iter().any(|l| special.contains(l)) {
-                            return false;
-                        }
-                    }
+/// All messages matching the filters of `query` and `expr`, sorted as requested.
+/// `expr` has its text terms resolved against the FTS index.
+pub(crate) fn select_messages<'a>(
+    data: &'a AppData,
+    query: &SearchQuery,
+    mut expr: Option<&mut Expr>,
+) -> Vec<(usize, Cow<'a, MetadataEntry>)> {
+    let loaded = match &data.metadata {
+        Metadata::Loaded(loaded) => loaded,
+        Metadata::Database { .. } => {
+            let Some(conn) = data.db() else {
+                return Vec::new();
+            };
+            let selection = sql_selection(&conn, data, query, expr.as_deref(), false);
+            return match metadata_db::select(&conn, &selection, 0, None) {
+                Ok(rows) => rows
+                    .into_iter()
+                    .map(|(idx, entry)| (idx, Cow::Owned(entry)))
+                    .collect(),
+                Err(e) => {
+                    eprintln!("Search in metadata.db failed: {}", e);
+                    Vec::new()
                 }
-            }
+            };
+        }
+    };
 
-            // Label Filter
-            if let Some(label) = &query.label {
-                if !label.is_empty() && label != "Alle Mails" {
-                    if let Some(labels) = &entry.gmail_labels {
-                        if !labels.contains(label) {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
+    // Text filters go through the FTS5 index of metadata.db if it is loaded
+    let sort_by = query.sort_by.unwrap_or(SortField::Date);
+    let order = query.order.unwrap_or(sort_by.default_order());
+    let ranked = sort_by == SortField::Relevance;
+    let metadata_index = &loaded.index;
+    // Released before the metadata is read, which may need the connection too
+    let db = data.db();
+    let candidates = db.as_deref().and_then(|conn| {
+        let fts_query = search::build_fts_query(query)?;
+        let found = search::fts_candidates(conn, &fts_query, metadata_index, ranked)
+            .and_then(|mut indices| {
+                // Free text also finds messages by the text of their attachments,
+                // ranked after the direct hits
+                let extra =
+                    search::attachment_text_candidates(conn, query, metadata_index)?;
+                let known: HashSet<usize> = indices.iter().copied().collect();
+                indices.extend(extra.into_iter().filter(|idx| !known.contains(idx)));
+                if !ranked {
+                    indices.sort_unstable();
                 }
+                Ok(indices)
+            });
+        match found {
+            Ok(indices) => Some(indices),
+            Err(e) => {
+                eprintln!("FTS query failed, falling back to scan: {}", e);
+                None
             }
+        }
+    });
 
-            // Subject Filter
-            if let Some(subject) = &query.subject {
-                if !subject.is_empty() {
-                    let q = subject.to_lowercase();
-                    if !entry
-                        .subject
-                        .as_ref()
-                        .map(|s| s.to_lowercase().contains(&q))
-                        .unwrap_or(false)
-                    {
-                        return false;
-                    }
-                }
-            }
+    let mut rank_order = None;
+    if let (Some(expr), Some(conn)) = (expr.as_deref_mut(), db.as_deref()) {
+        if let Err(e) = expr.resolve_fts(conn, metadata_index) {
+            eprintln!("FTS query failed, falling back to scan: {}", e);
+        }
+        if ranked {
+            rank_order = expr
+                .rank_query()
+                .and_then(|rq| search::fts_candidates(conn, &rq, metadata_index, true).ok());
+        }
+    }
+    drop(db);
 
-            // Attachment Filter
-            if let Some(has_att) = query.has_attachment {
-                if has_att && !entry.has_attachment {
-                    return false;
-                }
-            }
+    let searching_special = searching_special(data, query, expr.as_deref());
 
-            // Date Filters
-            if let Some(from) = &query.date_from {
-                if !from.is_empty() {
-                    if let Some(entry_date) = &entry.date_sent_iso {
-                        if entry_date < from {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                }
-            }
-            if let Some(to) = &query.date_to {
-                if !to.is_empty() {
-                    if let Some(entry_date) = &entry.date_sent_iso {
-                        if entry_date > to {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                }
-            }
+    let check_text = candidates.is_none();
+    let candidates_ranked = ranked && candidates.is_some();
+    let accept = |idx: usize, entry: &MetadataEntry| {
+        search::matches_filters(entry, query, &data.settings, searching_special, check_text)
+            && expr.as_deref().map(|e| e.matches(idx, entry)).unwrap_or(true)
+    };
 
-            // Any Filter (Full Text Search - Subject/Sender)
-            if let Some(any) = &query.any {
-                if !any.is_empty() {
-                    let q = any.to_lowercase();
-                    let subject_match = entry
-                        .subject
-                        .as_ref()
-                        .map(|s| s.to_lowercase().contains(&q))
-                        .unwrap_or(false);
-                    let sender_match = entry
-                        .sender_name
-                        .as_ref()
-                        .map(|s| s.to_lowercase().contains(&q))
-                        .unwrap_or(false)
-                        || entry
-                            .sender_address
-                            .as_ref()
-                            .map(|s| s.to_lowercase().contains(&q))
-                            .unwrap_or(false);
-                    if !subject_match && !sender_match {
-                        return false;
-                    }
-                }
-            }
+    let mut filtered: Vec<(usize, Cow<MetadataEntry>)> = match candidates {
+        Some(indices) => indices
+            .into_iter()
+            .filter_map(|idx| loaded.entries.get(idx).map(|entry| (idx, Cow::Borrowed(entry))))
+            .filter(|(idx, entry)| accept(*idx, entry))
+            .collect(),
+        // Full scan (fallback)
+        None => loaded
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(idx, entry)| accept(*idx, entry))
+            .map(|(idx, entry)| (idx, Cow::Borrowed(entry)))
+            .collect(),
+    };
 
-            // Sender Filter
-            if let Some(sender) = &query.sender {
-                if !sender.is_empty() {
-                    let q = sender.to_lowercase();
-                    let sender_match = entry
-                        .sender_name
-                        .as_ref()
-                        .map(|s| s.to_lowercase().contains(&q))
-                        .unwrap_or(false)
-                        || entry
-                            .sender_address
-                            .as_ref()
-                            .map(|s| s.to_lowercase().contains(&q))
-                            .unwrap_or(false);
-                    if !sender_match {
-                        return false;
-                    }
-                }
-            }
+    if let Some(rank_order) = rank_order {
+        let position: HashMap<usize, usize> =
+            rank_order.into_iter().enumerate().map(|(pos, idx)| (idx, pos)).collect();
+        filtered.sort_by_key(|(idx, _)| position.get(idx).copied().unwrap_or(usize::MAX));
+        if order == SortOrder::Asc {
+            filtered.reverse();
+        }
+    } else if candidates_ranked {
+        // Already in BM25 order from the FTS query
+        if order == SortOrder::Asc {
+            filtered.reverse();
+        }
+    } else {
+        loaded.sort_keys.sort(&mut filtered, sort_by, order);
+    }
 
-            true
-        })
-        .collect();
+    filtered
+}
+
+/// Number of results, the entries from `offset` on and, if requested, the facets
+/// of all results. Metadata read from the database is selected in SQL, so only
+/// the page is parsed.
+fn select_page(
+    data: &AppData,
+    query: &SearchQuery,
+    expr: Option<&mut Expr>,
+    offset: usize,
+    limit: usize,
+) -> rusqlite::Result<(usize, Vec<MetadataEntry>, Option<Facets>)> {
+    let group_by_thread = query.group_by_thread.unwrap_or(false);
+    let hidden = data.settings.filter_labels.as_deref().unwrap_or(&[]);
+
+    if let Metadata::Database { .. } = data.metadata {
+        let Some(conn) = data.db() else {
+            return Ok((0, Vec::new(), None));
+        };
+        let selection = sql_selection(&conn, data, query, expr.as_deref(), group_by_thread);
+        let facets = if query.facets.unwrap_or(false) {
+            Some(metadata_db::facets(&conn, &selection.filter, hidden)?)
+        } else {
+            None
+        };
+        let total = metadata_db::count(&conn, &selection)?;
+        let paged = metadata_db::select(&conn, &selection, offset, Some(limit))?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect();
+        return Ok((total, paged, facets));
+    }
+
+    let mut filtered = select_messages(data, query, expr);
+
+    let facets = if query.facets.unwrap_or(false) {
+        Some(search::compute_facets(&filtered, hidden))
+    } else {
+        None
+    };
+
+    // One row per conversation; messages without thread id stand alone
+    if group_by_thread {
+        let mut seen = std::collections::HashSet::new();
+        filtered.retain(|(_, entry)| match &entry.thread_id {
+            Some(thread_id) => seen.insert(thread_id.clone()),
+            None => true,
+        });
+    }
 
     let total = filtered.len();
-    let mut paged: Vec<MetadataEntry> = filtered
+    let paged = filtered
         .into_iter()
         .skip(offset)
         .take(limit)
-        .cloned()
+        .map(|(_, entry)| entry.into_owned())
         .collect();
+    Ok((total, paged, facets))
+}
+
+pub async fn search_messages(
+    State(state): State<AppState>,
+    Json(query): Json<SearchQuery>,
+) -> impl IntoResponse {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let limit = query.limit.unwrap_or(50);
+    let offset = query.offset.unwrap_or(0);
+
+    let expr = match parse_q(&query) {
+        Ok(expr) => expr,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    let data = state.snapshot();
+    // SQLite queries and sorting block
+    tokio::task::spawn_blocking(move || search_page(&data, &query, expr, offset, limit))
+        .await
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+/// One page of search results with facets, thread sizes and match fragments.
+fn search_page(
+    data: &AppData,
+    query: &SearchQuery,
+    mut expr: Option<Expr>,
+    offset: usize,
+    limit: usize,
+) -> Response {
+    let (total, mut paged, facets) = match select_page(data, query, expr.as_mut(), offset, limit)
+    {
+        Ok(page) => page,
+        Err(e) => {
+            eprintln!("Search failed: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let text_fallback = text_fallback(data, query, expr.as_ref());
+
+    let mut thread_counts = HashMap::new();
+    if query.group_by_thread.unwrap_or(false) {
+        for entry in &paged {
+            if let Some(thread_id) = &entry.thread_id {
+                let count = data.thread_size(thread_id);
+                thread_counts.insert(thread_id.clone(), count);
+            }
+        }
+    }
+
+    // Match fragments for the current page
+    let mut highlights = HashMap::new();
+    let mut attachment_matches = HashMap::new();
+    if let Some(conn) = data.db().as_deref() {
+        let highlight_query = [
+            search::build_fts_query(query),
+            expr.as_ref().and_then(|e| e.rank_query()),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|q| format!("({})", q))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+
+        if !highlight_query.is_empty() {
+            let ids: Vec<&str> = paged.iter().map(|e| e.id.as_str()).collect();
+            match search::fts_highlights(conn, &highlight_query, &ids) {
+                Ok(h) => highlights = h,
+                Err(e) => eprintln!("FTS highlight failed: {}", e),
+            }
+        }
+
+        let attachment_query = [
+            search::build_attachment_fts_query(query),
+            expr.as_ref().and_then(|e| e.text_query()),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|q| format!("({})", q))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+
+        if !attachment_query.is_empty() {
+            let ids: Vec<&str> = paged.iter().map(|e| e.id.as_str()).collect();
+            match search::attachment_highlights(conn, &attachment_query, &ids) {
+                Ok(m) => attachment_matches = m,
+                Err(e) => eprintln!("FTS attachment highlight failed: {}", e),
+            }
+        }
+    }
 
     // Labels für die Anzeige filtern (filter_labels beachten)
     if let Some(filters) = &data.settings.filter_labels {
@@ -243,60 +451,155 @@ pub async fn search_messages(
     Json(SearchResult {
         total,
         messages: paged,
+        highlights,
+        attachment_matches,
+        thread_counts,
+        facets,
+        text_fallback,
     })
     .into_response()
 }
 
+pub async fn get_thread(
+    State(state): State<AppState>,
+    AxumPath(thread_id): AxumPath<String>,
+) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let data = state.snapshot();
+    tokio::task::spawn_blocking(move || thread_json(&data, thread_id))
+        .await
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+fn thread_json(data: &AppData, thread_id: String) -> Response {
+    let mut messages: Vec<MetadataEntry> = match data.thread(&thread_id) {
+        Some(members) => members.into_iter().map(Cow::into_owned).collect(),
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    // Filter labels to hide configured ones
+    if let Some(filters) = &data.settings.filter_labels {
+        for entry in &mut messages {
+            if let Some(labels) = &mut entry.gmail_labels {
+                labels.retain(|l| !filters.contains(l));
+            }
+        }
+    }
+
+    Json(serde_json::json!({
+        "thread_id": thread_id,
+        "messages": messages,
+    }))
+    .into_response()
+}
+
+/// Remote content is loaded on request or for trusted senders.
+pub(crate) fn remote_allowed(data: &AppData, id: &str, requested: bool) -> bool {
+    requested
+        || data
+            .entry_by_id(id)
+            .and_then(|m| m.sender_address.clone())
+            .is_some_and(|a| data.settings.is_trusted_sender(&a))
+}
+
+pub(crate) struct MessageBody {
+    pub text: String,
+    pub is_html: bool,
+    /// Remote images or stylesheets were removed from the HTML.
+    pub remote_blocked: bool,
+}
+
+/// Body to display: the sanitized HTML part if there is one, otherwise the text part.
+/// `cid:` references are left for the caller to resolve.
+pub(crate) fn message_body(message: &Message, allow_remote: bool) -> MessageBody {
+    if let Some(body) = message.body_html(0) {
+        let sanitized = html::sanitize(&body, allow_remote);
+        return MessageBody {
+            text: sanitized.html,
+            is_html: true,
+            remote_blocked: sanitized.remote_blocked,
+        };
+    }
+    MessageBody {
+        text: message
+            .body_text(0)
+            .map(|s| s.into_owned())
+            .unwrap_or_else(|| "[Kein Inhalt]".to_string()),
+        is_html: false,
+        remote_blocked: false,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MessageQuery {
+    /// Load remote images and stylesheets in HTML bodies.
+    pub allow_remote: Option<bool>,
+}
+
 pub async fn get_message(
     State(state): State<AppState>,
     AxumPath(id): AxumPath<String>,
+    Query(params): Query<MessageQuery>,
 ) -> Result<Response, StatusCode> {
     if state.is_loading.load(Ordering::SeqCst) {
         return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response());
     }
-    let mut data = state.data.lock().unwrap();
+    let data = state.snapshot();
+    let requested_remote = params.allow_remote.unwrap_or(false);
+    // Reading the archive and parsing MIME block
+    tokio::task::spawn_blocking(move || message_json(&data, id, requested_remote))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+}
 
-    let (body, is_html, attachments) = {
-        let archive = data.zip_archive.as_mut().ok_or(StatusCode::NOT_FOUND)?;
+/// Message body, attachment list and header fields for the reader.
+fn message_json(data: &AppData, id: String, requested_remote: bool) -> Result<Response, StatusCode> {
+    let allow_remote = remote_allowed(data, &id, requested_remote);
 
-        let mut eml_file = archive.by_name(&id).map_err(|_| StatusCode::NOT_FOUND)?;
-        let mut buffer = Vec::new();
-        eml_file
-            .read_to_end(&mut buffer)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (body, is_html, attachments, remote_content_blocked) = {
+        let mut archive = data.archive().ok_or(StatusCode::NOT_FOUND)?;
+
+        let buffer = read_archive_eml(&mut archive, &id)?;
 
         // Parse EML
         let message = MessageParser::default()
             .parse(&buffer)
             .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
 
-        // Extract body (HTML/Text)
-        let body = message
-            .body_html(0)
-            .map(|s| s.into_owned())
-            .or_else(|| message.body_text(0).map(|s| s.into_owned()))
-            .unwrap_or_else(|| "[Kein Inhalt]".to_string());
-
-        let is_html = message.body_html(0).is_some();
+        let MessageBody {
+            text: body,
+            is_html,
+            remote_blocked: remote_content_blocked,
+        } = message_body(&message, allow_remote);
+
+        // Inline images (cid:) are served by get_inline_part
+        let body = if is_html {
+            html::rewrite_cid_urls(&body, &id)
+        } else {
+            body
+        };
 
         // Create JSON for attachments
         let attachments: Vec<serde_json::Value> = message
             .attachments()
-            .map(|a| {
+            .enumerate()
+            .map(|(index, a)| {
                 serde_json::json!({
-                    "filename": a.attachment_name().unwrap_or("unnamed"),
-                    "content_type": a.content_type().as_ref().map(|c| c.c_type.to_string()),
+                    "index": index,
+                    "filename": attachment_filename(a),
+                    "content_type": part_content_type(a),
                     "content_id": a.content_id().as_ref()
                 })
             })
             .collect();
 
-        (body, is_html, attachments)
+        (body, is_html, attachments, remote_content_blocked)
     };
 
-    // Release mutable borrow of data by using fields directly
-    let entry_idx = data.metadata_index.get(&id);
-    let entry = entry_idx.and_then(|&idx| data.metadata.get(idx));
+    let entry = data.entry_by_id(&id);
+    let entry = entry.as_deref();
 
     let mut labels = entry
         .and_then(|m| m.gmail_labels.clone())
@@ -337,8 +640,10 @@ pub async fn get_message(
         "from": decode_header_robust(&from_str),
         "to": decode_header_robust(&to_str),
         "date": date_str,
+        "thread_id": entry.and_then(|m| m.thread_id.clone()),
         "body": body,
         "is_html": is_html,
+        "remote_content_blocked": remote_content_blocked,
         "attachments": attachments,
         "labels": labels,
         "gmail_labels": labels
@@ -346,63 +651,268 @@ pub async fn get_message(
     .into_response())
 }
 
-pub async fn download_attachment(
+/// Reads the raw EML of a message with a reader of its own; needs no lock.
+pub(crate) fn read_eml(state: &AppState, id: &str) -> Result<Vec<u8>, StatusCode> {
+    let mut archive = state.snapshot().archive().ok_or(StatusCode::NOT_FOUND)?;
+    read_archive_eml(&mut archive, id)
+}
+
+/// Reads a message from the archive, restoring deduplicated attachments.
+pub(crate) fn read_archive_eml(
+    archive: &mut Archive,
+    id: &str,
+) -> Result<Vec<u8>, StatusCode> {
+    mbox2zip::dedup::read_message(archive, id).map_err(|e| match e {
+        ZipError::FileNotFound => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct RawQuery {
+    /// Send as a file download instead of inline text.
+    pub download: Option<bool>,
+}
+
+/// The converter keeps the mbox `From ` separator line at the top of each EML.
+pub(crate) fn strip_mbox_from_line(eml: &[u8]) -> &[u8] {
+    if eml.starts_with(b"From ") {
+        match eml.iter().position(|&b| b == b'\n') {
+            Some(pos) => &eml[pos + 1..],
+            None => &[],
+        }
+    } else {
+        eml
+    }
+}
+
+/// Original RFC 822 source of a message as stored in the archive.
+pub async fn get_raw_message(
     State(state): State<AppState>,
-    AxumPath((id, filename)): AxumPath<(String, String)>,
+    AxumPath(id): AxumPath<String>,
+    Query(params): Query<RawQuery>,
+    headers: HeaderMap,
 ) -> Response {
     if state.is_loading.load(Ordering::SeqCst) {
         return StatusCode::SERVICE_UNAVAILABLE.into_response();
     }
-    let mut data = match state.data.lock() {
-        Ok(d) => d,
-        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-    };
+    let etag = download::etag(&state, &[&id, "raw"]);
 
-    let archive = match data.zip_archive.as_mut() {
-        Some(a) => a,
-        None => return StatusCode::NOT_FOUND.into_response(),
+    // Shown as text in the browser unless explicitly downloaded
+    let (content_type, disposition) = if params.download.unwrap_or(false) {
+        ("message/rfc822", content_disposition("attachment", &id))
+    } else {
+        ("text/plain; charset=utf-8", "inline".to_string())
     };
 
-    let mut eml_file = match archive.by_name(&id) {
-        Ok(f) => f,
-        Err(_) => return StatusCode::NOT_FOUND.into_response(),
-    };
+    download::serve(&headers, etag, move |file| {
+        let buffer = read_eml(&state, &id)?;
+        file.write_all(strip_mbox_from_line(&buffer))
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok(Download {
+            content_type: content_type.to_string(),
+            content_disposition: disposition,
+        })
+    })
+    .await
+}
 
-    let mut buffer = Vec::new();
-    if eml_file.read_to_end(&mut buffer).is_err() {
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+/// All header fields of a message in their original order, including repeated
+/// fields like `Received`.
+pub async fn get_message_headers(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
     }
+    tokio::task::spawn_blocking(move || message_headers(&state, id))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+}
 
-    let message = match MessageParser::default().parse(&buffer) {
-        Some(m) => m,
-        None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-    };
+fn message_headers(state: &AppState, id: String) -> Result<Json<serde_json::Value>, StatusCode> {
+    let buffer = read_eml(state, &id)?;
 
-    for attachment in message.attachments() {
-        let att_name = attachment.attachment_name().unwrap_or("");
-        if att_name == filename {
-            let content_type = attachment
-                .content_type()
-                .as_ref()
-                .map(|c| c.c_type.to_string())
-                .unwrap_or_else(|| "application/octet-stream".to_string());
-            let att_data = attachment.contents().to_vec();
-
-            return (
-                [
-                    (header::CONTENT_TYPE, content_type),
-                    (
-                        header::CONTENT_DISPOSITION,
-                        format!("attachment; filename=\"{}\"", filename),
-                    ),
-                ],
-                att_data,
-            )
-                .into_response();
-        }
+    let message = MessageParser::default()
+        .parse(&buffer)
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let raw = message.raw_message();
+    let headers: Vec<serde_json::Value> = message
+        .headers()
+        .iter()
+        // Field names cannot contain spaces; this skips the mbox `From ` line
+        .filter(|h| !h.name.as_str().contains(' '))
+        .map(|h| {
+            let value = raw
+                .get(h.offset_start..h.offset_end)
+                .map(String::from_utf8_lossy)
+                .unwrap_or_default();
+            serde_json::json!({
+                "name": h.name.as_str(),
+                "value": decode_header_robust(value.trim()),
+            })
+        })
+        .collect();
+
+    Ok(Json(serde_json::json!({ "id": id, "headers": headers })))
+}
+
+/// Serves a MIME part by its Content-ID, used for `cid:` images in HTML bodies.
+/// Also finds inline parts without a file name.
+pub async fn get_inline_part(
+    State(state): State<AppState>,
+    AxumPath((id, content_id)): AxumPath<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
     }
+    let wanted = html::normalize_cid(&content_id).to_string();
+    let etag = download::etag(&state, &[&id, "cid", &wanted]);
+    download::serve(&headers, etag, move |file| {
+        let buffer = read_eml(&state, &id)?;
+        let message = MessageParser::default()
+            .parse(&buffer)
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let part = message
+            .parts
+            .iter()
+            .find(|p| p.content_id().map(html::normalize_cid) == Some(wanted.as_str()))
+            .ok_or(StatusCode::NOT_FOUND)?;
+        file.write_all(part.contents())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    StatusCode::NOT_FOUND.into_response()
+        let content_type = part_content_type(part);
+        // Only raster images are rendered inline; SVG can carry script and is
+        // offered as download like anything else
+        let content_disposition = if is_inline_image(&content_type) {
+            "inline".to_string()
+        } else {
+            content_disposition("attachment", attachment_filename(part))
+        };
+        Ok(Download {
+            content_type,
+            content_disposition,
+        })
+    })
+    .await
+}
+
+/// Image types `get_inline_part` serves inline.
+fn is_inline_image(content_type: &str) -> bool {
+    matches!(
+        content_type.to_ascii_lowercase().as_str(),
+        "image/png" | "image/jpeg" | "image/pjpeg" | "image/gif" | "image/webp" | "image/bmp"
+    )
+}
+
+/// Full MIME type of a part, e.g. `image/png`.
+pub(crate) fn part_content_type(part: &MessagePart) -> String {
+    part.content_type()
+        .map(|c| match &c.c_subtype {
+            Some(sub) => format!("{}/{}", c.c_type, sub),
+            None => c.c_type.to_string(),
+        })
+        .unwrap_or_else(|| "application/octet-stream".to_string())
+}
+
+pub(crate) fn attachment_filename<'a>(part: &'a MessagePart) -> &'a str {
+    part.attachment_name().unwrap_or("unnamed")
+}
+
+/// Content-Disposition with an ASCII fallback name and the exact name as RFC 6266 `filename*`.
+pub(crate) fn content_disposition(disposition: &str, filename: &str) -> String {
+    // Path separators and control characters never belong in a saved file name
+    let filename: String = filename
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' => c,
+            _ => '_',
+        })
+        .collect();
+
+    if fallback == filename {
+        format!("{}; filename=\"{}\"", disposition, filename)
+    } else {
+        format!(
+            "{}; filename=\"{}\"; filename*=UTF-8''{}",
+            disposition,
+            fallback,
+            html::encode_path_segment(&filename)
+        )
+    }
+}
+
+/// Writes the decoded contents of an attachment to a download file.
+pub(crate) fn write_attachment(file: &mut File, part: &MessagePart) -> Result<Download, StatusCode> {
+    file.write_all(part.contents())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Download {
+        content_type: part_content_type(part),
+        content_disposition: content_disposition("attachment", attachment_filename(part)),
+    })
+}
+
+/// Parses message `id` and writes the first attachment selected by `select`.
+pub(crate) fn spool_attachment(
+    state: &AppState,
+    id: &str,
+    file: &mut File,
+    select: impl FnOnce(&Message) -> Option<usize>,
+) -> Result<Download, StatusCode> {
+    let buffer = read_eml(state, id)?;
+    let message = MessageParser::default()
+        .parse(&buffer)
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    let index = select(&message).ok_or(StatusCode::NOT_FOUND)?;
+    let part = message.attachments().nth(index).ok_or(StatusCode::NOT_FOUND)?;
+    write_attachment(file, part)
+}
+
+/// Legacy lookup by file name; ambiguous for duplicate or missing names.
+pub async fn download_attachment(
+    State(state): State<AppState>,
+    AxumPath((id, filename)): AxumPath<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let etag = download::etag(&state, &[&id, "attachment-name", &filename]);
+    download::serve(&headers, etag, move |file| {
+        spool_attachment(&state, &id, file, |message| {
+            message
+                .attachments()
+                .position(|a| a.attachment_name().unwrap_or("") == filename)
+        })
+    })
+    .await
+}
+
+/// Serves the attachment at `index` in the `attachments` list returned by `get_message`.
+pub async fn download_attachment_by_index(
+    State(state): State<AppState>,
+    AxumPath((id, index)): AxumPath<(String, usize)>,
+    headers: HeaderMap,
+) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let etag = download::etag(&state, &[&id, "attachment", &index.to_string()]);
+    download::serve(&headers, etag, move |file| {
+        spool_attachment(&state, &id, file, |_| Some(index))
+    })
+    .await
 }
 
 pub fn decode_header_robust(value: &str) -> String {
@@ -470,13 +980,13 @@ pub fn decode_header_robust(value: &str) -> String {
 }
 
 pub async fn get_system_info(State(state): State<AppState>) -> Json<serde_json::Value> {
-    let data = state.data.lock().unwrap();
+    let data = state.snapshot();
     let instance_id = state.instance_id.lock().unwrap();
 
     Json(serde_json::json!({
         "instance_id": *instance_id,
         "zip_path": data.settings.zip_path,
-        "db_loaded": data.db_conn.is_some(),
+        "db_loaded": data.db_pool.is_some(),
         "is_loading": state.is_loading.load(Ordering::SeqCst),
         "settings_path": data.settings.source_path.as_ref().map(|p| p.to_string_lossy().to_string()),
         "browser": data.settings.browser,
@@ -713,8 +1223,8 @@ pub async fn convert_mbox(
             status.current_message = msg_count;
             status.total_bytes = total_bytes;
             status.total_bytes_read = bytes_read;
-            if total_bytes > 0 {
-                status.progress_percent = (bytes_read * 100 / total_bytes) as u8;
+            if let Some(percent) = (bytes_read * 100).checked_div(total_bytes) {
+                status.progress_percent = percent as u8;
             }
         };
 
@@ -765,7 +1275,7 @@ pub async fn update_settings(
     Json(req): Json<SettingsUpdateRequest>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let mut settings = {
-        let data = state.data.lock().unwrap();
+        let data = state.snapshot();
         data.settings.clone()
     };
 
@@ -784,10 +1294,13 @@ pub async fn update_settings(
     std::fs::write(&settings_path, toml_string)
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
-    // Hot reload
-    match crate::load_all_data(Some(settings_path), None) {
+    // Hot reload; reading the archive and filling the cache blocks
+    let loaded = tokio::task::spawn_blocking(move || crate::load_all_data(Some(settings_path), None))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    match loaded {
         Ok(raw) => {
-            state.apply_new_data(raw.settings.clone(), raw.metadata, raw.db_conn, raw.archive);
+            state.apply_new_data(raw.settings.clone(), raw.metadata, raw.db_conns, raw.archive, raw.fingerprint);
 
             // Notify launcher to update UI
             if let Some(tx) = state.log_tx.lock().unwrap().as_ref() {
@@ -811,10 +1324,14 @@ pub async fn restart_with_settings(
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let next_path = PathBuf::from(req.settings_path);
 
-    // Hot reload from new toml
-    match crate::load_all_data(Some(next_path.clone()), None) {
+    // Hot reload from new toml; reading the archive and filling the cache blocks
+    let settings_path = next_path.clone();
+    let loaded = tokio::task::spawn_blocking(move || crate::load_all_data(Some(settings_path), None))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    match loaded {
         Ok(raw) => {
-            state.apply_new_data(raw.settings.clone(), raw.metadata, raw.db_conn, raw.archive);
+            state.apply_new_data(raw.settings.clone(), raw.metadata, raw.db_conns, raw.archive, raw.fingerprint);
 
             // Notify launcher to persist the new path and update UI
             if let Some(tx) = state.log_tx.lock().unwrap().as_ref() {
@@ -867,6 +1384,9 @@ pub async fn create_settings(
         filter_labels: Some(req.filter_labels),
         special_labels: Some(req.special_labels),
         browser: None,
+        trusted_sender_domains: None,
+        lazy_metadata: None,
+        cache_dir: None,
         source_path: Some(PathBuf::from(&req.toml_path)),
     };
 
@@ -0,0 +1,70 @@
+//! Concurrent read access to the MBXC archive.
+//!
+//! `ArchiveFile` reads with positional I/O and keeps its own cursor, so clones of
+//! one `Archive` share the file handle and the parsed central directory but can be
+//! read from different threads at the same time without a lock.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+use zip::result::ZipResult;
+use zip::ZipArchive;
+
+pub type Archive = ZipArchive<ArchiveFile>;
+
+#[derive(Clone)]
+pub struct ArchiveFile {
+    file: Arc<File>,
+    len: u64,
+    pos: u64,
+}
+
+impl ArchiveFile {
+    pub fn new(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self {
+            file: Arc::new(file),
+            len,
+            pos: 0,
+        })
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    // Moves the shared cursor, which no reader relies on
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+impl Read for ArchiveFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = read_at(&self.file, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ArchiveFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = target.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Opens an MBXC archive for shared reading.
+pub fn open(path: &Path) -> ZipResult<Archive> {
+    ZipArchive::new(ArchiveFile::new(File::open(path)?)?)
+}
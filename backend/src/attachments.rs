@@ -0,0 +1,307 @@
+//! Attachments across the whole archive, listed from the metadata without
+//! parsing any EML, and downloads by content hash.
+
+use crate::api::{self, SearchQuery};
+use crate::download::{self, Download};
+use crate::model::AttachmentMetadata;
+use crate::search::SortOrder;
+use crate::state::{AppData, AppState};
+use axum::{
+    extract::{Path as AxumPath, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::atomic::Ordering;
+use zip::result::ZipError;
+
+/// MIME type/family and extension filter for attachments.
+pub(crate) struct AttachmentFilter {
+    mime: Option<String>,
+    extensions: Vec<String>,
+}
+
+impl AttachmentFilter {
+    pub(crate) fn new(mime: Option<String>, extension: Option<String>) -> Self {
+        Self {
+            mime: mime
+                .map(|m| m.trim().to_lowercase())
+                .filter(|m| !m.is_empty()),
+            extensions: extension
+                .unwrap_or_default()
+                .split(',')
+                .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+                .filter(|e| !e.is_empty())
+                .collect(),
+        }
+    }
+
+    /// `mime` may be just the family (`application`), as archive metadata stores it;
+    /// then only the family is compared.
+    pub(crate) fn matches(&self, filename: &str, mime: &str) -> bool {
+        let mime = mime.to_lowercase();
+        let family = |m: &str| m.split('/').next().unwrap_or("").to_string();
+        let mime_ok = match &self.mime {
+            Some(wanted) if wanted.contains('/') && mime.contains('/') => mime == *wanted,
+            Some(wanted) => family(&mime) == family(wanted),
+            None => true,
+        };
+        let ext_ok = self.extensions.is_empty()
+            || filename
+                .rsplit_once('.')
+                .is_some_and(|(_, ext)| self.extensions.contains(&ext.to_lowercase()));
+        mime_ok && ext_ok
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentSortField {
+    Date,
+    Size,
+    Filename,
+}
+
+#[derive(Deserialize)]
+pub struct AttachmentQuery {
+    /// MIME type or family, e.g. `application/pdf` or `image`.
+    pub mime: Option<String>,
+    /// File extensions without dot, comma separated, e.g. `pdf,docx`.
+    pub extension: Option<String>,
+    /// Part of the file name, case-insensitive.
+    pub filename: Option<String>,
+    /// Content hash; finds every copy of the same file.
+    pub sha256: Option<String>,
+    pub sender: Option<String>,
+    pub label: Option<String>,
+    pub date_from: Option<String>,
+    pub date_to: Option<String>,
+    /// Defaults to date.
+    pub sort_by: Option<AttachmentSortField>,
+    /// Defaults to ascending for file names, descending otherwise.
+    pub order: Option<SortOrder>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct AttachmentItem {
+    pub message_id: String,
+    /// Index for `/messages/:id/attachment/by-index/:index`.
+    pub index: usize,
+    pub filename: Option<String>,
+    pub mime: String,
+    pub size: usize,
+    pub sha256: Option<String>,
+    pub date: Option<String>,
+    pub sender_name: Option<String>,
+    pub sender_address: Option<String>,
+    pub subject: Option<String>,
+    pub thread_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AttachmentList {
+    pub total: usize,
+    pub attachments: Vec<AttachmentItem>,
+}
+
+pub async fn list_attachments(
+    State(state): State<AppState>,
+    Query(query): Query<AttachmentQuery>,
+) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let data = state.snapshot();
+    // Selecting and sorting the messages blocks
+    tokio::task::spawn_blocking(move || Json(attachment_list(&data, query)).into_response())
+        .await
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+/// The attachments of all messages that pass `query`, sorted and paged.
+fn attachment_list(data: &AppData, query: AttachmentQuery) -> AttachmentList {
+    let sort_by = query.sort_by.unwrap_or(AttachmentSortField::Date);
+    let order = query.order.unwrap_or(match sort_by {
+        AttachmentSortField::Filename => SortOrder::Asc,
+        _ => SortOrder::Desc,
+    });
+    let filter = AttachmentFilter::new(query.mime, query.extension);
+    let name_filter = query
+        .filename
+        .map(|f| f.trim().to_lowercase())
+        .filter(|f| !f.is_empty());
+    let sha256 = query
+        .sha256
+        .map(|h| h.trim().to_lowercase())
+        .filter(|h| !h.is_empty());
+
+    // Message-level filters work exactly like the search
+    let message_query = SearchQuery {
+        sender: query.sender,
+        label: query.label,
+        date_from: query.date_from,
+        date_to: query.date_to,
+        has_attachment: Some(true),
+        order: Some(order),
+        ..Default::default()
+    };
+
+    let messages = crate::api::select_messages(data, &message_query, None);
+
+    let mut found: Vec<_> = messages
+        .iter()
+        .flat_map(|(_, entry)| {
+            let entry = entry.as_ref();
+            entry
+                .attachments
+                .iter()
+                .flatten()
+                .enumerate()
+                .map(move |(index, a)| (entry, index, a))
+        })
+        .filter(|(_, _, a)| {
+            let name = a.filename.as_deref().unwrap_or("");
+            filter.matches(name, &a.mime)
+                && name_filter
+                    .as_deref()
+                    .is_none_or(|wanted| name.to_lowercase().contains(wanted))
+                && sha256
+                    .as_deref()
+                    .is_none_or(|wanted| a.sha256.as_deref() == Some(wanted))
+        })
+        .collect();
+
+    // Messages are already in date order, which stays the tie-breaker
+    let directed = |ord: std::cmp::Ordering| match order {
+        SortOrder::Asc => ord,
+        SortOrder::Desc => ord.reverse(),
+    };
+    match sort_by {
+        AttachmentSortField::Date => {}
+        AttachmentSortField::Size => {
+            found.sort_by(|(_, _, a), (_, _, b)| directed(a.size.cmp(&b.size)));
+        }
+        AttachmentSortField::Filename => {
+            let name = |a: &AttachmentMetadata| a.filename.as_deref().unwrap_or("").to_lowercase();
+            found.sort_by(|(_, _, a), (_, _, b)| directed(name(a).cmp(&name(b))));
+        }
+    }
+
+    let total = found.len();
+    let attachments = found
+        .into_iter()
+        .skip(query.offset.unwrap_or(0))
+        .take(query.limit.unwrap_or(50))
+        .map(|(entry, index, a)| AttachmentItem {
+            message_id: entry.id.clone(),
+            index,
+            filename: a.filename.clone(),
+            mime: a.mime.clone(),
+            size: a.size,
+            sha256: a.sha256.clone(),
+            date: entry.date_sent_iso.clone(),
+            sender_name: entry.sender_name.clone(),
+            sender_address: entry.sender_address.clone(),
+            subject: entry.subject.clone(),
+            thread_id: entry.thread_id.clone(),
+        })
+        .collect();
+
+    AttachmentList { total, attachments }
+}
+
+/// Serves an attachment by content hash: from `attachments/<sha256>` in
+/// deduplicated archives, otherwise from the first message carrying it.
+pub async fn download_attachment_by_hash(
+    State(state): State<AppState>,
+    AxumPath(sha256): AxumPath<String>,
+    headers: HeaderMap,
+) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let sha256 = sha256.to_lowercase();
+
+    let found = {
+        let data = state.snapshot();
+        data.entry_with_attachment(&sha256).and_then(|entry| {
+            entry
+                .attachments
+                .iter()
+                .flatten()
+                .enumerate()
+                .find(|(_, a)| a.sha256.as_deref() == Some(sha256.as_str()))
+                .map(|(index, a)| (entry.id.clone(), index, a.clone()))
+        })
+    };
+    let Some((id, index, attachment)) = found else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let etag = download::content_etag(&sha256);
+    download::serve(&headers, Some(etag), move |file| {
+        let mut archive = state.snapshot().archive().ok_or(StatusCode::NOT_FOUND)?;
+        let copied = match archive.by_name(&mbox2zip::dedup::blob_name(&sha256)) {
+            Ok(mut blob) => io::copy(&mut blob, file)
+                .map(|_| true)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+            Err(ZipError::FileNotFound) => false,
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+        if !copied {
+            return api::spool_attachment(&state, &id, file, |_| Some(index));
+        }
+        let filename = attachment.filename.as_deref().unwrap_or("unnamed");
+        Ok(Download {
+            content_type: attachment.mime,
+            content_disposition: api::content_disposition("attachment", filename),
+        })
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(mime: Option<&str>, extension: Option<&str>) -> AttachmentFilter {
+        AttachmentFilter::new(mime.map(String::from), extension.map(String::from))
+    }
+
+    #[test]
+    fn matches_mime_type_or_family() {
+        let pdf = filter(Some(" Application/PDF "), None);
+        assert!(pdf.matches("a.pdf", "application/pdf"));
+        assert!(!pdf.matches("a.zip", "application/zip"));
+        // Older archives only store the family
+        assert!(pdf.matches("a.zip", "application"));
+        assert!(!pdf.matches("a.png", "image"));
+
+        let images = filter(Some("image"), None);
+        assert!(images.matches("a.png", "image/png"));
+        assert!(images.matches("a.jpg", "IMAGE"));
+        assert!(!images.matches("a.pdf", "application/pdf"));
+
+        let all = filter(Some("  "), Some(""));
+        assert!(all.matches("", "application/octet-stream"));
+    }
+
+    #[test]
+    fn matches_any_of_several_extensions() {
+        let office = filter(None, Some("pdf, .DOCX,,xlsx"));
+        assert!(office.matches("Bericht.PDF", "application/pdf"));
+        assert!(office.matches("brief.final.docx", "application/octet-stream"));
+        assert!(office.matches("liste.xlsx", "application"));
+        assert!(!office.matches("pdf", "application/pdf"));
+        assert!(!office.matches("archiv.pdf.zip", "application/zip"));
+
+        // Both filters must match
+        let pdf_images = filter(Some("image"), Some("pdf,png"));
+        assert!(pdf_images.matches("scan.png", "image/png"));
+        assert!(!pdf_images.matches("scan.pdf", "application/pdf"));
+        assert!(!pdf_images.matches("scan.jpg", "image/jpeg"));
+    }
+}
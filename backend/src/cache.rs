@@ -0,0 +1,251 @@
+//! Per-archive cache of data derived from an MBXC file.
+//!
+//! Every archive gets its own directory, named after a hash of its path, with the
+//! extracted metadata.db, the parsed metadata.json in bincode format and a
+//! `fingerprint` file (see `metadata_fingerprint`); if it no longer matches, the
+//! directory is emptied and filled again on demand.
+
+use crate::archive::Archive;
+use crate::model::MetadataEntry;
+use crate::settings::Settings;
+use mbox2zip::dedup::sha256_hex;
+use rusqlite::{Connection, OpenFlags};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Increase when the format of the cached files changes, e.g. new fields in
+/// `MetadataEntry`; older caches are then rebuilt.
+const CACHE_VERSION: u32 = 1;
+
+const FINGERPRINT_FILE: &str = "fingerprint";
+const DATABASE_FILE: &str = "metadata.db";
+const METADATA_FILE: &str = "metadata.bin";
+
+/// Cache location if `Settings::cache_dir` is not set.
+pub fn default_dir() -> PathBuf {
+    #[cfg(target_os = "windows")]
+    let base = std::env::var_os("LOCALAPPDATA").map(PathBuf::from);
+    #[cfg(target_os = "macos")]
+    let base = std::env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join("Library").join("Caches"));
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")));
+
+    base.unwrap_or_else(std::env::temp_dir)
+        .join("eml_viewer")
+        .join("cache")
+}
+
+/// Identifies the archive at `zip_path` and its current state from metadata:
+/// path, size and modification time of the file plus the CRCs and sizes that the
+/// ZIP directory records for metadata.json and metadata.db.
+///
+/// This is not a hash of the content, which would mean reading the whole archive
+/// on every start. It changes whenever the archive is converted or appended to,
+/// but not if a file with the same size and modification time and unchanged
+/// metadata files is put in its place.
+pub fn metadata_fingerprint(archive: &mut Archive, zip_path: &Path) -> Result<String> {
+    let meta = fs::metadata(zip_path)?;
+    let mtime = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+
+    // Both files are rewritten on every conversion and append
+    let mut content = String::new();
+    for name in ["metadata.json", "metadata.db"] {
+        match archive.by_name(name) {
+            Ok(file) => content.push_str(&format!("{}:{:08x}:{};", name, file.crc32(), file.size())),
+            Err(_) => content.push_str(&format!("{}:-;", name)),
+        }
+    }
+
+    Ok(format!(
+        "version={}\npath={}\nsize={}\nmtime={}\ncontent={}\n",
+        CACHE_VERSION,
+        zip_path.display(),
+        meta.len(),
+        mtime,
+        sha256_hex(content.as_bytes())
+    ))
+}
+
+/// Writes a file through a temporary file that is renamed into place when
+/// complete, so an interrupted write leaves no partial cache file.
+fn write_atomic(path: &Path, write: impl FnOnce(&mut dyn Write) -> Result<()>) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    {
+        let mut out = BufWriter::new(temp.as_file_mut());
+        write(&mut out)?;
+        out.flush()?;
+    }
+    temp.persist(path)?;
+    Ok(())
+}
+
+pub struct ArchiveCache {
+    dir: PathBuf,
+    fingerprint: String,
+}
+
+impl ArchiveCache {
+    /// Opens the cache directory of the archive at `zip_path`, clearing it if it
+    /// belongs to an older state of the archive.
+    pub fn open(settings: &Settings, archive: &mut Archive, zip_path: &Path) -> Result<Self> {
+        let zip_path = zip_path
+            .canonicalize()
+            .unwrap_or_else(|_| zip_path.to_path_buf());
+        let base = settings
+            .cache_dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(default_dir);
+        let dir = base.join(&sha256_hex(zip_path.to_string_lossy().as_bytes())[..32]);
+
+        let current = metadata_fingerprint(archive, &zip_path)?;
+        let fingerprint_path = dir.join(FINGERPRINT_FILE);
+        if fs::read_to_string(&fingerprint_path).ok().as_deref() != Some(current.as_str()) {
+            match fs::remove_dir_all(&dir) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            fs::create_dir_all(&dir)?;
+            fs::write(&fingerprint_path, &current)?;
+        }
+
+        Ok(Self {
+            dir,
+            fingerprint: current,
+        })
+    }
+
+    /// The `metadata_fingerprint` of the archive.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// The archive's metadata.db, opened read-only in place. It is extracted on
+    /// first use only.
+    pub fn database(&self, archive: &mut Archive) -> Result<Connection> {
+        let path = self.dir.join(DATABASE_FILE);
+        if !path.exists() {
+            let mut db_file = archive.by_name("metadata.db")?;
+            write_atomic(&path, |out| {
+                io::copy(&mut db_file, out)?;
+                Ok(())
+            })?;
+        }
+
+        Ok(Connection::open_with_flags(
+            &path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?)
+    }
+
+    /// Metadata stored by `store_metadata`, `None` if there is none yet.
+    pub fn load_metadata(&self) -> Option<Vec<MetadataEntry>> {
+        let file = File::open(self.dir.join(METADATA_FILE)).ok()?;
+        bincode::deserialize_from(BufReader::new(file))
+            .map_err(|e| eprintln!("Invalid metadata cache: {}", e))
+            .ok()
+    }
+
+    pub fn store_metadata(&self, entries: &[MetadataEntry]) -> Result<()> {
+        write_atomic(&self.dir.join(METADATA_FILE), |out| {
+            bincode::serialize_into(out, entries)?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    fn write_archive(path: &Path, metadata: &str) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        zip.start_file("metadata.json", FileOptions::default())
+            .unwrap();
+        zip.write_all(metadata.as_bytes()).unwrap();
+        zip.finish().unwrap();
+    }
+
+    fn settings(cache_dir: &Path) -> Settings {
+        Settings {
+            zip_path: String::new(),
+            filter_labels: None,
+            special_labels: None,
+            browser: None,
+            trusted_sender_domains: None,
+            lazy_metadata: None,
+            cache_dir: Some(cache_dir.to_string_lossy().into_owned()),
+            source_path: None,
+        }
+    }
+
+    fn open(settings: &Settings, zip_path: &Path) -> ArchiveCache {
+        let mut archive = crate::archive::open(zip_path).unwrap();
+        ArchiveCache::open(settings, &mut archive, zip_path).unwrap()
+    }
+
+    #[test]
+    fn keeps_cache_of_unchanged_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip_path = dir.path().join("mail.mbxc");
+        let settings = settings(&dir.path().join("cache"));
+        write_archive(&zip_path, "[]");
+
+        let cache = open(&settings, &zip_path);
+        assert!(cache.load_metadata().is_none());
+        cache.store_metadata(&[]).unwrap();
+
+        let reopened = open(&settings, &zip_path);
+        assert_eq!(reopened.fingerprint(), cache.fingerprint());
+        assert_eq!(reopened.load_metadata().map(|m| m.len()), Some(0));
+    }
+
+    #[test]
+    fn clears_cache_of_changed_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip_path = dir.path().join("mail.mbxc");
+        let settings = settings(&dir.path().join("cache"));
+        write_archive(&zip_path, "[]");
+        let cache = open(&settings, &zip_path);
+        cache.store_metadata(&[]).unwrap();
+
+        // Same size and modification time, only metadata.json differs
+        let modified = fs::metadata(&zip_path).unwrap().modified().unwrap();
+        write_archive(&zip_path, "{}");
+        File::options()
+            .write(true)
+            .open(&zip_path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let reopened = open(&settings, &zip_path);
+        assert_ne!(reopened.fingerprint(), cache.fingerprint());
+        assert!(reopened.load_metadata().is_none());
+
+        // A new modification time alone also counts as a change
+        reopened.store_metadata(&[]).unwrap();
+        File::options()
+            .write(true)
+            .open(&zip_path)
+            .unwrap()
+            .set_modified(SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        assert!(open(&settings, &zip_path).load_metadata().is_none());
+    }
+}
@@ -0,0 +1,289 @@
+//! File downloads with `ETag`/`If-None-Match` and single `Range` requests.
+//!
+//! The payload is read and decoded into an anonymous temp file on a blocking
+//! thread. The response streams from that file, which lets browsers resume and seek.
+
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
+
+/// Headers of a spooled download.
+pub(crate) struct Download {
+    pub content_type: String,
+    pub content_disposition: String,
+}
+
+/// Entity tag for content identified by `parts` in the loaded archive. Message ids
+/// are immutable within an MBXC, the archive fingerprint changes when it is rewritten.
+/// `None` if no archive is loaded.
+pub(crate) fn etag(state: &AppState, parts: &[&str]) -> Option<String> {
+    let mut key = state.snapshot().fingerprint.clone()?;
+    for part in parts {
+        key.push('\0');
+        key.push_str(part);
+    }
+    Some(format!(
+        "\"{}\"",
+        &mbox2zip::dedup::sha256_hex(key.as_bytes())[..32]
+    ))
+}
+
+/// Entity tag of content addressed by its SHA-256, valid across archives.
+pub(crate) fn content_etag(sha256: &str) -> String {
+    format!("\"{}\"", sha256)
+}
+
+/// `If-None-Match` lists `etag` (weak comparison) or is `*`.
+pub(crate) fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    let Some(value) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    value
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RangeRequest {
+    /// No usable `Range` header: the whole content is sent.
+    Full,
+    /// First and last byte (inclusive).
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a single byte range (`bytes=a-b`, `bytes=a-`, `bytes=-n`). Multiple
+/// ranges and malformed headers are ignored, as RFC 9110 allows.
+fn parse_range(value: &str, len: u64) -> RangeRequest {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeRequest::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // Suffix range: the last `n` bytes
+        let Ok(suffix) = end.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+        if suffix == 0 {
+            return RangeRequest::Unsatisfiable;
+        }
+        (len.saturating_sub(suffix), len.saturating_sub(1))
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+        let end = match end {
+            "" => len.saturating_sub(1),
+            end => match end.parse::<u64>() {
+                Ok(end) if end >= start => end.min(len.saturating_sub(1)),
+                _ => return RangeRequest::Full,
+            },
+        };
+        (start, end)
+    };
+
+    if len == 0 || range.0 >= len {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(range.0, range.1)
+    }
+}
+
+/// A `Range` is only honoured if `If-Range` is absent or names the current entity.
+fn range_request(headers: &HeaderMap, etag: Option<&str>, len: u64) -> RangeRequest {
+    let Some(range) = headers.get(header::RANGE).and_then(|v| v.to_str().ok()) else {
+        return RangeRequest::Full;
+    };
+    if let Some(if_range) = headers.get(header::IF_RANGE) {
+        if etag.is_none() || if_range.to_str().ok().map(str::trim) != etag {
+            return RangeRequest::Full;
+        }
+    }
+    parse_range(range, len)
+}
+
+/// Answers with 304 if the client already has `etag`; otherwise runs `build` on a
+/// blocking thread to fill a temp file and streams it, honouring `Range`.
+pub(crate) async fn serve<F>(headers: &HeaderMap, etag: Option<String>, build: F) -> Response
+where
+    F: FnOnce(&mut File) -> Result<Download, StatusCode> + Send + 'static,
+{
+    let etag_value = etag.as_deref().and_then(|e| HeaderValue::from_str(e).ok());
+    if let (Some(etag), Some(value)) = (etag.as_deref(), &etag_value) {
+        if not_modified(headers, etag) {
+            return (StatusCode::NOT_MODIFIED, [(header::ETAG, value.clone())]).into_response();
+        }
+    }
+
+    let spooled = tokio::task::spawn_blocking(move || {
+        let mut file = tempfile::tempfile().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let download = build(&mut file)?;
+        let len = file
+            .stream_position()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok::<_, StatusCode>((download, file, len))
+    })
+    .await;
+    let (download, mut file, len) = match spooled {
+        Ok(Ok(spooled)) => spooled,
+        Ok(Err(status)) => return status.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let (status, start, end) = match range_request(headers, etag.as_deref(), len) {
+        RangeRequest::Full => (StatusCode::OK, 0, len.saturating_sub(1)),
+        RangeRequest::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end),
+        RangeRequest::Unsatisfiable => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", len))],
+            )
+                .into_response();
+        }
+    };
+    if file.seek(SeekFrom::Start(start)).is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    let content_length = if len == 0 { 0 } else { end - start + 1 };
+    let reader = tokio::fs::File::from_std(file).take(content_length);
+
+    let mut response = (
+        status,
+        [
+            (header::CONTENT_TYPE, download.content_type),
+            (header::CONTENT_DISPOSITION, download.content_disposition),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            // Archived content must never run script on the viewer's origin
+            (
+                header::CONTENT_SECURITY_POLICY,
+                "default-src 'none'; style-src 'unsafe-inline'".to_string(),
+            ),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+            (header::CACHE_CONTROL, "private, no-cache".to_string()),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response();
+    let response_headers = response.headers_mut();
+    if let Some(etag_value) = etag_value {
+        response_headers.insert(header::ETAG, etag_value);
+    }
+    response_headers.insert(header::CONTENT_LENGTH, content_length.into());
+    if status == StatusCode::PARTIAL_CONTENT {
+        if let Ok(value) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, len)) {
+            response_headers.insert(header::CONTENT_RANGE, value);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RangeRequest::{Full, Partial, Unsatisfiable};
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Partial(0, 99));
+        assert_eq!(parse_range("bytes=500-", 1000), Partial(500, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), Partial(900, 999));
+        assert_eq!(parse_range(" bytes= 10 - 20 ", 1000), Partial(10, 20));
+        // The end is clamped to the content, a long suffix covers all of it
+        assert_eq!(parse_range("bytes=900-5000", 1000), Partial(900, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000), Partial(0, 999));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=1000-1001", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_malformed_and_multiple_ranges() {
+        assert_eq!(parse_range("bytes=0-9,20-29", 1000), Full);
+        assert_eq!(parse_range("items=0-9", 1000), Full);
+        assert_eq!(parse_range("bytes=9-0", 1000), Full);
+        assert_eq!(parse_range("bytes=a-9", 1000), Full);
+        assert_eq!(parse_range("bytes=-", 1000), Full);
+        assert_eq!(parse_range("bytes=5", 1000), Full);
+    }
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn if_range_must_name_current_entity() {
+        let etag = Some("\"abc\"");
+        let partial =
+            |headers: &HeaderMap, etag| range_request(headers, etag, 1000) == Partial(0, 9);
+
+        assert!(partial(&headers(&[(header::RANGE, "bytes=0-9")]), etag));
+        assert!(partial(
+            &headers(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, "\"abc\"")]),
+            etag
+        ));
+        // A changed entity or a date validator sends the whole content
+        assert!(!partial(
+            &headers(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, "\"old\"")]),
+            etag
+        ));
+        assert!(!partial(
+            &headers(&[
+                (header::RANGE, "bytes=0-9"),
+                (header::IF_RANGE, "Mon, 01 Jan 2024 00:00:00 GMT")
+            ]),
+            etag
+        ));
+        assert!(!partial(
+            &headers(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, "\"abc\"")]),
+            None
+        ));
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        assert!(not_modified(
+            &headers(&[(header::IF_NONE_MATCH, "\"abc\"")]),
+            "\"abc\""
+        ));
+        assert!(not_modified(
+            &headers(&[(header::IF_NONE_MATCH, "\"x\", W/\"abc\"")]),
+            "\"abc\""
+        ));
+        assert!(not_modified(
+            &headers(&[(header::IF_NONE_MATCH, "*")]),
+            "\"abc\""
+        ));
+        assert!(!not_modified(
+            &headers(&[(header::IF_NONE_MATCH, "\"x\"")]),
+            "\"abc\""
+        ));
+        assert!(!not_modified(&HeaderMap::new(), "\"abc\""));
+    }
+}
@@ -0,0 +1,557 @@
+//! Exporting selections of messages from the archive.
+
+use crate::api::{self, SearchQuery};
+use crate::attachments::AttachmentFilter;
+use crate::model::MetadataEntry;
+use crate::query::{Expr, QueryError};
+use crate::search::{SortField, SortOrder};
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Utc};
+use mail_parser::MessageParser;
+use mbox2zip::export::MboxWriter;
+use mbox2zip::metadata_export::{self, write_csv_row, MetadataFormat};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::{self, Seek, Write};
+use std::sync::atomic::Ordering;
+use tokio_util::io::ReaderStream;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+#[derive(Deserialize)]
+pub struct ExportRequest {
+    /// Same filter as `/query`; `limit` and `offset` are ignored.
+    #[serde(flatten)]
+    pub query: SearchQuery,
+    /// Explicit selection in this order; the filter is ignored if set.
+    pub ids: Option<Vec<String>>,
+}
+
+/// An export request with a parsed query. Without explicit ids and sort order,
+/// messages are exported oldest first.
+struct ExportSelection {
+    ids: Option<Vec<String>>,
+    query: SearchQuery,
+    expr: Option<Expr>,
+}
+
+impl ExportSelection {
+    fn new(req: ExportRequest) -> Result<Self, QueryError> {
+        let mut query = req.query;
+        if query.sort_by.is_none() {
+            query.sort_by = Some(SortField::Date);
+            query.order.get_or_insert(SortOrder::Asc);
+        }
+        let expr = api::parse_q(&query)?;
+        Ok(Self {
+            ids: req.ids,
+            query,
+            expr,
+        })
+    }
+
+    /// Resolves the messages; reads metadata.db in lazy mode, so call it on a
+    /// blocking thread.
+    fn entries(mut self, state: &AppState) -> Vec<MetadataEntry> {
+        let data = state.snapshot();
+
+        if let Some(ids) = self.ids {
+            return ids
+                .iter()
+                .filter_map(|id| data.entry_by_id(id).map(Cow::into_owned))
+                .collect();
+        }
+
+        api::select_messages(&data, &self.query, self.expr.as_mut())
+            .into_iter()
+            .map(|(_, entry)| entry.into_owned())
+            .collect()
+    }
+}
+
+/// Runs `write` on a blocking thread and serves its output as a file download.
+/// The output is spooled through `tempfile_download`, so a failed export answers
+/// 500 instead of ending a 200 response early.
+async fn spooled_download<F>(content_type: &str, filename: &str, write: F) -> Response
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+{
+    tempfile_download(content_type, filename, move |file| {
+        let mut out = io::BufWriter::new(file);
+        write(&mut out)?;
+        out.flush()?;
+        Ok(())
+    })
+    .await
+}
+
+/// Builds the download in an anonymous temp file (seekable, as ZIP needs) and
+/// streams it when complete.
+async fn tempfile_download<F>(content_type: &str, filename: &str, write: F) -> Response
+where
+    F: FnOnce(&mut std::fs::File) -> anyhow::Result<()> + Send + 'static,
+{
+    let built = tokio::task::spawn_blocking(move || -> anyhow::Result<std::fs::File> {
+        let mut file = tempfile::tempfile()?;
+        write(&mut file)?;
+        file.rewind()?;
+        Ok(file)
+    })
+    .await;
+
+    let file = match built {
+        Ok(Ok(file)) => file,
+        Ok(Err(e)) => {
+            eprintln!("Export failed: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let length = file.metadata().map(|m| m.len()).ok();
+
+    let mut response = (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                api::content_disposition("attachment", filename),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(tokio::fs::File::from_std(file))),
+    )
+        .into_response();
+    if let Some(length) = length {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, length.into());
+    }
+    response
+}
+
+fn entry_date(entry: &MetadataEntry) -> Option<DateTime<Utc>> {
+    entry
+        .timestamp()
+        .and_then(|ts| DateTime::from_timestamp(ts, 0))
+}
+
+/// The selected messages as an mboxrd file.
+pub async fn export_mbox(
+    State(state): State<AppState>,
+    Json(req): Json<ExportRequest>,
+) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let selection = match ExportSelection::new(req) {
+        Ok(selection) => selection,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    spooled_download("application/mbox", "export.mbox", move |out| {
+        let entries = selection.entries(&state);
+        let mut writer = MboxWriter::new(out);
+        for entry in &entries {
+            let eml = match api::read_eml(&state, &entry.id) {
+                Ok(eml) => eml,
+                Err(_) => {
+                    eprintln!("Export: {} not readable, skipped", entry.id);
+                    continue;
+                }
+            };
+            writer.write_message(&eml, entry.sender_address.as_deref(), entry_date(entry))?;
+        }
+        writer.finish().map(|_| ())
+    })
+    .await
+}
+
+/// Replaces characters that are not allowed in file names on common systems.
+fn file_name_part(value: &str, max_chars: usize) -> String {
+    let cleaned: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() || c.is_whitespace() => '_',
+            c => c,
+        })
+        .take(max_chars)
+        .collect();
+    let cleaned = cleaned.trim_matches(|c| c == '_' || c == '.');
+    let mut result = String::with_capacity(cleaned.len());
+    for c in cleaned.chars() {
+        if !(c == '_' && result.ends_with('_')) {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Decoded From header (sender_name holds the whole header).
+fn from_header(entry: &MetadataEntry) -> String {
+    let from = entry
+        .sender_name
+        .as_deref()
+        .filter(|n| !n.is_empty())
+        .or(entry.sender_address.as_deref())
+        .unwrap_or("");
+    api::decode_header_robust(from)
+}
+
+/// Display name of the sender, or the address if there is none.
+fn sender_display_name(entry: &MetadataEntry) -> String {
+    entry
+        .sender_name
+        .as_deref()
+        .map(|n| {
+            // sender_name holds the whole From header, keep the display name
+            let decoded = api::decode_header_robust(n);
+            let name = decoded.split('<').next().unwrap_or("");
+            name.trim().trim_matches('"').trim().to_string()
+        })
+        .filter(|n| !n.is_empty())
+        .or_else(|| entry.sender_address.clone())
+        .unwrap_or_default()
+}
+
+/// `YYYY-MM-DD_sender_subject`, safe to use as file name.
+fn readable_base_name(entry: &MetadataEntry) -> String {
+    let date = entry
+        .date_sent_iso
+        .as_deref()
+        .and_then(|d| d.get(..10))
+        .unwrap_or("ohne-datum");
+    let subject = entry
+        .subject
+        .as_deref()
+        .map(api::decode_header_robust)
+        .unwrap_or_default();
+
+    [
+        file_name_part(date, 10),
+        file_name_part(&sender_display_name(entry), 40),
+        file_name_part(&subject, 80),
+    ]
+    .into_iter()
+    .filter(|part| !part.is_empty())
+    .collect::<Vec<_>>()
+    .join("_")
+}
+
+/// `stem.ext`, or `stem_2.ext` etc. if the name is already in `used`.
+fn unique_name(stem: &str, ext: &str, used: &mut HashSet<String>) -> String {
+    let with_ext = |stem: &str| {
+        if ext.is_empty() {
+            stem.to_string()
+        } else {
+            format!("{}.{}", stem, ext)
+        }
+    };
+    let mut name = with_ext(stem);
+    let mut n = 2;
+    // Case-insensitive file systems treat names differing in case as equal
+    while !used.insert(name.to_lowercase()) {
+        name = with_ext(&format!("{}_{}", stem, n));
+        n += 1;
+    }
+    name
+}
+
+/// ZIP with the selected messages as `.eml` files under readable names, plus
+/// `manifest.csv` mapping them to their archive ids and headers.
+const MESSAGE_MANIFEST_HEADER: [&str; 7] =
+    ["file", "id", "date", "from", "to", "subject", "labels"];
+
+/// manifest.csv row of the EML export for the message stored as `file`.
+fn message_manifest_row(file: &str, entry: &MetadataEntry) -> [String; 7] {
+    [
+        file.to_string(),
+        entry.id.clone(),
+        entry.date_sent_iso.clone().unwrap_or_default(),
+        from_header(entry),
+        entry.to_addresses.as_deref().unwrap_or(&[]).join(", "),
+        api::decode_header_robust(entry.subject.as_deref().unwrap_or("")),
+        entry.gmail_labels.as_deref().unwrap_or(&[]).join("; "),
+    ]
+}
+
+pub async fn export_eml(State(state): State<AppState>, Json(req): Json<ExportRequest>) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let selection = match ExportSelection::new(req) {
+        Ok(selection) => selection,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    tempfile_download("application/zip", "export.zip", move |file| {
+        let entries = selection.entries(&state);
+        let mut zip = ZipWriter::new(io::BufWriter::new(file));
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+        // UTF-8 BOM so spreadsheet programs detect the encoding
+        let mut manifest = b"\xEF\xBB\xBF".to_vec();
+        write_csv_row(&mut manifest, &MESSAGE_MANIFEST_HEADER)?;
+
+        let mut used = HashSet::new();
+        for entry in &entries {
+            let eml = match api::read_eml(&state, &entry.id) {
+                Ok(eml) => eml,
+                Err(_) => {
+                    eprintln!("Export: {} not readable, skipped", entry.id);
+                    continue;
+                }
+            };
+            let name = unique_name(&readable_base_name(entry), "eml", &mut used);
+            zip.start_file(name.as_str(), options)?;
+            zip.write_all(api::strip_mbox_from_line(&eml))?;
+
+            write_csv_row(&mut manifest, &message_manifest_row(&name, entry))?;
+        }
+
+        zip.start_file("manifest.csv", options)?;
+        zip.write_all(&manifest)?;
+        zip.finish()?.flush()?;
+        Ok(())
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct MetadataExportRequest {
+    #[serde(flatten)]
+    pub export: ExportRequest,
+    /// Defaults to CSV.
+    pub format: Option<MetadataFormat>,
+}
+
+/// Metadata of the selected messages as a flat table (CSV, JSON Lines or columnar JSON).
+pub async fn export_metadata(
+    State(state): State<AppState>,
+    Json(req): Json<MetadataExportRequest>,
+) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let format = req.format.unwrap_or(MetadataFormat::Csv);
+    let selection = match ExportSelection::new(req.export) {
+        Ok(selection) => selection,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    let filename = format!("metadata.{}", format.extension());
+    spooled_download(format.content_type(), &filename, move |out| {
+        let entries = selection.entries(&state);
+        metadata_export::write_metadata(out, &entries, format).map_err(io::Error::other)
+    })
+    .await
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentFolders {
+    /// One folder per message, named like the `.eml` files of `export_eml`.
+    #[default]
+    Message,
+    /// One folder per sender address.
+    Sender,
+    /// All files in the root of the ZIP.
+    None,
+}
+
+#[derive(Deserialize)]
+pub struct AttachmentExportRequest {
+    #[serde(flatten)]
+    pub export: ExportRequest,
+    /// MIME type or family, e.g. `application/pdf` or `image`.
+    pub mime: Option<String>,
+    /// File extensions without dot, comma separated, e.g. `pdf,docx`.
+    pub extension: Option<String>,
+    pub folders: Option<AttachmentFolders>,
+}
+
+const ATTACHMENT_MANIFEST_HEADER: [&str; 9] = [
+    "file",
+    "id",
+    "attachment_index",
+    "filename",
+    "content_type",
+    "size",
+    "date",
+    "from",
+    "subject",
+];
+
+/// manifest.csv row of the attachment export for attachment `index` stored as `file`.
+fn attachment_manifest_row(
+    file: &str,
+    entry: &MetadataEntry,
+    index: usize,
+    filename: &str,
+    content_type: &str,
+    size: usize,
+) -> [String; 9] {
+    [
+        file.to_string(),
+        entry.id.clone(),
+        index.to_string(),
+        filename.to_string(),
+        content_type.to_string(),
+        size.to_string(),
+        entry.date_sent_iso.clone().unwrap_or_default(),
+        from_header(entry),
+        api::decode_header_robust(entry.subject.as_deref().unwrap_or("")),
+    ]
+}
+
+/// ZIP of all attachments of the selected messages that pass the MIME/extension
+/// filter, sorted into folders, with `manifest.csv` mapping each file to its message.
+pub async fn export_attachments(
+    State(state): State<AppState>,
+    Json(req): Json<AttachmentExportRequest>,
+) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let filter = AttachmentFilter::new(req.mime, req.extension);
+    let folders = req.folders.unwrap_or_default();
+    let selection = match ExportSelection::new(req.export) {
+        Ok(selection) => selection,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    tempfile_download("application/zip", "attachments.zip", move |file| {
+        let mut entries = selection.entries(&state);
+        // The metadata already lists the attachments, so most messages need not be read
+        entries.retain(|e| {
+            e.has_attachment
+                && e.attachments.as_ref().is_none_or(|list| {
+                    list.iter()
+                        .any(|a| filter.matches(a.filename.as_deref().unwrap_or(""), &a.mime))
+                })
+        });
+
+        let mut zip = ZipWriter::new(io::BufWriter::new(file));
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+        let mut manifest = b"\xEF\xBB\xBF".to_vec();
+        write_csv_row(&mut manifest, &ATTACHMENT_MANIFEST_HEADER)?;
+
+        let mut used_files = HashSet::new();
+        let mut used_folders = HashSet::new();
+        for entry in &entries {
+            let eml = match api::read_eml(&state, &entry.id) {
+                Ok(eml) => eml,
+                Err(_) => {
+                    eprintln!("Export: {} not readable, skipped", entry.id);
+                    continue;
+                }
+            };
+            let Some(message) = MessageParser::default().parse(&eml) else {
+                continue;
+            };
+
+            let mut folder: Option<String> = None;
+            for (index, part) in message.attachments().enumerate() {
+                let filename = api::attachment_filename(part);
+                let content_type = api::part_content_type(part);
+                if !filter.matches(filename, &content_type) {
+                    continue;
+                }
+
+                let folder = folder.get_or_insert_with(|| match folders {
+                    AttachmentFolders::Message => format!(
+                        "{}/",
+                        unique_name(&readable_base_name(entry), "", &mut used_folders)
+                    ),
+                    AttachmentFolders::Sender => {
+                        let sender = entry.sender_address.as_deref().unwrap_or("unbekannt");
+                        format!("{}/", file_name_part(sender, 80))
+                    }
+                    AttachmentFolders::None => String::new(),
+                });
+                let (stem, ext) = match filename.rsplit_once('.') {
+                    Some((stem, ext)) if !stem.is_empty() => {
+                        (file_name_part(stem, 100), file_name_part(ext, 10))
+                    }
+                    _ => (file_name_part(filename, 100), String::new()),
+                };
+                let stem = if stem.is_empty() {
+                    "anhang".to_string()
+                } else {
+                    stem
+                };
+                let path = unique_name(&format!("{}{}", folder, stem), &ext, &mut used_files);
+
+                zip.start_file(path.as_str(), options)?;
+                zip.write_all(part.contents())?;
+
+                let size = part.contents().len();
+                let row =
+                    attachment_manifest_row(&path, entry, index, filename, &content_type, size);
+                write_csv_row(&mut manifest, &row)?;
+            }
+        }
+
+        zip.start_file("manifest.csv", options)?;
+        zip.write_all(&manifest)?;
+        zip.finish()?.flush()?;
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv(row: &[String]) -> String {
+        let mut out = Vec::new();
+        write_csv_row(&mut out, row).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn message_manifest_defuses_formulas() {
+        let entry = MetadataEntry {
+            id: "m1".to_string(),
+            subject: Some("=HYPERLINK(\"http://example.com\",\"x\")".to_string()),
+            sender_name: Some("@Team".to_string()),
+            to_addresses: Some(vec!["+49 30 1234 <a@example.com>".to_string()]),
+            gmail_labels: Some(vec!["-Inbox".to_string(), "Sent".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            csv(&message_manifest_row("m1.eml", &entry)),
+            "m1.eml,m1,,'@Team,'+49 30 1234 <a@example.com>,\
+             \"'=HYPERLINK(\"\"http://example.com\"\",\"\"x\"\")\",'-Inbox; Sent\r\n"
+        );
+    }
+    #[test]
+    fn attachment_manifest_defuses_formulas() {
+        let entry = MetadataEntry {
+            id: "m2".to_string(),
+            subject: Some("-Rechnung".to_string()),
+            sender_address: Some("a@example.com".to_string()),
+            ..Default::default()
+        };
+        let row = attachment_manifest_row(
+            "a/=cmd.csv",
+            &entry,
+            1,
+            "=cmd|' /C calc'!A0.csv",
+            "text/csv",
+            12,
+        );
+        assert_eq!(
+            csv(&row),
+            "a/=cmd.csv,m2,1,'=cmd|' /C calc'!A0.csv,text/csv,12,,a@example.com,'-Rechnung\r\n"
+        );
+    }
+}
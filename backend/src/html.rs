@@ -0,0 +1,327 @@
+use once_cell::sync::Lazy;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+static RE_CID_REF: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?i)\b(src|background)\s*=\s*(["']?)cid:([^"'\s>]+)"#).unwrap()
+});
+
+static RE_STYLE_BLOCK: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?is)<style[^>]*>(.*?)</style\s*>").unwrap());
+static RE_CSS_IMPORT: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)@import[^;]*;?").unwrap());
+static RE_CSS_URL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?is)url\(\s*(?:"((?:[^"\\]|\\.)*)"|'((?:[^'\\]|\\.)*)'|([^"'()\s]*))\s*\)"#)
+        .unwrap()
+});
+/// `url(` that `RE_CSS_URL` could not parse, e.g. an unterminated string.
+static RE_CSS_URL_START: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)url\(").unwrap());
+static RE_CSS_IMAGE_SET: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)(?:-webkit-)?image-set\(").unwrap());
+static RE_CSS_EXPRESSION: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)expression\s*\(|behavior\s*:|-moz-binding").unwrap());
+
+/// Shown instead of blocked remote images.
+const BLOCKED_IMAGE: &str = "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' width='16' height='16'%3E%3Crect width='16' height='16' fill='%23e8eaed'/%3E%3C/svg%3E";
+
+/// Attributes that make the browser load a resource when the body is displayed.
+const RESOURCE_ATTRIBUTES: [&str; 3] = ["src", "background", "poster"];
+
+/// Presentational attributes commonly used by HTML mails for layout; `class` for
+/// the rules of their `<style>` blocks.
+const LAYOUT_ATTRIBUTES: [&str; 15] = [
+    "style",
+    "class",
+    "align",
+    "valign",
+    "bgcolor",
+    "background",
+    "width",
+    "height",
+    "border",
+    "cellpadding",
+    "cellspacing",
+    "color",
+    "face",
+    "size",
+    "dir",
+];
+
+pub struct SanitizedHtml {
+    pub html: String,
+    /// True if remote images or stylesheets were replaced.
+    pub remote_blocked: bool,
+}
+
+/// True unless `url` stays local: `data:`, `cid:` or relative to the viewer's own
+/// origin. Browsers skip ASCII whitespace and control characters in URLs and read
+/// `\` as `/`, so `ht&#9;tp:` or `/\host` count as remote too.
+fn is_remote_url(url: &str) -> bool {
+    let url: String = url
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace() && !c.is_ascii_control())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if url.starts_with("data:") || url.starts_with("cid:") {
+        return false;
+    }
+    if url.starts_with("//") || url.contains('\\') {
+        return true;
+    }
+    // Any scheme, as a colon before the path
+    match (url.find(':'), url.find(['/', '?', '#'])) {
+        (Some(colon), path) => path.is_none_or(|path| colon < path),
+        (None, _) => false,
+    }
+}
+
+/// Decodes CSS escapes of letters, digits and `-`, so `\75 rl(` or `\68ttp` are
+/// seen as `url(` and `http`. Other escapes are kept as they are.
+fn unescape_css(css: &str) -> String {
+    let mut out = String::with_capacity(css.len());
+    let mut chars = css.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        let mut escape = String::from('\\');
+        while escape.len() <= 6 && chars.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+            escape.push(chars.next().unwrap());
+        }
+        let decoded = if escape.len() > 1 {
+            // One whitespace character ends a hex escape
+            if let Some(space) = chars.next_if(|c| c.is_ascii_whitespace()) {
+                escape.push(space);
+            }
+            u32::from_str_radix(escape[1..].trim_end(), 16)
+                .ok()
+                .and_then(char::from_u32)
+        } else {
+            chars.next().inspect(|&c| escape.push(c))
+        };
+        match decoded {
+            Some(c) if c.is_ascii_alphanumeric() || c == '-' => out.push(c),
+            _ => out.push_str(&escape),
+        }
+    }
+    out
+}
+
+/// Neutralizes CSS that can run code or (unless allowed) fetch remote resources.
+fn clean_css(css: &str, allow_remote: bool, blocked: &AtomicBool) -> String {
+    let css = unescape_css(css);
+    let mut css = RE_CSS_EXPRESSION
+        .replace_all(&css, "x-blocked:")
+        .into_owned();
+    if !allow_remote && RE_CSS_IMPORT.is_match(&css) {
+        blocked.store(true, Ordering::Relaxed);
+        css = RE_CSS_IMPORT.replace_all(&css, "").into_owned();
+    }
+    // Takes plain strings as URLs, which would need a check of its own
+    if !allow_remote && RE_CSS_IMAGE_SET.is_match(&css) {
+        blocked.store(true, Ordering::Relaxed);
+        css = RE_CSS_IMAGE_SET
+            .replace_all(&css, "x-blocked(")
+            .into_owned();
+    }
+
+    let mut cleaned = String::with_capacity(css.len());
+    let mut last = 0;
+    // Browsers may still load what the regex could not parse
+    let push_unparsed = |cleaned: &mut String, css: &str| {
+        if RE_CSS_URL_START.is_match(css) {
+            blocked.store(true, Ordering::Relaxed);
+        }
+        cleaned.push_str(&RE_CSS_URL_START.replace_all(css, "x-blocked("));
+    };
+    for caps in RE_CSS_URL.captures_iter(&css) {
+        let whole = caps.get(0).unwrap();
+        push_unparsed(&mut cleaned, &css[last..whole.start()]);
+        last = whole.end();
+
+        let url = caps
+            .get(1)
+            .or_else(|| caps.get(2))
+            .or_else(|| caps.get(3))
+            .map_or("", |url| url.as_str());
+        if url.trim().to_ascii_lowercase().starts_with("javascript:") {
+            cleaned.push_str("none");
+        } else if !allow_remote && is_remote_url(url) {
+            blocked.store(true, Ordering::Relaxed);
+            cleaned.push_str("none");
+        } else {
+            cleaned.push_str(whole.as_str());
+        }
+    }
+    push_unparsed(&mut cleaned, &css[last..]);
+
+    // Keep the stylesheet from closing its <style> element
+    cleaned.replace('<', "\\3C ")
+}
+
+/// Cleans an HTML mail body for display: removes scripts, event handlers, forms and
+/// other active content. Unless `allow_remote` is set, remote images are replaced by
+/// a placeholder and remote CSS (`@import`, `url()`, `image-set()`) is dropped, so
+/// opening a mail does not trigger tracking pixels.
+pub fn sanitize(html: &str, allow_remote: bool) -> SanitizedHtml {
+    let blocked = Arc::new(AtomicBool::new(false));
+
+    // ammonia drops <style> elements entirely, so their CSS is cleaned separately
+    let styles: Vec<String> = RE_STYLE_BLOCK
+        .captures_iter(html)
+        .map(|caps| clean_css(&caps[1], allow_remote, &blocked))
+        .collect();
+
+    let filter_flag = blocked.clone();
+    let cleaned = ammonia::Builder::default()
+        .add_generic_attributes(LAYOUT_ATTRIBUTES)
+        .add_url_schemes(["data", "cid"])
+        .attribute_filter(move |_element, attribute, value| {
+            if RESOURCE_ATTRIBUTES.contains(&attribute) {
+                if !allow_remote && is_remote_url(value) {
+                    filter_flag.store(true, Ordering::Relaxed);
+                    return Some(BLOCKED_IMAGE.into());
+                }
+            } else if attribute == "style" {
+                return Some(clean_css(value, allow_remote, &filter_flag).into());
+            } else if attribute == "href" && value.trim().to_ascii_lowercase().starts_with("data:") {
+                return None;
+            }
+            Some(value.into())
+        })
+        .link_rel(Some("noopener noreferrer"))
+        .clean_content_tags(HashSet::from(["script", "style", "title"]))
+        .clean(html)
+        .to_string();
+
+    let html = if styles.is_empty() {
+        cleaned
+    } else {
+        format!("<style>{}</style>{}", styles.join("\n"), cleaned)
+    };
+
+    SanitizedHtml {
+        html,
+        remote_blocked: blocked.load(Ordering::Relaxed),
+    }
+}
+
+/// Characters left as-is in URL path segments (RFC 3986 unreserved).
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+pub fn encode_path_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, PATH_SEGMENT).to_string()
+}
+
+/// Normalizes a Content-ID for comparison (`<abc@host>` and `abc@host` are the same).
+pub fn normalize_cid(cid: &str) -> &str {
+    cid.trim().trim_start_matches('<').trim_end_matches('>')
+}
+
+/// Points `src="cid:..."` references at `/api/messages/{id}/cid/{content_id}`.
+pub fn rewrite_cid_urls(html: &str, message_id: &str) -> String {
+    let base = format!("/api/messages/{}/cid/", encode_path_segment(message_id));
+    replace_cid_refs(html, |cid| {
+        Some(format!("{}{}", base, encode_path_segment(cid)))
+    })
+}
+
+/// Replaces the target of every `cid:` reference with `url_for(content_id)`;
+/// references for which it returns `None` are left unchanged.
+pub fn replace_cid_refs(html: &str, url_for: impl Fn(&str) -> Option<String>) -> String {
+    RE_CID_REF
+        .replace_all(html, |caps: &regex::Captures| {
+            let cid = percent_encoding::percent_decode_str(&caps[3]).decode_utf8_lossy();
+            match url_for(normalize_cid(&cid)) {
+                Some(url) => format!("{}={}{}", &caps[1], &caps[2], url),
+                None => caps[0].to_string(),
+            }
+        })
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts that `html` has remote content and that `gone` was removed.
+    fn blocks(html: &str, gone: &str) {
+        let sanitized = sanitize(html, false);
+        assert!(sanitized.remote_blocked, "not blocked: {}", html);
+        assert!(!sanitized.html.contains(gone), "{}", sanitized.html);
+    }
+
+    #[test]
+    fn blocks_remote_images() {
+        blocks(r#"<img src="http://evil/x.png">"#, "evil");
+        blocks(r#"<img src="ht&#9;tp://evil/x.png">"#, "evil");
+        blocks(r#"<img src=" //evil/x.png">"#, "evil");
+        blocks(r#"<img src="/\evil/x.png">"#, "evil");
+        blocks(r#"<table background="ftp://evil/x.png"></table>"#, "evil");
+    }
+
+    #[test]
+    fn blocks_remote_css() {
+        blocks(
+            r#"<div style="background: url(http://evil/x.png)"></div>"#,
+            "evil",
+        );
+        blocks(
+            r#"<div style="background: url(\68ttp://evil/x.png)"></div>"#,
+            "evil",
+        );
+        blocks(
+            r#"<div style="background: \75 rl('https://evil/x.png')"></div>"#,
+            "evil",
+        );
+        blocks(
+            r#"<div style="background: url(&quot;https://evil/a'b&quot;)"></div>"#,
+            "evil",
+        );
+        blocks(
+            r#"<div style="background-image: image-set('https://evil/x.png' 1x)"></div>"#,
+            "image-set(",
+        );
+        blocks(
+            r#"<style>p { background: -webkit-image-set('https://evil/x.png' 1x) }</style>"#,
+            "image-set(",
+        );
+        blocks(
+            r#"<style>p { background: url(\68ttp://evil/x.png) }</style>"#,
+            "evil",
+        );
+        blocks(r#"<style>@import 'https://evil/x.css';</style>"#, "evil");
+        blocks(
+            r#"<style>p { background: url("https://evil/x.png</style>"#,
+            "url(",
+        );
+    }
+
+    #[test]
+    fn keeps_local_resources() {
+        let html = r#"<style>.logo { background: url(data:image/png;base64,AAAA) }</style>
+            <p class="logo"><img src="/api/messages/msg_000001.eml/cid/logo"><img src="cid:x"></p>"#;
+        let sanitized = sanitize(html, false);
+        assert!(!sanitized.remote_blocked);
+        assert!(sanitized.html.contains("url(data:image/png;base64,AAAA)"));
+        assert!(sanitized.html.contains(r#"class="logo""#));
+        assert!(sanitized
+            .html
+            .contains(r#"src="/api/messages/msg_000001.eml/cid/logo""#));
+        assert!(sanitized.html.contains(r#"src="cid:x""#));
+    }
+
+    #[test]
+    fn allows_remote_on_request() {
+        let sanitized = sanitize(r#"<img src="https://example.com/x.png">"#, true);
+        assert!(!sanitized.remote_blocked);
+        assert!(sanitized.html.contains("https://example.com/x.png"));
+    }
+}
@@ -1,11 +1,23 @@
 pub mod api;
+pub mod archive;
+pub mod attachments;
+pub mod cache;
+pub mod export;
+pub mod download;
+pub mod html;
+pub mod metadata_db;
 pub mod model;
+pub mod preview;
+pub mod print;
+pub mod query;
+pub mod search;
 pub mod settings;
 pub mod state;
 
-use crate::model::MetadataEntry;
+use crate::archive::Archive;
+use crate::cache::ArchiveCache;
 use crate::settings::Settings;
-use crate::state::AppState;
+use crate::state::{AppState, MetadataSource};
 use axum::{
     routing::{get, post},
     Router,
@@ -20,10 +32,9 @@ use tower_http::{
     cors::CorsLayer,
     services::{ServeDir, ServeFile},
 };
-use zip::ZipArchive;
 
 /// Loads the metadata.db from the ZIP into an in-memory SQLite database.
-fn load_database_to_memory(archive: &mut ZipArchive<File>) -> Option<Connection> {
+fn load_database_to_memory(archive: &mut Archive) -> Option<Connection> {
     let mut db_file = archive.by_name("metadata.db").ok()?;
 
     // 1. Create a temporary file to hold the extracted DB
@@ -53,11 +64,20 @@ fn load_database_to_memory(archive: &mut ZipArchive<File>) -> Option<Connection>
     Some(mem_conn)
 }
 
+/// Read connections per archive if metadata.db can be opened from the cache.
+fn db_pool_size() -> usize {
+    std::thread::available_parallelism()
+        .map_or(4, |n| n.get())
+        .min(8)
+}
+
 pub struct RawAppData {
     pub settings: Settings,
-    pub metadata: Vec<MetadataEntry>,
-    pub db_conn: Option<Connection>,
-    pub archive: Option<ZipArchive<File>>,
+    pub metadata: MetadataSource,
+    /// Connections to metadata.db, see `state::DbPool`.
+    pub db_conns: Vec<Connection>,
+    pub archive: Option<Archive>,
+    pub fingerprint: Option<String>,
 }
 
 pub fn load_all_data(
@@ -103,41 +123,110 @@ pub fn load_all_data(
         log_err(format!("Archiv nicht gefunden unter: {:?}", zip_path));
         return Ok(RawAppData {
             settings,
-            metadata: Vec::new(),
-            db_conn: None,
+            metadata: MetadataSource::Entries(Vec::new()),
+            db_conns: Vec::new(),
             archive: None,
+            fingerprint: None,
         });
     }
 
-    let file = File::open(&zip_path)?;
-    let mut archive = ZipArchive::new(file)?;
+    let mut archive = archive::open(&zip_path)?;
 
-    // Load metadata.json
-    let mut metadata = Vec::new();
-    match archive.by_name("metadata.json") {
-        Ok(mut file) => {
-            let mut content = String::new();
-            if file.read_to_string(&mut content).is_ok() {
-                metadata = serde_json::from_str(&content).unwrap_or_default();
-                log(format!("Lade {} Nachrichten ...", metadata.len()));
+    // Extracted database and parsed metadata are reused from earlier starts
+    let cache = match ArchiveCache::open(&settings, &mut archive, &zip_path) {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            log_err(format!("Cache nicht verfügbar: {}", e));
+            None
+        }
+    };
+    let fingerprint = match &cache {
+        Some(cache) => Some(cache.fingerprint().to_string()),
+        None => cache::metadata_fingerprint(&mut archive, &zip_path).ok(),
+    };
+    let mut cached_db: Vec<Connection> = match &cache {
+        Some(cache) => (0..db_pool_size())
+            .map_while(|_| {
+                cache
+                    .database(&mut archive)
+                    .map_err(|e| log_err(format!("Datenbank-Cache nicht verfügbar: {}", e)))
+                    .ok()
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    // Lazy mode: metadata stays in the database file, nothing is loaded up front
+    if settings.lazy_metadata.unwrap_or(false) {
+        match cached_db.first().map(metadata_db::message_count) {
+            Some(Some(count)) => {
+                log(format!(
+                    "{} Nachrichten, Metadaten werden bei Bedarf aus der Datenbank gelesen.",
+                    count
+                ));
+                return Ok(RawAppData {
+                    settings,
+                    metadata: MetadataSource::Database(count),
+                    db_conns: std::mem::take(&mut cached_db),
+                    archive: Some(archive),
+                    fingerprint,
+                });
             }
+            Some(None) => log(
+                "metadata.db enthält keine Metadaten (älteres Archiv), lade metadata.json ..."
+                    .to_string(),
+            ),
+            None => {}
         }
-        Err(e) => log_err(format!("metadata.json nicht gefunden: {}", e)),
+    }
+
+    let mut metadata = Vec::new();
+    match cache.as_ref().and_then(ArchiveCache::load_metadata) {
+        Some(cached) => {
+            metadata = cached;
+            log(format!("{} Nachrichten aus dem Cache geladen.", metadata.len()));
+        }
+        // Load metadata.json
+        None => match archive.by_name("metadata.json") {
+            Ok(mut file) => {
+                let mut content = String::new();
+                if file.read_to_string(&mut content).is_ok() {
+                    match serde_json::from_str(&content) {
+                        Ok(parsed) => {
+                            metadata = parsed;
+                            if let Some(cache) = &cache {
+                                if let Err(e) = cache.store_metadata(&metadata) {
+                                    log_err(format!("Metadaten-Cache nicht geschrieben: {}", e));
+                                }
+                            }
+                        }
+                        Err(e) => log_err(format!("metadata.json ungültig: {}", e)),
+                    }
+                    log(format!("Lade {} Nachrichten ...", metadata.len()));
+                }
+            }
+            Err(e) => log_err(format!("metadata.json nicht gefunden: {}", e)),
+        },
     }
 
     log("Verarbeite Daten ...".to_string());
 
-    // Load database to memory
-    let db_conn = load_database_to_memory(&mut archive);
-    if db_conn.is_some() {
+    // Without a usable cache the database is loaded into memory, once
+    let db_conns = if cached_db.is_empty() {
+        load_database_to_memory(&mut archive).into_iter().collect()
+    } else {
+        cached_db
+    };
+    if !db_conns.is_empty() {
         log("Datenbank ist bereit.".to_string());
     }
 
     Ok(RawAppData {
         settings,
-        metadata,
-        db_conn,
+        metadata: MetadataSource::Entries(metadata),
+        db_conns,
         archive: Some(archive),
+        fingerprint,
     })
 }
 
@@ -149,7 +238,14 @@ pub fn init_app_state(
     let settings = Settings::new(settings_path.clone())?;
 
     // 2. Create initial state with is_loading = true and otherwise empty data
-    let app_state = AppState::new(settings.clone(), Vec::new(), None, None, log_tx.clone());
+    let app_state = AppState::new(
+        settings.clone(),
+        MetadataSource::Entries(Vec::new()),
+        Vec::new(),
+        None,
+        None,
+        log_tx.clone(),
+    );
 
     // 3. Spawn background loader
     let state_clone = app_state.clone();
@@ -158,7 +254,7 @@ pub fn init_app_state(
 
         match raw_result {
             Ok(raw) => {
-                state_clone.apply_new_data(raw.settings, raw.metadata, raw.db_conn, raw.archive);
+                state_clone.apply_new_data(raw.settings, raw.metadata, raw.db_conns, raw.archive, raw.fingerprint);
                 if let Some(tx) = &log_tx {
                     let _ = tx.send("Background data loading completed.".to_string());
                 }
@@ -261,10 +357,33 @@ pub async fn run_server_with_state(
                 .route("/labels", get(api::get_labels))
                 .route("/query", post(api::search_messages))
                 .route("/messages/:id", get(api::get_message))
+                .route("/messages/:id/raw", get(api::get_raw_message))
+                .route("/messages/:id/headers", get(api::get_message_headers))
+                .route("/messages/:id/print", get(print::print_message))
+                .route("/threads/:id", get(api::get_thread))
+                .route("/threads/:id/print", get(print::print_thread))
                 .route(
                     "/messages/:id/attachment/:filename",
                     get(api::download_attachment),
                 )
+                .route(
+                    "/messages/:id/attachment/by-index/:index",
+                    get(api::download_attachment_by_index),
+                )
+                .route(
+                    "/messages/:id/attachment/by-index/:index/preview",
+                    get(preview::preview_attachment),
+                )
+                .route("/messages/:id/cid/:content_id", get(api::get_inline_part))
+                .route("/export/mbox", post(export::export_mbox))
+                .route("/export/eml", post(export::export_eml))
+                .route("/export/metadata", post(export::export_metadata))
+                .route("/attachments", get(attachments::list_attachments))
+                .route("/attachments/export", post(export::export_attachments))
+                .route(
+                    "/attachments/by-hash/:sha256",
+                    get(attachments::download_attachment_by_hash),
+                )
                 .route("/system/info", get(api::get_system_info))
                 .route("/system/select-file", post(api::select_file))
                 .route("/system/select-save-file", post(api::select_save_file))
@@ -0,0 +1,565 @@
+//! Metadata read on demand from the `metadata` column of metadata.db.
+//!
+//! Archives converted with metadata in the database store every entry of
+//! metadata.json as JSON in `messages.metadata`, in the same order. The position
+//! of an entry is its `rowid - 1`, so positions match metadata.json.
+//!
+//! Searches are answered by a single query per page (`Selection`): filters, sort
+//! order and paging run in SQLite, only the returned rows are parsed. The SQL
+//! functions of `register_functions` give them the same semantics as the
+//! in-memory evaluation.
+
+use crate::model::MetadataEntry;
+use crate::query::normalize_label;
+use crate::search::{self, FacetCounter, Facets, SortField, SortOrder};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, OptionalExtension};
+
+/// Number of messages if metadata.db carries complete metadata with positions
+/// that can be derived from the rowid; `None` for older archives.
+pub fn message_count(conn: &Connection) -> Option<usize> {
+    let has_column = conn
+        .prepare("SELECT metadata FROM messages LIMIT 0")
+        .is_ok();
+    if !has_column {
+        return None;
+    }
+    let (count, max_rowid, missing): (i64, Option<i64>, i64) = conn
+        .query_row(
+            "SELECT count(*), max(rowid), count(*) - count(metadata) FROM messages",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .ok()?;
+    (missing == 0 && max_rowid.unwrap_or(0) == count).then_some(count as usize)
+}
+
+fn parse(json: String) -> Option<MetadataEntry> {
+    serde_json::from_str(&json)
+        .map_err(|e| eprintln!("Invalid metadata in metadata.db: {}", e))
+        .ok()
+}
+
+pub fn entry(conn: &Connection, idx: usize) -> Option<MetadataEntry> {
+    let json: String = conn
+        .prepare_cached("SELECT metadata FROM messages WHERE rowid = ?1")
+        .ok()?
+        .query_row([idx as i64 + 1], |row| row.get(0))
+        .optional()
+        .ok()??;
+    parse(json)
+}
+
+pub fn position(conn: &Connection, id: &str) -> Option<usize> {
+    let rowid: i64 = conn
+        .prepare_cached("SELECT rowid FROM messages WHERE id = ?1")
+        .ok()?
+        .query_row([id], |row| row.get(0))
+        .optional()
+        .ok()??;
+    Some(rowid as usize - 1)
+}
+
+/// First entry, in archive order, with an attachment of this content hash.
+pub fn entry_with_attachment(conn: &Connection, sha256: &str) -> Option<MetadataEntry> {
+    let sql = format!(
+        "SELECT metadata FROM messages WHERE {} ORDER BY rowid LIMIT 1",
+        any_element_sql("$.attachments", "json_extract(value, '$.sha256') = ?1")
+    );
+    let json: String = conn
+        .prepare_cached(&sql)
+        .ok()?
+        .query_row([sha256], |row| row.get(0))
+        .optional()
+        .ok()??;
+    parse(json)
+}
+
+/// Members of a thread, unordered.
+pub fn thread_entries(
+    conn: &Connection,
+    thread_id: &str,
+) -> rusqlite::Result<Vec<(usize, MetadataEntry)>> {
+    let mut stmt =
+        conn.prepare_cached("SELECT rowid, metadata FROM messages WHERE thread_id = ?1")?;
+    let rows = stmt.query_map([thread_id], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+    })?;
+
+    let mut entries = Vec::new();
+    for row in rows {
+        let (rowid, json) = row?;
+        if let Some(entry) = parse(json) {
+            entries.push((rowid as usize - 1, entry));
+        }
+    }
+    Ok(entries)
+}
+
+pub fn thread_size(conn: &Connection, thread_id: &str) -> rusqlite::Result<usize> {
+    conn.prepare_cached("SELECT count(*) FROM messages WHERE thread_id = ?1")?
+        .query_row([thread_id], |row| row.get::<_, i64>(0))
+        .map(|count| count as usize)
+}
+
+/// All labels, sorted.
+pub fn labels(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT DISTINCT label.value FROM messages, json_each(messages.metadata, '$.gmail_labels') AS label
+         WHERE label.type = 'text' ORDER BY 1",
+    )?;
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+    rows.collect()
+}
+
+/// Registers the SQL functions used by `Filter` and `Sort`: `casefold(text)`,
+/// `label_key(label)`, `sender_key(name, address)` and `subject_key(subject)`.
+pub fn register_functions(conn: &Connection) -> rusqlite::Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    conn.create_scalar_function("casefold", 1, flags, |ctx| {
+        Ok(ctx.get::<Option<String>>(0)?.map(|s| s.to_lowercase()))
+    })?;
+    conn.create_scalar_function("label_key", 1, flags, |ctx| {
+        Ok(ctx.get::<Option<String>>(0)?.map(|s| normalize_label(&s)))
+    })?;
+    conn.create_scalar_function("sender_key", 2, flags, |ctx| {
+        let name = ctx.get::<Option<String>>(0)?;
+        let address = ctx.get::<Option<String>>(1)?;
+        Ok(search::sender_sort_key(name.as_deref(), address.as_deref()))
+    })?;
+    conn.create_scalar_function("subject_key", 1, flags, |ctx| {
+        Ok(search::base_subject(
+            ctx.get::<Option<String>>(0)?.as_deref(),
+        ))
+    })?;
+    Ok(())
+}
+
+/// Conditions on `messages`, joined by AND, with their numbered parameters.
+#[derive(Default)]
+pub struct Filter {
+    clauses: Vec<String>,
+    params: Vec<Value>,
+}
+
+impl Filter {
+    pub fn push(&mut self, clause: String) {
+        self.clauses.push(clause);
+    }
+
+    /// Adds a parameter and returns its placeholder.
+    pub fn param(&mut self, value: impl Into<Value>) -> String {
+        self.params.push(value.into());
+        format!("?{}", self.params.len())
+    }
+
+    fn where_clause(&self) -> String {
+        if self.clauses.is_empty() {
+            "1".to_string()
+        } else {
+            self.clauses.join(" AND ")
+        }
+    }
+}
+
+/// `column` contains `needle` (lowercase), ignoring case; false for NULL.
+pub fn contains_sql(column: &str, needle: &str) -> String {
+    format!("coalesce(instr(casefold({}), {}), 0) > 0", column, needle)
+}
+
+/// An element of the JSON array at `path` of the metadata satisfies `condition`
+/// on `value`.
+pub fn any_element_sql(path: &str, condition: &str) -> String {
+    format!(
+        "EXISTS (SELECT 1 FROM json_each(messages.metadata, '{}') WHERE {})",
+        path, condition
+    )
+}
+
+pub enum Sort {
+    Field(SortField, SortOrder),
+    /// By BM25 rank of an FTS5 query on `messages_fts`, best match first for
+    /// `Desc`; messages without a hit follow in archive order.
+    Rank(String, SortOrder),
+}
+
+/// The messages matching `filter` in `sort` order.
+pub struct Selection {
+    pub filter: Filter,
+    pub sort: Sort,
+    /// Only the first message per thread.
+    pub group_by_thread: bool,
+}
+
+impl Selection {
+    /// The query for a page, with its parameters. The sort key is computed once
+    /// per row; dates are compared as instants like `MetadataEntry::timestamp`,
+    /// not as text.
+    fn select_sql(&self, offset: usize, limit: Option<usize>) -> (String, Vec<Value>) {
+        let mut params = self.filter.params.clone();
+        let (sort_key, join, order) = match &self.sort {
+            Sort::Field(field, order) => {
+                let key = match field {
+                    SortField::Date | SortField::Relevance => "unixepoch(messages.date_sent_iso)",
+                    SortField::Sender => {
+                        "sender_key(messages.sender_name, messages.sender_address)"
+                    }
+                    SortField::Subject => "subject_key(messages.subject)",
+                    SortField::Size => "json_extract(messages.metadata, '$.rfc822_size')",
+                    SortField::Attachments => {
+                        "coalesce(json_array_length(messages.metadata, '$.attachments'), 0)"
+                    }
+                };
+                let order = match order {
+                    SortOrder::Asc => "sort_key, pos",
+                    SortOrder::Desc => "sort_key DESC, pos DESC",
+                };
+                (key, String::new(), order)
+            }
+            Sort::Rank(fts_query, order) => {
+                params.push(Value::Text(fts_query.clone()));
+                let join = format!(
+                    "LEFT JOIN (SELECT id AS ranked_id, rank AS ranked_rank FROM messages_fts
+                     WHERE messages_fts MATCH ?{}) ON ranked_id = messages.id",
+                    params.len()
+                );
+                let order = match order {
+                    SortOrder::Desc => "sort_key IS NULL, sort_key, pos",
+                    SortOrder::Asc => "sort_key IS NULL DESC, sort_key DESC, pos DESC",
+                };
+                ("ranked_rank", join, order)
+            }
+        };
+
+        let rows = format!(
+            "SELECT messages.rowid AS pos, messages.metadata AS metadata,
+                    coalesce(messages.thread_id, messages.rowid) AS thread, {} AS sort_key
+             FROM messages {} WHERE {}",
+            sort_key,
+            join,
+            self.filter.where_clause()
+        );
+        let rows = if self.group_by_thread {
+            format!(
+                "SELECT * FROM (SELECT *, row_number() OVER (PARTITION BY thread ORDER BY {}) AS nth
+                 FROM ({})) WHERE nth = 1",
+                order, rows
+            )
+        } else {
+            rows
+        };
+        let limit = limit.map_or(-1, |limit| limit as i64);
+        let sql = format!(
+            "SELECT pos, metadata FROM ({}) ORDER BY {} LIMIT {} OFFSET {}",
+            rows, order, limit, offset
+        );
+        (sql, params)
+    }
+}
+
+/// Number of rows of `selection`.
+pub fn count(conn: &Connection, selection: &Selection) -> rusqlite::Result<usize> {
+    let counted = if selection.group_by_thread {
+        "count(DISTINCT coalesce(messages.thread_id, messages.rowid))"
+    } else {
+        "count(*)"
+    };
+    let sql = format!(
+        "SELECT {} FROM messages WHERE {}",
+        counted,
+        selection.filter.where_clause()
+    );
+    conn.prepare(&sql)?
+        .query_row(params_from_iter(&selection.filter.params), |row| {
+            row.get::<_, i64>(0)
+        })
+        .map(|count| count as usize)
+}
+
+/// Rows `offset..offset + limit` of `selection`, all remaining rows without
+/// `limit`.
+pub fn select(
+    conn: &Connection,
+    selection: &Selection,
+    offset: usize,
+    limit: Option<usize>,
+) -> rusqlite::Result<Vec<(usize, MetadataEntry)>> {
+    let (sql, params) = selection.select_sql(offset, limit);
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(params_from_iter(&params), |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+    })?;
+
+    let mut entries = Vec::new();
+    for row in rows {
+        let (rowid, json) = row?;
+        if let Some(entry) = parse(json) {
+            entries.push((rowid as usize - 1, entry));
+        }
+    }
+    Ok(entries)
+}
+
+/// `Facets` of all messages matching `filter`; reads only the counted fields.
+pub fn facets(
+    conn: &Connection,
+    filter: &Filter,
+    hidden_labels: &[String],
+) -> rusqlite::Result<Facets> {
+    let sql = format!(
+        "SELECT json_extract(metadata, '$.gmail_labels'), sender_address, date_sent_iso, has_attachment
+         FROM messages WHERE {}",
+        filter.where_clause()
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(params_from_iter(&filter.params))?;
+
+    let mut counter = FacetCounter::new(hidden_labels);
+    while let Some(row) = rows.next()? {
+        let labels: Vec<String> = row
+            .get::<_, Option<String>>(0)?
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        counter.add(
+            &labels,
+            row.get::<_, Option<String>>(1)?.as_deref(),
+            row.get::<_, Option<String>>(2)?.as_deref(),
+            row.get::<_, Option<bool>>(3)?.unwrap_or(false),
+        );
+    }
+    Ok(counter.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{self, SearchQuery};
+    use crate::model::MetadataEntry;
+    use crate::search::{SortField, SortOrder};
+    use crate::settings::Settings;
+    use crate::state::{AppData, AppState, MetadataSource};
+    use rusqlite::Connection;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn entries() -> Vec<MetadataEntry> {
+        let rows = [
+            json!({"subject": "Rechnung 2019", "sender_name": "Alice", "sender_address": "alice@example.com",
+                   "date_sent_iso": "2019-01-15T10:00:00+01:00", "gmail_labels": ["Inbox", "Rechnungen"],
+                   "rfc822_size": 3000, "thread_id": "t1", "has_attachment": true,
+                   "attachments": [{"filename": "rechnung.pdf", "mime": "application/pdf", "size": 100}]}),
+            json!({"subject": "Re: Rechnung 2019", "sender_name": "Bob", "sender_address": "bob@example.org",
+                   "to_addresses": ["carol@example.com"], "date_sent_iso": "2019-01-16T08:00:00-05:00",
+                   "gmail_labels": ["Inbox"], "rfc822_size": 800, "thread_id": "t1"}),
+            json!({"subject": "Über den Winter", "sender_address": "dora@example.net",
+                   "cc_addresses": ["Alice <alice@example.com>"], "date_sent_iso": "2019-05-31T23:30:00+00:00",
+                   "gmail_labels": ["My Label"], "rfc822_size": 1500}),
+            json!({"subject": "Gewinnspiel", "sender_name": "\"Spammer\"", "sender_address": "win@spam.test",
+                   "date_sent_iso": "2019-03-01T12:00:00+01:00", "gmail_labels": ["Spam"], "rfc822_size": 500}),
+            json!({"subject": "Fotos", "sender_name": "alice", "sender_address": "alice@example.com",
+                   "date_sent_iso": "2020-07-04T09:00:00+02:00", "gmail_labels": ["Inbox", "Familie/Urlaub"],
+                   "rfc822_size": 250000, "thread_id": "t2", "has_attachment": true,
+                   "attachments": [{"filename": "IMG_1.JPG", "mime": "image/jpeg", "size": 200000},
+                                   {"filename": "IMG_2.jpg", "mime": "image/jpeg", "size": 40000}]}),
+            json!({"subject": "Fwd: Fotos", "sender_name": "Bob", "sender_address": "bob@example.org",
+                   "date_sent_iso": "2020-07-04T08:30:00+00:00", "gmail_labels": ["Inbox"],
+                   "rfc822_size": 260000, "thread_id": "t2", "has_attachment": true,
+                   "attachments": [{"filename": "IMG_1.JPG", "mime": "image/jpeg", "size": 200000}]}),
+            json!({"subject": null, "sender_address": "noreply@example.com", "rfc822_size": 100}),
+            json!({"subject": "Rechnung März", "sender_name": "Buchhaltung", "sender_address": "rechnung@firma.de",
+                   "date_sent_iso": "2019-03-10T10:00:00+01:00", "gmail_labels": ["Rechnungen"],
+                   "rfc822_size": 2048, "thread_id": "t3"}),
+        ];
+        rows.into_iter()
+            .enumerate()
+            .map(|(i, row)| {
+                let mut row = row;
+                let object = row.as_object_mut().unwrap();
+                object.insert("id".into(), json!(format!("msg_{:06}.eml", i + 1)));
+                object.entry("has_attachment").or_insert(json!(false));
+                serde_json::from_value(row).unwrap()
+            })
+            .collect()
+    }
+
+    /// The same archive twice: metadata loaded in memory and read from the database.
+    fn archives(
+        entries: &[MetadataEntry],
+    ) -> (tempfile::NamedTempFile, Arc<AppData>, Arc<AppData>) {
+        let db_file = tempfile::NamedTempFile::new().unwrap();
+        let conn = Connection::open(db_file.path()).unwrap();
+        mbox2zip::db::init_db(&conn).unwrap();
+        for entry in entries {
+            mbox2zip::db::insert_metadata(&conn, entry, None).unwrap();
+        }
+        mbox2zip::db::update_metadata(&conn, entries).unwrap();
+        assert_eq!(super::message_count(&conn), Some(entries.len()));
+
+        let settings: Settings =
+            toml::from_str("zip_path = \"\"\nspecial_labels = [\"Spam\"]").unwrap();
+        let open = |metadata| {
+            let conn = Connection::open(db_file.path()).unwrap();
+            AppState::new(settings.clone(), metadata, vec![conn], None, None, None).snapshot()
+        };
+        let loaded = open(MetadataSource::Entries(entries.to_vec()));
+        let database = open(MetadataSource::Database(entries.len()));
+        (db_file, loaded, database)
+    }
+
+    fn ids(data: &AppData, query: &SearchQuery) -> Vec<String> {
+        let mut expr = api::parse_q(query).unwrap();
+        api::select_messages(data, query, expr.as_mut())
+            .into_iter()
+            .map(|(_, entry)| entry.id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn database_selects_like_memory() {
+        let entries = entries();
+        let (_db_file, loaded, database) = archives(&entries);
+
+        let searches = [
+            "",
+            "from:alice",
+            "-label:Spam",
+            "label:Spam",
+            "label:my-label",
+            "label:familie-urlaub",
+            "has:attachment",
+            "larger:2k",
+            "smaller:1k",
+            "after:2019/01/01 before:2019/06/01",
+            "rechnung",
+            "subject:rechnung OR from:bob",
+            "filename:jpg",
+            "to:carol",
+            "cc:alice",
+            "-(from:alice)",
+            "über",
+            "-rechnung -label:Inbox",
+            "newer_than:100y",
+        ];
+        let sorts = [
+            SortField::Date,
+            SortField::Sender,
+            SortField::Subject,
+            SortField::Size,
+            SortField::Attachments,
+            SortField::Relevance,
+        ];
+        for q in searches {
+            for sort_by in sorts {
+                for order in [SortOrder::Asc, SortOrder::Desc] {
+                    let query = SearchQuery {
+                        q: Some(q.to_string()),
+                        sort_by: Some(sort_by),
+                        order: Some(order),
+                        ..Default::default()
+                    };
+                    assert_eq!(
+                        ids(&loaded, &query),
+                        ids(&database, &query),
+                        "{:?} {:?} {:?}",
+                        q,
+                        sort_by,
+                        order
+                    );
+                }
+            }
+        }
+
+        let filters = [
+            SearchQuery {
+                label: Some("Rechnungen".into()),
+                ..Default::default()
+            },
+            SearchQuery {
+                label: Some("Spam".into()),
+                ..Default::default()
+            },
+            SearchQuery {
+                has_attachment: Some(true),
+                date_from: Some("2019-02-01".into()),
+                date_to: Some("2020-12-31".into()),
+                ..Default::default()
+            },
+            SearchQuery {
+                any: Some("alice".into()),
+                ..Default::default()
+            },
+            SearchQuery {
+                sender: Some("bob".into()),
+                subject: Some("fotos".into()),
+                ..Default::default()
+            },
+            SearchQuery {
+                any: Some("@".into()),
+                ..Default::default()
+            },
+        ];
+        for query in &filters {
+            assert_eq!(
+                ids(&loaded, query),
+                ids(&database, query),
+                "{:?}",
+                query.label
+            );
+        }
+    }
+
+    #[test]
+    fn database_pages_groups_and_counts() {
+        let entries = entries();
+        let (_db_file, loaded, database) = archives(&entries);
+        let query = SearchQuery {
+            q: Some("-label:Familie/Urlaub".into()),
+            ..Default::default()
+        };
+        let mut expr = api::parse_q(&query).unwrap();
+        let matching = api::select_messages(&loaded, &query, expr.as_mut());
+        let all: Vec<String> = matching.iter().map(|(_, entry)| entry.id.clone()).collect();
+
+        let conn = database.db().unwrap();
+        let mut selection = api::sql_selection(&conn, &database, &query, expr.as_ref(), false);
+        assert_eq!(super::count(&conn, &selection).unwrap(), all.len());
+        let page: Vec<String> = super::select(&conn, &selection, 2, Some(3))
+            .unwrap()
+            .into_iter()
+            .map(|(_, entry)| entry.id)
+            .collect();
+        assert_eq!(page, all[2..5]);
+
+        // First message per thread in sort order
+        let mut threads = std::collections::HashSet::new();
+        let first_per_thread: Vec<String> = matching
+            .iter()
+            .filter(|(_, entry)| {
+                entry
+                    .thread_id
+                    .as_ref()
+                    .is_none_or(|t| threads.insert(t.clone()))
+            })
+            .map(|(_, entry)| entry.id.clone())
+            .collect();
+        selection.group_by_thread = true;
+        assert_eq!(
+            super::count(&conn, &selection).unwrap(),
+            first_per_thread.len()
+        );
+        let grouped: Vec<String> = super::select(&conn, &selection, 0, None)
+            .unwrap()
+            .into_iter()
+            .map(|(_, entry)| entry.id)
+            .collect();
+        assert_eq!(grouped, first_per_thread);
+
+        let hidden = vec!["Inbox".to_string()];
+        let in_memory = crate::search::compute_facets(&matching, &hidden);
+        let facets = super::facets(&conn, &selection.filter, &hidden).unwrap();
+        assert_eq!(
+            serde_json::to_value(facets).unwrap(),
+            serde_json::to_value(in_memory).unwrap()
+        );
+    }
+}
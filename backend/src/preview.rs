@@ -0,0 +1,356 @@
+//! Inline previews of attachments: image thumbnails and HTML renditions of text,
+//! CSV and the first page of PDFs.
+
+use crate::api;
+use crate::download;
+use crate::search::escape_html;
+use crate::state::AppState;
+use axum::{
+    extract::{Path as AxumPath, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use mail_parser::MessageParser;
+use serde::Deserialize;
+use std::io::Cursor;
+use std::sync::atomic::Ordering;
+
+/// Larger attachments are not previewed.
+const MAX_PREVIEW_INPUT: usize = 25 * 1024 * 1024;
+/// Characters of text shown in a text or PDF preview.
+const MAX_PREVIEW_CHARS: usize = 100_000;
+/// Rows of a CSV preview.
+const MAX_CSV_ROWS: usize = 500;
+/// Thumbnail size limits (longest side in pixels).
+const DEFAULT_THUMBNAIL_SIZE: u32 = 400;
+const MAX_THUMBNAIL_SIZE: u32 = 1600;
+
+const PREVIEW_CSS: &str = r#"
+body { font-family: Arial, Helvetica, sans-serif; font-size: 13px; margin: 16px; color: #202124; }
+pre { white-space: pre-wrap; overflow-wrap: break-word; margin: 0; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #dadce0; padding: 2px 6px; text-align: left; vertical-align: top; }
+th { background: #f1f3f4; }
+.note { color: #5f6368; font-style: italic; margin-top: 12px; }
+"#;
+
+#[derive(Deserialize)]
+pub struct PreviewQuery {
+    /// Longest side of an image thumbnail in pixels.
+    pub size: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PreviewKind {
+    Image,
+    Text,
+    Csv,
+    Pdf,
+}
+
+fn preview_kind(mime: &str, filename: &str) -> Option<PreviewKind> {
+    let extension = filename
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_lowercase())
+        .unwrap_or_default();
+    match mime {
+        "image/jpeg" | "image/png" | "image/gif" | "image/webp" => Some(PreviewKind::Image),
+        "text/csv" => Some(PreviewKind::Csv),
+        "application/pdf" => Some(PreviewKind::Pdf),
+        "text/plain" if extension == "csv" => Some(PreviewKind::Csv),
+        "text/plain" => Some(PreviewKind::Text),
+        _ => match extension.as_str() {
+            "jpg" | "jpeg" | "png" | "gif" | "webp" => Some(PreviewKind::Image),
+            "csv" => Some(PreviewKind::Csv),
+            "pdf" => Some(PreviewKind::Pdf),
+            "txt" | "log" => Some(PreviewKind::Text),
+            _ => None,
+        },
+    }
+}
+
+/// Reads attachment `index` of message `id` and renders its preview.
+fn render(
+    state: &AppState,
+    id: &str,
+    index: usize,
+    size: u32,
+) -> Result<(&'static str, Vec<u8>, String), StatusCode> {
+    let buffer = api::read_eml(state, id)?;
+    let message = MessageParser::default()
+        .parse(&buffer)
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    let part = message
+        .attachments()
+        .nth(index)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let filename = api::attachment_filename(part);
+    let mime = api::part_content_type(part).to_lowercase();
+    let kind = preview_kind(&mime, filename).ok_or(StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+    let contents = part.contents();
+    if contents.len() > MAX_PREVIEW_INPUT {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let rendered = match kind {
+        PreviewKind::Image => thumbnail(contents, size),
+        PreviewKind::Text => Some(text_page(filename, &String::from_utf8_lossy(contents))),
+        PreviewKind::Csv => Some(csv_page(filename, &String::from_utf8_lossy(contents))),
+        PreviewKind::Pdf => Some(pdf_page(filename, contents)),
+    };
+    let (content_type, body) = rendered.ok_or(StatusCode::UNPROCESSABLE_ENTITY)?;
+    Ok((content_type, body, filename.to_string()))
+}
+
+/// Inline rendition of the attachment at `index` (see `download_attachment_by_index`).
+pub async fn preview_attachment(
+    State(state): State<AppState>,
+    AxumPath((id, index)): AxumPath<(String, usize)>,
+    Query(params): Query<PreviewQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let size = params
+        .size
+        .unwrap_or(DEFAULT_THUMBNAIL_SIZE)
+        .clamp(16, MAX_THUMBNAIL_SIZE);
+
+    let etag = download::etag(
+        &state,
+        &[&id, "preview", &index.to_string(), &size.to_string()],
+    );
+    let etag_value = etag.as_deref().and_then(|e| HeaderValue::from_str(e).ok());
+    if let (Some(etag), Some(value)) = (etag.as_deref(), &etag_value) {
+        if download::not_modified(&headers, etag) {
+            return (StatusCode::NOT_MODIFIED, [(header::ETAG, value.clone())]).into_response();
+        }
+    }
+
+    // Reading the archive, parsing and decoding images and PDFs block
+    let rendered = tokio::task::spawn_blocking(move || render(&state, &id, index, size)).await;
+    let (content_type, body, filename) = match rendered {
+        Ok(Ok(rendered)) => rendered,
+        Ok(Err(status)) => return status.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let mut response = (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                api::content_disposition("inline", &filename),
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                "default-src 'none'; style-src 'unsafe-inline'".to_string(),
+            ),
+            (header::CACHE_CONTROL, "private, no-cache".to_string()),
+        ],
+        body,
+    )
+        .into_response();
+    if let Some(value) = etag_value {
+        response.headers_mut().insert(header::ETAG, value);
+    }
+    response
+}
+
+/// Scaled down image, JPEG for photos and PNG for everything else.
+fn thumbnail(data: &[u8], size: u32) -> Option<(&'static str, Vec<u8>)> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(20_000);
+    limits.max_image_height = Some(20_000);
+    limits.max_alloc = Some(512 * 1024 * 1024);
+
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?;
+    let format = reader.format()?;
+    reader.limits(limits);
+    let image = reader.decode().ok()?;
+    let image = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+
+    let mut out = Cursor::new(Vec::new());
+    if format == ImageFormat::Jpeg {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_to(&mut out, ImageFormat::Jpeg)
+            .ok()?;
+        Some(("image/jpeg", out.into_inner()))
+    } else {
+        image.write_to(&mut out, ImageFormat::Png).ok()?;
+        Some(("image/png", out.into_inner()))
+    }
+}
+
+fn html_page(title: &str, body: &str) -> (&'static str, Vec<u8>) {
+    let page = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head><body>{}</body></html>",
+        escape_html(title),
+        PREVIEW_CSS,
+        body
+    );
+    ("text/html; charset=utf-8", page.into_bytes())
+}
+
+/// Cuts `text` to `MAX_PREVIEW_CHARS`; the flag tells whether something was cut.
+fn truncate(text: &str) -> (&str, bool) {
+    match text.char_indices().nth(MAX_PREVIEW_CHARS) {
+        Some((pos, _)) => (&text[..pos], true),
+        None => (text, false),
+    }
+}
+
+fn truncation_note(truncated: bool) -> &'static str {
+    if truncated {
+        "<p class=\"note\">Vorschau gekürzt, der vollständige Inhalt steht im Download.</p>"
+    } else {
+        ""
+    }
+}
+
+fn text_page(title: &str, text: &str) -> (&'static str, Vec<u8>) {
+    let (text, truncated) = truncate(text);
+    let body = format!(
+        "<pre>{}</pre>{}",
+        escape_html(text),
+        truncation_note(truncated)
+    );
+    html_page(title, &body)
+}
+
+fn pdf_page(title: &str, data: &[u8]) -> (&'static str, Vec<u8>) {
+    let body = match mbox2zip::attachment_text::pdf_first_page_text(data) {
+        Some(text) => {
+            let (text, truncated) = truncate(&text);
+            format!(
+                "<pre>{}</pre><p class=\"note\">Textvorschau der ersten Seite.</p>{}",
+                escape_html(text),
+                truncation_note(truncated)
+            )
+        }
+        None => "<p class=\"note\">Die erste Seite enthält keinen lesbaren Text.</p>".to_string(),
+    };
+    html_page(title, &body)
+}
+
+/// The most frequent of `;`, `,` and tab in the first line.
+fn csv_delimiter(text: &str) -> char {
+    let first_line = text.lines().next().unwrap_or("");
+    [';', ',', '\t']
+        .into_iter()
+        .max_by_key(|&d| first_line.matches(d).count())
+        .filter(|&d| first_line.contains(d))
+        .unwrap_or(',')
+}
+
+/// Parses up to `max_rows` CSV records (RFC 4180 quoting); the flag tells whether
+/// more rows follow.
+fn parse_csv(text: &str, delimiter: char, max_rows: usize) -> (Vec<Vec<String>>, bool) {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => in_quotes = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            '\r' => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+                if rows.len() == max_rows {
+                    return (rows, chars.any(|c| !c.is_whitespace()));
+                }
+            }
+            c if c == delimiter => row.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    (rows, false)
+}
+
+fn csv_page(title: &str, text: &str) -> (&'static str, Vec<u8>) {
+    let (rows, truncated) = parse_csv(text, csv_delimiter(text), MAX_CSV_ROWS);
+    let mut body = String::from("<table>");
+    for (i, row) in rows.iter().enumerate() {
+        let cell = if i == 0 { "th" } else { "td" };
+        body.push_str("<tr>");
+        for value in row {
+            body.push_str(&format!("<{0}>{1}</{0}>", cell, escape_html(value)));
+        }
+        body.push_str("</tr>");
+    }
+    body.push_str("</table>");
+    body.push_str(truncation_note(truncated));
+    html_page(title, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(text: &str) -> Vec<Vec<String>> {
+        parse_csv(text, ',', usize::MAX).0
+    }
+
+    #[test]
+    fn detects_delimiter_from_header() {
+        assert_eq!(csv_delimiter("Name;Ort;Datum\r\na,b;c;d\n"), ';');
+        assert_eq!(csv_delimiter("name,city;x,date\n"), ',');
+        assert_eq!(csv_delimiter("a\tb\tc"), '\t');
+        assert_eq!(csv_delimiter("single column\n1;2;3\n"), ',');
+        assert_eq!(csv_delimiter(""), ',');
+    }
+
+    #[test]
+    fn parses_quoted_fields() {
+        assert_eq!(
+            rows("a,\"b,c\",d\r\n1,2,3\r\n"),
+            vec![vec!["a", "b,c", "d"], vec!["1", "2", "3"]]
+        );
+        // Doubled quotes are escapes, line breaks inside quotes belong to the field
+        assert_eq!(
+            rows("\"say \"\"hi\"\"\",\"line 1\nline 2\"\nx,y"),
+            vec![vec!["say \"hi\"", "line 1\nline 2"], vec!["x", "y"]]
+        );
+        // Quotes inside an unquoted field are kept, empty fields survive
+        assert_eq!(rows("\u{feff}a\"b,,\n"), vec![vec!["a\"b", "", ""]]);
+        assert_eq!(parse_csv("a;\"b;c\"\n", ';', 10).0, vec![vec!["a", "b;c"]]);
+    }
+
+    #[test]
+    fn reports_truncation() {
+        let (parsed, more) = parse_csv("1\n2\n3\n", ',', 2);
+        assert_eq!(parsed, vec![vec!["1"], vec!["2"]]);
+        assert!(more);
+        // Trailing blank lines are not more rows
+        let (parsed, more) = parse_csv("1\n2\n\r\n", ',', 2);
+        assert_eq!(parsed.len(), 2);
+        assert!(!more);
+    }
+}
@@ -0,0 +1,240 @@
+//! Self-contained, printable HTML pages for a message or a whole thread.
+
+use crate::api::{self, MessageBody, MessageQuery};
+use crate::model::MetadataEntry;
+use crate::search::escape_html;
+use crate::state::AppState;
+use axum::{
+    extract::{Path as AxumPath, Query, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
+};
+use base64::Engine;
+use mail_parser::{MessageParser, MimeHeaders};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+
+const PRINT_CSS: &str = r#"
+@page { margin: 15mm; }
+body { font-family: Arial, Helvetica, sans-serif; font-size: 11pt; color: #000; margin: 0 auto; max-width: 210mm; }
+article { padding: 12px 0; }
+article + article { border-top: 2px solid #444; }
+table.headers { border-collapse: collapse; margin-bottom: 12px; }
+table.headers th { text-align: left; vertical-align: top; padding: 2px 12px 2px 0; white-space: nowrap; }
+table.headers td { padding: 2px 0; }
+.body { border-top: 1px solid #ccc; padding-top: 12px; overflow-wrap: break-word; }
+.body img { max-width: 100%; height: auto; }
+pre.plain { white-space: pre-wrap; font-family: inherit; margin: 0; }
+.attachments { border-top: 1px solid #ccc; margin-top: 12px; font-size: 10pt; }
+.attachments h3 { font-size: 10pt; margin: 8px 0 4px; }
+.attachments ul { margin: 0; padding-left: 18px; }
+"#;
+
+fn format_size(bytes: usize) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    } else if bytes >= 1024 {
+        format!("{:.0} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+fn format_date(entry: &MetadataEntry) -> String {
+    let Some(iso) = entry.date_sent_iso.as_deref() else {
+        return String::new();
+    };
+    chrono::DateTime::parse_from_rfc3339(iso)
+        .map(|d| d.format("%d.%m.%Y %H:%M:%S %:z").to_string())
+        .unwrap_or_else(|_| iso.to_string())
+}
+
+fn header_row(out: &mut String, label: &str, value: &str) {
+    if !value.is_empty() {
+        out.push_str(&format!(
+            "<tr><th>{}</th><td>{}</td></tr>",
+            label,
+            escape_html(value)
+        ));
+    }
+}
+
+/// One message as `<article>`. Inline images are embedded as data URIs so the
+/// page needs no further requests.
+fn render_message(
+    eml: &[u8],
+    entry: &MetadataEntry,
+    labels: &[String],
+    allow_remote: bool,
+) -> Option<String> {
+    let message = MessageParser::default().parse(eml)?;
+    let MessageBody { text, is_html, .. } = api::message_body(&message, allow_remote);
+
+    let body = if is_html {
+        let images: HashMap<&str, String> = message
+            .parts
+            .iter()
+            .filter_map(|part| {
+                let cid = crate::html::normalize_cid(part.content_id()?);
+                let mime = api::part_content_type(part);
+                mime.starts_with("image/").then(|| {
+                    let data = base64::engine::general_purpose::STANDARD.encode(part.contents());
+                    (cid, format!("data:{};base64,{}", mime, data))
+                })
+            })
+            .collect();
+        crate::html::replace_cid_refs(&text, |cid| images.get(cid).cloned())
+    } else {
+        format!("<pre class=\"plain\">{}</pre>", escape_html(&text))
+    };
+
+    let from = entry
+        .sender_name
+        .as_deref()
+        .filter(|n| !n.is_empty())
+        .or(entry.sender_address.as_deref())
+        .unwrap_or("");
+    let join = |list: &Option<Vec<String>>| list.as_deref().unwrap_or(&[]).join(", ");
+
+    let mut out = String::from("<article><table class=\"headers\">");
+    header_row(&mut out, "Von", &api::decode_header_robust(from));
+    header_row(
+        &mut out,
+        "An",
+        &api::decode_header_robust(&join(&entry.to_addresses)),
+    );
+    header_row(
+        &mut out,
+        "Cc",
+        &api::decode_header_robust(&join(&entry.cc_addresses)),
+    );
+    header_row(&mut out, "Datum", &format_date(entry));
+    header_row(
+        &mut out,
+        "Betreff",
+        &api::decode_header_robust(entry.subject.as_deref().unwrap_or("")),
+    );
+    header_row(&mut out, "Labels", &labels.join(", "));
+    out.push_str("</table><div class=\"body\">");
+    out.push_str(&body);
+    out.push_str("</div>");
+
+    let attachments: Vec<String> = message
+        .attachments()
+        .map(|a| {
+            format!(
+                "<li>{} ({}, {})</li>",
+                escape_html(api::attachment_filename(a)),
+                escape_html(&api::part_content_type(a)),
+                format_size(a.contents().len())
+            )
+        })
+        .collect();
+    if !attachments.is_empty() {
+        out.push_str("<div class=\"attachments\"><h3>Anhänge</h3><ul>");
+        out.push_str(&attachments.concat());
+        out.push_str("</ul></div>");
+    }
+    out.push_str("</article>");
+    Some(out)
+}
+
+/// Renders the given messages into one page.
+fn render_page(state: &AppState, ids: &[String], requested_remote: bool) -> Response {
+    let items: Vec<(MetadataEntry, Vec<String>, bool)> = {
+        let data = state.snapshot();
+        let hidden = data.settings.filter_labels.as_deref().unwrap_or(&[]);
+        ids.iter()
+            .filter_map(|id| {
+                let entry = data.entry_by_id(id)?;
+                let labels = entry
+                    .gmail_labels
+                    .iter()
+                    .flatten()
+                    .filter(|l| !hidden.contains(l))
+                    .cloned()
+                    .collect();
+                let allow_remote = api::remote_allowed(&data, id, requested_remote);
+                Some((entry.into_owned(), labels, allow_remote))
+            })
+            .collect()
+    };
+    if items.is_empty() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let mut articles = String::new();
+    for (entry, labels, allow_remote) in &items {
+        let eml = match api::read_eml(state, &entry.id) {
+            Ok(eml) => eml,
+            Err(status) => return status.into_response(),
+        };
+        match render_message(&eml, entry, labels, *allow_remote) {
+            Some(article) => articles.push_str(&article),
+            None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    let title = items
+        .first()
+        .and_then(|(entry, _, _)| entry.subject.as_deref())
+        .map(api::decode_header_robust)
+        .unwrap_or_default();
+    let page = format!(
+        "<!DOCTYPE html><html lang=\"de\"><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head><body>{}</body></html>",
+        escape_html(&title),
+        PRINT_CSS,
+        articles
+    );
+
+    // Defense in depth on top of sanitizing: no scripts, only inline and embedded resources
+    let remote = if items.iter().any(|(_, _, allow)| *allow) {
+        " http: https:"
+    } else {
+        ""
+    };
+    let csp = format!(
+        "default-src 'none'; img-src data:{0}; style-src 'unsafe-inline'{0}; font-src data:{0}",
+        remote
+    );
+    ([(header::CONTENT_SECURITY_POLICY, csp)], Html(page)).into_response()
+}
+
+/// Printable page for a single message.
+pub async fn print_message(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<String>,
+    Query(params): Query<MessageQuery>,
+) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let requested_remote = params.allow_remote.unwrap_or(false);
+    tokio::task::spawn_blocking(move || render_page(&state, &[id], requested_remote))
+        .await
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+/// Printable page with all messages of a conversation, oldest first.
+pub async fn print_thread(
+    State(state): State<AppState>,
+    AxumPath(thread_id): AxumPath<String>,
+    Query(params): Query<MessageQuery>,
+) -> Response {
+    if state.is_loading.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let requested_remote = params.allow_remote.unwrap_or(false);
+    tokio::task::spawn_blocking(move || {
+        let ids: Vec<String> = {
+            let data = state.snapshot();
+            match data.thread(&thread_id) {
+                Some(members) => members.into_iter().map(|e| e.id.clone()).collect(),
+                None => return StatusCode::NOT_FOUND.into_response(),
+            }
+        };
+        render_page(&state, &ids, requested_remote)
+    })
+    .await
+    .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
@@ -0,0 +1,879 @@
+use crate::metadata_db::{any_element_sql, contains_sql, Filter};
+use crate::model::MetadataEntry;
+use crate::search;
+use chrono::{Days, Local, Months, NaiveDate};
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+// Parser for Gmail-style search strings, e.g.
+// `from:alice subject:"Rechnung 2019" -label:Spam (has:attachment OR larger:5M)`.
+//
+// The string is parsed into an `Expr` tree that is evaluated against `MetadataEntry`.
+// Text terms can be resolved through the FTS5 index beforehand (`resolve_fts`);
+// without the index they fall back to `Term::matches`, which only does substring
+// matching on the metadata (see `Expr::scans_text`).
+
+#[derive(Debug)]
+pub struct QueryError(pub String);
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    /// Free text. FTS matches word prefixes in all indexed columns (subject,
+    /// sender, recipients, body) and attachment text; the fallback without the
+    /// index matches substrings of subject and sender only.
+    Text { value: String, phrase: bool },
+    From(String),
+    To(String),
+    Cc(String),
+    Subject { value: String, phrase: bool },
+    Label(String),
+    HasAttachment,
+    Filename(String),
+    /// Sent before this day (exclusive).
+    Before(NaiveDate),
+    /// Sent on or after this day.
+    After(NaiveDate),
+    /// Size of the raw message in bytes is larger than this.
+    Larger(usize),
+    /// Size of the raw message in bytes is smaller than this.
+    Smaller(usize),
+}
+
+#[derive(Debug)]
+pub enum Expr {
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Leaf {
+        term: Term,
+        /// Metadata positions matching this term, if answered by FTS.
+        hits: Option<HashSet<usize>>,
+    },
+}
+
+// --- Lexer ---
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Or,
+    And,
+    Neg,
+    Word {
+        op: Option<String>,
+        value: String,
+        quoted: bool,
+    },
+}
+
+const OPERATORS: &[&str] = &[
+    "from",
+    "to",
+    "cc",
+    "subject",
+    "label",
+    "has",
+    "filename",
+    "before",
+    "after",
+    "older_than",
+    "newer_than",
+    "larger",
+    "smaller",
+];
+
+fn read_quoted(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    // Opening quote has already been consumed; a missing closing quote ends at EOF.
+    let mut value = String::new();
+    for c in chars.by_ref() {
+        if c == '"' {
+            break;
+        }
+        value.push(c);
+    }
+    value
+}
+
+fn tokenize(input: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        match c {
+            '(' => {
+                chars.next();
+                tokens.push(Token::LParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RParen);
+            }
+            '-' => {
+                chars.next();
+                match chars.peek() {
+                    Some(n) if !n.is_whitespace() && *n != ')' => tokens.push(Token::Neg),
+                    _ => tokens.push(Token::Word {
+                        op: None,
+                        value: "-".to_string(),
+                        quoted: false,
+                    }),
+                }
+            }
+            '"' => {
+                chars.next();
+                tokens.push(Token::Word {
+                    op: None,
+                    value: read_quoted(&mut chars),
+                    quoted: true,
+                });
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&n) = chars.peek() {
+                    if n.is_whitespace() || n == '(' || n == ')' {
+                        break;
+                    }
+                    word.push(n);
+                    chars.next();
+
+                    // `subject:"foo bar"`
+                    if n == ':' && chars.peek() == Some(&'"') {
+                        let op = word[..word.len() - 1].to_lowercase();
+                        if OPERATORS.contains(&op.as_str()) {
+                            chars.next();
+                            tokens.push(Token::Word {
+                                op: Some(op),
+                                value: read_quoted(&mut chars),
+                                quoted: true,
+                            });
+                            word.clear();
+                            break;
+                        }
+                    }
+                }
+                if word.is_empty() {
+                    continue;
+                }
+
+                if word == "OR" || word == "|" {
+                    tokens.push(Token::Or);
+                } else if word == "AND" {
+                    tokens.push(Token::And);
+                } else if let Some((op, value)) = word.split_once(':') {
+                    let op = op.to_lowercase();
+                    if OPERATORS.contains(&op.as_str()) && !value.is_empty() {
+                        tokens.push(Token::Word {
+                            op: Some(op),
+                            value: value.to_string(),
+                            quoted: false,
+                        });
+                    } else {
+                        tokens.push(Token::Word {
+                            op: None,
+                            value: word,
+                            quoted: false,
+                        });
+                    }
+                } else {
+                    tokens.push(Token::Word {
+                        op: None,
+                        value: word,
+                        quoted: false,
+                    });
+                }
+            }
+        }
+    }
+
+    tokens
+}
+
+// --- Parser ---
+
+fn parse_date(value: &str) -> Result<NaiveDate, QueryError> {
+    let normalized = value.replace('/', "-");
+    NaiveDate::parse_from_str(&normalized, "%Y-%m-%d")
+        .map_err(|_| QueryError(format!("Ungültiges Datum: {}", value)))
+}
+
+fn parse_size(value: &str) -> Result<usize, QueryError> {
+    let lower = value.to_lowercase();
+    let (number, factor) = if let Some(n) = lower.strip_suffix('k') {
+        (n, 1024)
+    } else if let Some(n) = lower.strip_suffix('m') {
+        (n, 1024 * 1024)
+    } else if let Some(n) = lower.strip_suffix('g') {
+        (n, 1024 * 1024 * 1024)
+    } else {
+        (lower.as_str(), 1)
+    };
+
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(factor))
+        .ok_or_else(|| QueryError(format!("Ungültige Größe: {}", value)))
+}
+
+/// Resolves relative ages like `3d`, `2m` or `1y` to the day that lies that far
+/// back from `today`.
+fn parse_age(value: &str, today: NaiveDate) -> Result<NaiveDate, QueryError> {
+    let err = || QueryError(format!("Ungültige Zeitangabe: {}", value));
+    let lower = value.to_lowercase();
+    let unit = lower.chars().last().ok_or_else(err)?;
+    let amount: u32 = lower[..lower.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| err())?;
+
+    match unit {
+        'd' => today.checked_sub_days(Days::new(amount as u64)),
+        'm' => today.checked_sub_months(Months::new(amount)),
+        'y' => today.checked_sub_months(Months::new(amount.saturating_mul(12))),
+        _ => None,
+    }
+    .ok_or_else(err)
+}
+
+fn make_term(
+    op: Option<&str>,
+    value: String,
+    quoted: bool,
+    today: NaiveDate,
+) -> Result<Term, QueryError> {
+    // `""` would become an empty FTS5 phrase
+    if value.trim().is_empty() {
+        return Err(QueryError("Leere Phrase".to_string()));
+    }
+    let term = match op {
+        None => Term::Text {
+            value,
+            phrase: quoted,
+        },
+        Some("from") => Term::From(value),
+        Some("to") => Term::To(value),
+        Some("cc") => Term::Cc(value),
+        Some("subject") => Term::Subject {
+            value,
+            phrase: quoted,
+        },
+        Some("label") => Term::Label(value),
+        Some("has") => {
+            if value.eq_ignore_ascii_case("attachment") {
+                Term::HasAttachment
+            } else {
+                return Err(QueryError(format!("Unbekannter Wert für has: {}", value)));
+            }
+        }
+        Some("filename") => Term::Filename(value),
+        Some("before") => Term::Before(parse_date(&value)?),
+        Some("after") => Term::After(parse_date(&value)?),
+        Some("older_than") => Term::Before(parse_age(&value, today)?),
+        Some("newer_than") => Term::After(parse_age(&value, today)?),
+        Some("larger") => Term::Larger(parse_size(&value)?),
+        Some("smaller") => Term::Smaller(parse_size(&value)?),
+        Some(other) => return Err(QueryError(format!("Unbekannter Operator: {}", other))),
+    };
+    Ok(term)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Reference day for `older_than`/`newer_than`.
+    today: NaiveDate,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, QueryError> {
+        let mut items = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            items.push(self.parse_and()?);
+        }
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Expr::Or(items)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, QueryError> {
+        let mut items = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(Token::RParen) | Some(Token::Or) => break,
+                Some(Token::And) => self.pos += 1,
+                _ => items.push(self.parse_unary()?),
+            }
+        }
+
+        match items.len() {
+            0 => Err(QueryError("Leerer Ausdruck".to_string())),
+            1 => Ok(items.pop().unwrap()),
+            _ => Ok(Expr::And(items)),
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, QueryError> {
+        match self.next() {
+            Some(Token::Neg) => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::LParen) => {
+                let inner = self.parse_or()?;
+                if self.next() != Some(Token::RParen) {
+                    return Err(QueryError("Fehlende schließende Klammer".to_string()));
+                }
+                Ok(inner)
+            }
+            Some(Token::Word { op, value, quoted }) => Ok(Expr::Leaf {
+                term: make_term(op.as_deref(), value, quoted, self.today)?,
+                hits: None,
+            }),
+            _ => Err(QueryError("Unerwartetes Ende der Suche".to_string())),
+        }
+    }
+}
+
+/// Parses a Gmail-style search string into an expression tree.
+pub fn parse(input: &str) -> Result<Expr, QueryError> {
+    parse_at(input, Local::now().date_naive())
+}
+
+/// Like `parse`, with relative ages counted back from `today`.
+fn parse_at(input: &str, today: NaiveDate) -> Result<Expr, QueryError> {
+    let mut parser = Parser {
+        tokens: tokenize(input),
+        pos: 0,
+        today,
+    };
+    let expr = parser.parse_or()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(QueryError("Unerwartete schließende Klammer".to_string()));
+    }
+    Ok(expr)
+}
+
+// --- Evaluation ---
+
+fn contains_ci(haystack: Option<&str>, needle: &str) -> bool {
+    haystack
+        .map(|h| h.to_lowercase().contains(needle))
+        .unwrap_or(false)
+}
+
+fn any_contains_ci(values: Option<&Vec<String>>, needle: &str) -> bool {
+    values
+        .map(|v| v.iter().any(|s| s.to_lowercase().contains(needle)))
+        .unwrap_or(false)
+}
+
+/// Gmail writes labels with spaces or slashes as `my-label`; compare both forms.
+pub(crate) fn normalize_label(label: &str) -> String {
+    label.to_lowercase().replace([' ', '/'], "-")
+}
+
+fn entry_date(entry: &MetadataEntry) -> Option<NaiveDate> {
+    entry
+        .date_sent_iso
+        .as_deref()
+        .and_then(|d| d.get(..10))
+        .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+}
+
+impl Term {
+    pub fn matches(&self, entry: &MetadataEntry) -> bool {
+        match self {
+            Term::Text { value, .. } => {
+                let q = value.to_lowercase();
+                contains_ci(entry.subject.as_deref(), &q)
+                    || contains_ci(entry.sender_name.as_deref(), &q)
+                    || contains_ci(entry.sender_address.as_deref(), &q)
+            }
+            Term::From(value) => {
+                let q = value.to_lowercase();
+                contains_ci(entry.sender_name.as_deref(), &q)
+                    || contains_ci(entry.sender_address.as_deref(), &q)
+            }
+            Term::To(value) => any_contains_ci(entry.to_addresses.as_ref(), &value.to_lowercase()),
+            Term::Cc(value) => any_contains_ci(entry.cc_addresses.as_ref(), &value.to_lowercase()),
+            Term::Subject { value, .. } => {
+                contains_ci(entry.subject.as_deref(), &value.to_lowercase())
+            }
+            Term::Label(value) => {
+                let q = normalize_label(value);
+                entry
+                    .gmail_labels
+                    .as_ref()
+                    .map(|labels| labels.iter().any(|l| normalize_label(l) == q))
+                    .unwrap_or(false)
+            }
+            Term::HasAttachment => entry.has_attachment,
+            Term::Filename(value) => {
+                let q = value.to_lowercase();
+                entry
+                    .attachments
+                    .as_ref()
+                    .map(|atts| {
+                        atts.iter()
+                            .any(|a| contains_ci(a.filename.as_deref(), &q))
+                    })
+                    .unwrap_or(false)
+            }
+            Term::Before(day) => entry_date(entry).map(|d| d < *day).unwrap_or(false),
+            Term::After(day) => entry_date(entry).map(|d| d >= *day).unwrap_or(false),
+            Term::Larger(size) => entry.rfc822_size > *size,
+            Term::Smaller(size) => entry.rfc822_size < *size,
+        }
+    }
+
+    /// SQL condition on `messages` with the result of `matches`, see `metadata_db`.
+    fn sql(&self, filter: &mut Filter) -> String {
+        let day = |day: &NaiveDate| day.format("%Y-%m-%d").to_string();
+        match self {
+            Term::Text { value, .. } => {
+                let q = filter.param(value.to_lowercase());
+                format!(
+                    "({} OR {} OR {})",
+                    contains_sql("messages.subject", &q),
+                    contains_sql("messages.sender_name", &q),
+                    contains_sql("messages.sender_address", &q)
+                )
+            }
+            Term::From(value) => {
+                let q = filter.param(value.to_lowercase());
+                format!(
+                    "({} OR {})",
+                    contains_sql("messages.sender_name", &q),
+                    contains_sql("messages.sender_address", &q)
+                )
+            }
+            Term::To(value) | Term::Cc(value) => {
+                let path = if matches!(self, Term::To(_)) {
+                    "$.to_addresses"
+                } else {
+                    "$.cc_addresses"
+                };
+                let q = filter.param(value.to_lowercase());
+                any_element_sql(path, &contains_sql("value", &q))
+            }
+            Term::Subject { value, .. } => {
+                let q = filter.param(value.to_lowercase());
+                contains_sql("messages.subject", &q)
+            }
+            Term::Label(value) => {
+                let q = filter.param(normalize_label(value));
+                any_element_sql("$.gmail_labels", &format!("label_key(value) = {}", q))
+            }
+            Term::HasAttachment => "messages.has_attachment IS 1".to_string(),
+            Term::Filename(value) => {
+                let q = filter.param(value.to_lowercase());
+                any_element_sql(
+                    "$.attachments",
+                    &contains_sql("json_extract(value, '$.filename')", &q),
+                )
+            }
+            Term::Before(d) | Term::After(d) => {
+                let op = if matches!(self, Term::Before(_)) { "<" } else { ">=" };
+                format!(
+                    "coalesce(date(substr(messages.date_sent_iso, 1, 10)) {} {}, 0)",
+                    op,
+                    filter.param(day(d))
+                )
+            }
+            Term::Larger(size) | Term::Smaller(size) => {
+                let op = if matches!(self, Term::Larger(_)) { ">" } else { "<" };
+                format!(
+                    "coalesce(json_extract(messages.metadata, '$.rfc822_size') {} {}, 0)",
+                    op,
+                    filter.param(*size as i64)
+                )
+            }
+        }
+    }
+
+    /// FTS5 expression for terms that the `messages_fts` index can answer.
+    fn fts_query(&self) -> Option<String> {
+        let terms = |value: &str, phrase: bool| {
+            if phrase {
+                Some(format!("\"{}\"", value.replace('"', "\"\"")))
+            } else {
+                search::fts_terms(value)
+            }
+        };
+
+        match self {
+            Term::Text { value, phrase } => terms(value, *phrase),
+            Term::Subject { value, phrase } => {
+                Some(format!("{{subject}} : ({})", terms(value, *phrase)?))
+            }
+            Term::From(value) => Some(format!(
+                "{{sender_name sender_address}} : ({})",
+                terms(value, false)?
+            )),
+            Term::Filename(value) => Some(format!(
+                "{{attachment_names}} : ({})",
+                terms(value, false)?
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl Expr {
+    /// Evaluates the expression for the entry at metadata position `idx`.
+    pub fn matches(&self, idx: usize, entry: &MetadataEntry) -> bool {
+        match self {
+            Expr::And(items) => items.iter().all(|e| e.matches(idx, entry)),
+            Expr::Or(items) => items.iter().any(|e| e.matches(idx, entry)),
+            Expr::Not(inner) => !inner.matches(idx, entry),
+            Expr::Leaf {
+                hits: Some(hits), ..
+            } => hits.contains(&idx),
+            Expr::Leaf { term, .. } => term.matches(entry),
+        }
+    }
+
+    /// Answers all text terms of the tree from the FTS5 index.
+    /// Terms that cannot be expressed in FTS5 keep their in-memory evaluation.
+    pub fn resolve_fts(
+        &mut self,
+        conn: &Connection,
+        metadata_index: &HashMap<String, usize>,
+    ) -> rusqlite::Result<()> {
+        match self {
+            Expr::And(items) | Expr::Or(items) => {
+                for item in items {
+                    item.resolve_fts(conn, metadata_index)?;
+                }
+            }
+            Expr::Not(inner) => inner.resolve_fts(conn, metadata_index)?,
+            Expr::Leaf { term, hits } => {
+                if let Some(fts_query) = term.fts_query() {
+                    let mut found: HashSet<usize> =
+                        search::fts_candidates(conn, &fts_query, metadata_index, false)?
+                            .into_iter()
+                            .collect();
+                    // Free text also matches the text of attachments
+                    if matches!(term, Term::Text { .. }) {
+                        found.extend(search::attachment_candidates(
+                            conn,
+                            &fts_query,
+                            metadata_index,
+                        )?);
+                    }
+                    *hits = Some(found);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a term that FTS answers (`fts_query`) is left to the substring
+    /// matching of `Term::matches`, because there is no index or the FTS query failed.
+    pub fn scans_text(&self) -> bool {
+        match self {
+            Expr::And(items) | Expr::Or(items) => items.iter().any(Expr::scans_text),
+            Expr::Not(inner) => inner.scans_text(),
+            Expr::Leaf { term, hits } => {
+                hits.is_none()
+                    && matches!(
+                        term,
+                        Term::Text { .. }
+                            | Term::Subject { .. }
+                            | Term::From(_)
+                            | Term::Filename(_)
+                    )
+            }
+        }
+    }
+
+    /// SQL condition on `messages` with the result of `matches`; text terms are
+    /// answered by the FTS5 index as in `resolve_fts`.
+    pub fn sql(&self, filter: &mut Filter, attachment_index: bool) -> String {
+        let join = |items: &[Expr], filter: &mut Filter, op: &str| {
+            let items: Vec<String> = items
+                .iter()
+                .map(|item| item.sql(filter, attachment_index))
+                .collect();
+            format!("({})", items.join(op))
+        };
+        match self {
+            Expr::And(items) => join(items, filter, " AND "),
+            Expr::Or(items) => join(items, filter, " OR "),
+            Expr::Not(inner) => format!("NOT {}", inner.sql(filter, attachment_index)),
+            Expr::Leaf { term, .. } => match term.fts_query() {
+                Some(fts_query) => {
+                    let fts_query = filter.param(fts_query);
+                    let mut sql = format!(
+                        "messages.id IN (SELECT id FROM messages_fts WHERE messages_fts MATCH {})",
+                        fts_query
+                    );
+                    // Free text also matches the text of attachments
+                    if attachment_index && matches!(term, Term::Text { .. }) {
+                        sql.push_str(&format!(
+                            " OR messages.id IN (SELECT id FROM attachments_fts WHERE attachments_fts MATCH {})",
+                            fts_query
+                        ));
+                    }
+                    format!("({})", sql)
+                }
+                None => term.sql(filter),
+            },
+        }
+    }
+
+    /// FTS5 expression of all positive text terms, used to rank results by BM25.
+    pub fn rank_query(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        self.collect_rank_terms(&mut parts, false);
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(" OR "))
+        }
+    }
+
+    /// Like `rank_query`, but only free text terms, for `attachments_fts`.
+    pub fn text_query(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        self.collect_rank_terms(&mut parts, true);
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(" OR "))
+        }
+    }
+
+    fn collect_rank_terms(&self, parts: &mut Vec<String>, text_only: bool) {
+        match self {
+            Expr::And(items) | Expr::Or(items) => {
+                for item in items {
+                    item.collect_rank_terms(parts, text_only);
+                }
+            }
+            Expr::Not(_) => {}
+            Expr::Leaf { term, .. } => {
+                if text_only && !matches!(term, Term::Text { .. }) {
+                    return;
+                }
+                if let Some(fts_query) = term.fts_query() {
+                    parts.push(format!("({})", fts_query));
+                }
+            }
+        }
+    }
+
+    /// Checks whether the expression asks for one of the given labels outside a negation,
+    /// so that special labels like Spam are not hidden from explicit searches.
+    pub fn selects_label(&self, labels: &[String]) -> bool {
+        match self {
+            Expr::And(items) | Expr::Or(items) => items.iter().any(|e| e.selects_label(labels)),
+            Expr::Not(_) => false,
+            Expr::Leaf {
+                term: Term::Label(value),
+                ..
+            } => {
+                let q = normalize_label(value);
+                labels.iter().any(|l| normalize_label(l) == q)
+            }
+            Expr::Leaf { .. } => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(json: serde_json::Value) -> MetadataEntry {
+        let mut value = serde_json::json!({
+            "id": "msg_000001.eml",
+            "rfc822_size": 2048,
+            "has_attachment": false,
+        });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(json.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn term(input: &str) -> Term {
+        term_at(input, Local::now().date_naive())
+    }
+
+    fn term_at(input: &str, today: NaiveDate) -> Term {
+        match parse_at(input, today).unwrap() {
+            Expr::Leaf { term, .. } => term,
+            other => panic!("not a single term: {:?}", other),
+        }
+    }
+
+    fn error(input: &str) -> String {
+        parse(input).unwrap_err().0
+    }
+
+    #[test]
+    fn parses_operators() {
+        assert_eq!(term("from:alice"), Term::From("alice".into()));
+        assert_eq!(term("TO:bob@example.com"), Term::To("bob@example.com".into()));
+        assert_eq!(term("label:Rechnungen"), Term::Label("Rechnungen".into()));
+        assert_eq!(term("has:attachment"), Term::HasAttachment);
+        assert_eq!(term("filename:pdf"), Term::Filename("pdf".into()));
+        // Unknown operators are plain text
+        assert_eq!(
+            term("foo:bar"),
+            Term::Text {
+                value: "foo:bar".into(),
+                phrase: false
+            }
+        );
+    }
+
+    #[test]
+    fn parses_quoting() {
+        assert_eq!(
+            term("\"Rechnung 2019\""),
+            Term::Text {
+                value: "Rechnung 2019".into(),
+                phrase: true
+            }
+        );
+        assert_eq!(
+            term("subject:\"Rechnung 2019\""),
+            Term::Subject {
+                value: "Rechnung 2019".into(),
+                phrase: true
+            }
+        );
+        // A missing closing quote ends at the end of the search
+        assert_eq!(
+            term("subject:\"offen"),
+            Term::Subject {
+                value: "offen".into(),
+                phrase: true
+            }
+        );
+    }
+
+    #[test]
+    fn parses_negation_and_grouping() {
+        let expr = parse("from:alice -label:Spam (has:attachment OR larger:5M)").unwrap();
+        let Expr::And(items) = &expr else {
+            panic!("{:?}", expr);
+        };
+        assert_eq!(items.len(), 3);
+        assert!(matches!(&items[1], Expr::Not(inner)
+            if matches!(&**inner, Expr::Leaf { term: Term::Label(l), .. } if l == "Spam")));
+        assert!(matches!(&items[2], Expr::Or(alternatives) if alternatives.len() == 2));
+
+        let spam = entry(serde_json::json!({
+            "sender_name": "Alice",
+            "gmail_labels": ["Spam"],
+            "has_attachment": true,
+        }));
+        let inbox = entry(serde_json::json!({
+            "sender_name": "Alice",
+            "gmail_labels": ["Inbox"],
+            "has_attachment": true,
+        }));
+        assert!(!expr.matches(0, &spam));
+        assert!(expr.matches(0, &inbox));
+        // A lone dash is text, not a negation
+        assert_eq!(
+            term("-"),
+            Term::Text {
+                value: "-".into(),
+                phrase: false
+            }
+        );
+    }
+
+    #[test]
+    fn parses_size_units() {
+        assert_eq!(term("larger:500"), Term::Larger(500));
+        assert_eq!(term("larger:2k"), Term::Larger(2 * 1024));
+        assert_eq!(term("smaller:5M"), Term::Smaller(5 * 1024 * 1024));
+        assert_eq!(term("larger:1g"), Term::Larger(1024 * 1024 * 1024));
+
+        let message = entry(serde_json::json!({ "rfc822_size": 3000 }));
+        assert!(term("larger:2k").matches(&message));
+        assert!(!term("smaller:2k").matches(&message));
+    }
+
+    #[test]
+    fn parses_date_ranges() {
+        let day = |d: &str| NaiveDate::parse_from_str(d, "%Y-%m-%d").unwrap();
+        assert_eq!(term("after:2019/01/01"), Term::After(day("2019-01-01")));
+        assert_eq!(term("before:2019-02-01"), Term::Before(day("2019-02-01")));
+
+        let expr = parse("after:2019/01/01 before:2019/02/01").unwrap();
+        let january = entry(serde_json::json!({ "date_sent_iso": "2019-01-15T10:00:00+01:00" }));
+        let february = entry(serde_json::json!({ "date_sent_iso": "2019-02-01T00:00:00+01:00" }));
+        let undated = entry(serde_json::json!({}));
+        assert!(expr.matches(0, &january));
+        assert!(!expr.matches(0, &february));
+        assert!(!expr.matches(0, &undated));
+
+        let today = day("2020-03-31");
+        assert_eq!(term_at("newer_than:3d", today), Term::After(day("2020-03-28")));
+        assert_eq!(term_at("newer_than:1m", today), Term::After(day("2020-02-29")));
+        assert_eq!(term_at("older_than:1y", today), Term::Before(day("2019-03-31")));
+    }
+
+    #[test]
+    fn reports_text_fallback() {
+        assert!(parse("rechnung label:Inbox").unwrap().scans_text());
+        assert!(parse("-subject:\"Rechnung 2019\"").unwrap().scans_text());
+        assert!(!parse("label:Inbox has:attachment larger:1M").unwrap().scans_text());
+
+        let mut expr = parse("rechnung OR from:alice").unwrap();
+        if let Expr::Or(items) = &mut expr {
+            for item in items {
+                if let Expr::Leaf { hits, .. } = item {
+                    *hits = Some(HashSet::new());
+                }
+            }
+        }
+        assert!(!expr.scans_text());
+    }
+
+    #[test]
+    fn rejects_invalid_searches() {
+        assert_eq!(error("larger:99999999999G"), "Ungültige Größe: 99999999999G");
+        assert_eq!(error("larger:5x"), "Ungültige Größe: 5x");
+        assert_eq!(error("before:2019-13-01"), "Ungültiges Datum: 2019-13-01");
+        assert_eq!(error("newer_than:3w"), "Ungültige Zeitangabe: 3w");
+        assert_eq!(error("has:nothing"), "Unbekannter Wert für has: nothing");
+        assert_eq!(error("\"\""), "Leere Phrase");
+        assert_eq!(error("subject:\" \""), "Leere Phrase");
+        assert_eq!(error("(from:alice"), "Fehlende schließende Klammer");
+        assert_eq!(error("from:alice)"), "Unerwartete schließende Klammer");
+        assert_eq!(error("from:alice OR"), "Leerer Ausdruck");
+        assert_eq!(error(""), "Leerer Ausdruck");
+    }
+}
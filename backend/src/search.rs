@@ -0,0 +1,703 @@
+use crate::api::SearchQuery;
+use crate::metadata_db::{any_element_sql, contains_sql, Filter};
+use crate::model::MetadataEntry;
+use crate::settings::Settings;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Deref;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    Date,
+    Sender,
+    Subject,
+    Size,
+    Attachments,
+    /// BM25 rank of the text search; falls back to date without text filter.
+    Relevance,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortField {
+    /// Newest first for dates, best match first for relevance, A-Z/smallest first otherwise.
+    pub fn default_order(self) -> SortOrder {
+        match self {
+            SortField::Date | SortField::Relevance => SortOrder::Desc,
+            _ => SortOrder::Asc,
+        }
+    }
+}
+
+/// Precomputed position of every metadata entry in each sort order, so result
+/// sets can be ordered by comparing integers and paging stays stable.
+/// Equal keys keep archive order.
+pub struct SortKeys {
+    date: Vec<u32>,
+    sender: Vec<u32>,
+    subject: Vec<u32>,
+    size: Vec<u32>,
+    attachments: Vec<u32>,
+}
+
+fn ranks<K: Ord>(metadata: &[MetadataEntry], key: impl Fn(&MetadataEntry) -> K) -> Vec<u32> {
+    let mut order: Vec<usize> = (0..metadata.len()).collect();
+    order.sort_by_cached_key(|&i| key(&metadata[i]));
+
+    let mut ranks = vec![0u32; metadata.len()];
+    for (pos, idx) in order.into_iter().enumerate() {
+        ranks[idx] = pos as u32;
+    }
+    ranks
+}
+
+/// Subject without reply/forward prefixes, for sorting.
+pub(crate) fn base_subject(subject: Option<&str>) -> String {
+    let mut s = subject.unwrap_or("").trim().to_lowercase();
+    loop {
+        let stripped = ["re:", "aw:", "fw:", "fwd:", "wg:"]
+            .iter()
+            .find_map(|p| s.strip_prefix(p))
+            .map(|rest| rest.trim_start().to_string());
+        match stripped {
+            Some(rest) => s = rest,
+            None => return s,
+        }
+    }
+}
+
+/// Sender name, or address without a name, for sorting.
+pub(crate) fn sender_sort_key(name: Option<&str>, address: Option<&str>) -> String {
+    name.or(address)
+        .unwrap_or("")
+        .trim_matches(['"', ' '])
+        .to_lowercase()
+}
+
+fn sender_key(entry: &MetadataEntry) -> String {
+    sender_sort_key(entry.sender_name.as_deref(), entry.sender_address.as_deref())
+}
+
+fn subject_key(entry: &MetadataEntry) -> String {
+    base_subject(entry.subject.as_deref())
+}
+
+fn attachment_count(entry: &MetadataEntry) -> usize {
+    entry.attachments.as_ref().map(|a| a.len()).unwrap_or(0)
+}
+
+impl SortKeys {
+    pub fn build(metadata: &[MetadataEntry]) -> Self {
+        Self {
+            date: ranks(metadata, |e| e.timestamp()),
+            sender: ranks(metadata, sender_key),
+            subject: ranks(metadata, subject_key),
+            size: ranks(metadata, |e| e.rfc822_size),
+            attachments: ranks(metadata, attachment_count),
+        }
+    }
+
+    /// Orders `(position, entry)` pairs; `Relevance` is treated as date here.
+    pub fn sort<T>(&self, items: &mut [(usize, T)], field: SortField, order: SortOrder) {
+        let ranks = match field {
+            SortField::Date | SortField::Relevance => &self.date,
+            SortField::Sender => &self.sender,
+            SortField::Subject => &self.subject,
+            SortField::Size => &self.size,
+            SortField::Attachments => &self.attachments,
+        };
+        let rank = |idx: usize| ranks.get(idx).copied().unwrap_or(u32::MAX);
+
+        match order {
+            SortOrder::Asc => items.sort_unstable_by_key(|&(idx, _)| rank(idx)),
+            SortOrder::Desc => items.sort_unstable_by_key(|&(idx, _)| std::cmp::Reverse(rank(idx))),
+        }
+    }
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// Turns free user input into an FTS5 expression of quoted prefix terms,
+/// e.g. `Rechnung 2019` -> `"rechnung"* AND "2019"*`.
+/// Returns `None` if the input contains nothing the tokenizer would index.
+pub fn fts_terms(input: &str) -> Option<String> {
+    let terms: Vec<String> = input
+        .split_whitespace()
+        .filter(|t| t.chars().any(char::is_alphanumeric))
+        .map(|t| format!("\"{}\"*", t.replace('"', "\"\"")))
+        .collect();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" AND "))
+    }
+}
+
+/// Builds the MATCH expression for `messages_fts` from the text filters of a query.
+///
+/// `any` searches all indexed columns, `sender` and `subject` are restricted to
+/// their columns. Returns `None` if there is no text filter or one of them cannot
+/// be expressed in FTS5 syntax; the caller then uses the in-memory scan.
+pub fn build_fts_query(query: &SearchQuery) -> Option<String> {
+    let mut clauses = Vec::new();
+
+    if let Some(any) = non_empty(&query.any) {
+        clauses.push(format!("({})", fts_terms(any)?));
+    }
+    clauses.extend(field_clauses(query)?);
+
+    if clauses.is_empty() {
+        None
+    } else {
+        Some(clauses.join(" AND "))
+    }
+}
+
+/// Column restricted clauses for `sender` and `subject`, `None` if one of them
+/// cannot be expressed in FTS5 syntax.
+fn field_clauses(query: &SearchQuery) -> Option<Vec<String>> {
+    let mut clauses = Vec::new();
+    if let Some(sender) = non_empty(&query.sender) {
+        clauses.push(format!(
+            "{{sender_name sender_address}} : ({})",
+            fts_terms(sender)?
+        ));
+    }
+    if let Some(subject) = non_empty(&query.subject) {
+        clauses.push(format!("{{subject}} : ({})", fts_terms(subject)?));
+    }
+    Some(clauses)
+}
+
+/// MATCH expression for `attachments_fts` from the free text filter (`any`).
+pub fn build_attachment_fts_query(query: &SearchQuery) -> Option<String> {
+    fts_terms(non_empty(&query.any)?)
+}
+
+/// Runs an FTS5 query and maps the hits to metadata positions.
+///
+/// With `ranked` the hits are ordered by BM25, otherwise by archive order.
+pub fn fts_candidates(
+    conn: &Connection,
+    fts_query: &str,
+    metadata_index: &HashMap<String, usize>,
+    ranked: bool,
+) -> rusqlite::Result<Vec<usize>> {
+    let sql = if ranked {
+        "SELECT id FROM messages_fts WHERE messages_fts MATCH ?1 ORDER BY rank"
+    } else {
+        "SELECT id FROM messages_fts WHERE messages_fts MATCH ?1"
+    };
+
+    let mut stmt = conn.prepare_cached(sql)?;
+    let rows = stmt.query_map([fts_query], |row| row.get::<_, String>(0))?;
+
+    let mut indices = Vec::new();
+    for id in rows {
+        if let Some(&idx) = metadata_index.get(&id?) {
+            indices.push(idx);
+        }
+    }
+
+    if !ranked {
+        indices.sort_unstable();
+    }
+
+    Ok(indices)
+}
+
+/// Whether metadata.db has the `attachments_fts` table; older archives only
+/// index attachment names.
+pub fn has_attachment_index(conn: &Connection) -> bool {
+    conn.query_row(
+        "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'attachments_fts'",
+        [],
+        |_| Ok(()),
+    )
+    .is_ok()
+}
+
+/// Messages with an attachment whose extracted text matches `fts_query`, as
+/// metadata positions in archive order.
+pub fn attachment_candidates(
+    conn: &Connection,
+    fts_query: &str,
+    metadata_index: &HashMap<String, usize>,
+) -> rusqlite::Result<Vec<usize>> {
+    if !has_attachment_index(conn) {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare_cached(
+        "SELECT DISTINCT id FROM attachments_fts WHERE attachments_fts MATCH ?1",
+    )?;
+    let rows = stmt.query_map([fts_query], |row| row.get::<_, String>(0))?;
+
+    let mut indices = Vec::new();
+    for id in rows {
+        if let Some(&idx) = metadata_index.get(&id?) {
+            indices.push(idx);
+        }
+    }
+    indices.sort_unstable();
+    Ok(indices)
+}
+
+/// Messages whose attachments match the free text of `query` and that also pass
+/// its `sender`/`subject` filters; complements `build_fts_query` hits.
+pub fn attachment_text_candidates(
+    conn: &Connection,
+    query: &SearchQuery,
+    metadata_index: &HashMap<String, usize>,
+) -> rusqlite::Result<Vec<usize>> {
+    let Some(fts_query) = build_attachment_fts_query(query) else {
+        return Ok(Vec::new());
+    };
+    let mut hits = attachment_candidates(conn, &fts_query, metadata_index)?;
+    let fields = field_clauses(query).unwrap_or_default();
+    if !hits.is_empty() && !fields.is_empty() {
+        let allowed: HashSet<usize> =
+            fts_candidates(conn, &fields.join(" AND "), metadata_index, false)?
+                .into_iter()
+                .collect();
+        hits.retain(|idx| allowed.contains(idx));
+    }
+    Ok(hits)
+}
+
+#[derive(Serialize)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: usize,
+}
+
+/// Counts over the whole result set of a query (not only the current page).
+#[derive(Serialize, Default)]
+pub struct Facets {
+    pub labels: BTreeMap<String, usize>,
+    /// Most frequent sender domains, highest count first.
+    pub sender_domains: Vec<FacetCount>,
+    /// Keyed by `YYYY`.
+    pub years: BTreeMap<String, usize>,
+    /// Keyed by `YYYY-MM`.
+    pub months: BTreeMap<String, usize>,
+    pub with_attachment: usize,
+    pub without_attachment: usize,
+}
+
+const MAX_SENDER_DOMAINS: usize = 50;
+
+/// Adds up `Facets` message by message.
+pub struct FacetCounter<'a> {
+    facets: Facets,
+    domains: HashMap<String, usize>,
+    hidden_labels: &'a [String],
+}
+
+impl<'a> FacetCounter<'a> {
+    pub fn new(hidden_labels: &'a [String]) -> Self {
+        Self {
+            facets: Facets::default(),
+            domains: HashMap::new(),
+            hidden_labels,
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        labels: &[String],
+        sender_address: Option<&str>,
+        date_sent_iso: Option<&str>,
+        has_attachment: bool,
+    ) {
+        let facets = &mut self.facets;
+        for label in labels {
+            if !self.hidden_labels.contains(label) {
+                *facets.labels.entry(label.clone()).or_default() += 1;
+            }
+        }
+
+        if let Some((_, domain)) = sender_address.and_then(|a| a.rsplit_once('@')) {
+            *self.domains.entry(domain.trim().to_lowercase()).or_default() += 1;
+        }
+
+        if let Some(month) = date_sent_iso.and_then(|d| d.get(..7)) {
+            *facets.years.entry(month[..4].to_string()).or_default() += 1;
+            *facets.months.entry(month.to_string()).or_default() += 1;
+        }
+
+        if has_attachment {
+            facets.with_attachment += 1;
+        } else {
+            facets.without_attachment += 1;
+        }
+    }
+
+    pub fn finish(self) -> Facets {
+        let mut facets = self.facets;
+        let mut domains: Vec<FacetCount> = self
+            .domains
+            .into_iter()
+            .map(|(value, count)| FacetCount { value, count })
+            .collect();
+        domains.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+        domains.truncate(MAX_SENDER_DOMAINS);
+        facets.sender_domains = domains;
+        facets
+    }
+}
+
+pub fn compute_facets<T: Deref<Target = MetadataEntry>>(
+    items: &[(usize, T)],
+    hidden_labels: &[String],
+) -> Facets {
+    let mut counter = FacetCounter::new(hidden_labels);
+    for (_, entry) in items {
+        counter.add(
+            entry.gmail_labels.as_deref().unwrap_or(&[]),
+            entry.sender_address.as_deref(),
+            entry.date_sent_iso.as_deref(),
+            entry.has_attachment,
+        );
+    }
+    counter.finish()
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Returns a highlighted fragment per message id for the hits of `fts_query`.
+///
+/// The fragment is picked by FTS5 `snippet()` from the best matching column
+/// (subject, sender, recipients, attachment names or the full body if indexed)
+/// and returned as escaped HTML with the matched terms wrapped in `<mark>`.
+pub fn fts_highlights(
+    conn: &Connection,
+    fts_query: &str,
+    ids: &[&str],
+) -> rusqlite::Result<HashMap<String, String>> {
+    let mut highlights = HashMap::new();
+    if ids.is_empty() {
+        return Ok(highlights);
+    }
+
+    // Control characters as markers, so the text can be escaped before adding the tags
+    let placeholders = vec!["?"; ids.len()].join(",");
+    let sql = format!(
+        "SELECT id, snippet(messages_fts, -1, char(2), char(3), '…', 24)
+         FROM messages_fts WHERE messages_fts MATCH ? AND id IN ({})",
+        placeholders
+    );
+
+    let mut stmt = conn.prepare(&sql)?;
+    let params = std::iter::once(fts_query).chain(ids.iter().copied());
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+
+    for row in rows {
+        let (id, fragment) = row?;
+        highlights.insert(id, mark_fragment(&fragment));
+    }
+
+    Ok(highlights)
+}
+
+/// Escapes a `snippet()` fragment and turns its marker characters into `<mark>`.
+fn mark_fragment(fragment: &str) -> String {
+    escape_html(&fragment.replace(['\r', '\n'], " "))
+        .replace('\u{2}', "<mark>")
+        .replace('\u{3}', "</mark>")
+}
+
+/// An attachment whose extracted text matched a search.
+#[derive(Serialize)]
+pub struct AttachmentMatch {
+    /// Position in the message's `attachments`.
+    pub index: usize,
+    pub filename: Option<String>,
+    /// Highlighted fragment of the attachment text, escaped HTML.
+    pub fragment: String,
+}
+
+/// Matching attachments per message id for the hits of `fts_query` in
+/// `attachments_fts`, best match first.
+pub fn attachment_highlights(
+    conn: &Connection,
+    fts_query: &str,
+    ids: &[&str],
+) -> rusqlite::Result<HashMap<String, Vec<AttachmentMatch>>> {
+    let mut matches: HashMap<String, Vec<AttachmentMatch>> = HashMap::new();
+    if ids.is_empty() || !has_attachment_index(conn) {
+        return Ok(matches);
+    }
+
+    let placeholders = vec!["?"; ids.len()].join(",");
+    let sql = format!(
+        "SELECT id, attachment_index, filename, snippet(attachments_fts, 3, char(2), char(3), '…', 24)
+         FROM attachments_fts WHERE attachments_fts MATCH ? AND id IN ({}) ORDER BY rank",
+        placeholders
+    );
+
+    let mut stmt = conn.prepare(&sql)?;
+    let params = std::iter::once(fts_query).chain(ids.iter().copied());
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, Option<String>>(2)?,
+            row.get::<_, String>(3)?,
+        ))
+    })?;
+
+    for row in rows {
+        let (id, index, filename, fragment) = row?;
+        matches.entry(id).or_default().push(AttachmentMatch {
+            index: index as usize,
+            filename,
+            fragment: mark_fragment(&fragment),
+        });
+    }
+
+    Ok(matches)
+}
+
+fn sender_contains(entry: &MetadataEntry, q: &str) -> bool {
+    entry
+        .sender_name
+        .as_ref()
+        .map(|s| s.to_lowercase().contains(q))
+        .unwrap_or(false)
+        || entry
+            .sender_address
+            .as_ref()
+            .map(|s| s.to_lowercase().contains(q))
+            .unwrap_or(false)
+}
+
+/// Special labels (Spam, Papierkorb, Gesendet) are hidden from every search
+/// unless the user explicitly selected one of them in the sidebar or the label dropdown.
+pub fn searching_special(query: &SearchQuery, settings: &Settings) -> bool {
+    query
+        .label
+        .as_ref()
+        .map(|l| {
+            settings
+                .special_labels
+                .as_ref()
+                .map(|s| s.contains(l))
+                .unwrap_or(false)
+        })
+        .unwrap_or(false)
+}
+
+/// Checks a metadata entry against the filters of a query.
+///
+/// `check_text` controls whether the `any`/`sender`/`subject` filters are applied
+/// here; it is `false` when these were already answered by the FTS index.
+pub fn matches_filters(
+    entry: &MetadataEntry,
+    query: &SearchQuery,
+    settings: &Settings,
+    searching_special: bool,
+    check_text: bool,
+) -> bool {
+    // Basis-Filter für spezielle Labels
+    if !searching_special {
+        if let Some(entry_labels) = &entry.gmail_labels {
+            if let Some(special) = &settings.special_labels {
+                if entry_labels.iter().any(|l| special.contains(l)) {
+                    return false;
+                }
+            }
+        }
+    }
+
+    // Label Filter
+    if let Some(label) = &query.label {
+        if !label.is_empty() && label != "Alle Mails" {
+            if let Some(labels) = &entry.gmail_labels {
+                if !labels.contains(label) {
+                    return false;
+                }
+            } else {
+                return false;
+            }
+        }
+    }
+
+    // Attachment Filter
+    if let Some(has_att) = query.has_attachment {
+        if has_att && !entry.has_attachment {
+            return false;
+        }
+    }
+
+    // Date Filters
+    if let Some(from) = &query.date_from {
+        if !from.is_empty() {
+            if let Some(entry_date) = &entry.date_sent_iso {
+                if entry_date < from {
+                    return false;
+                }
+            } else {
+                return false;
+            }
+        }
+    }
+    if let Some(to) = &query.date_to {
+        if !to.is_empty() {
+            if let Some(entry_date) = &entry.date_sent_iso {
+                if entry_date > to {
+                    return false;
+                }
+            } else {
+                return false;
+            }
+        }
+    }
+
+    if !check_text {
+        return true;
+    }
+
+    // Subject Filter
+    if let Some(subject) = &query.subject {
+        if !subject.is_empty() {
+            let q = subject.to_lowercase();
+            if !entry
+                .subject
+                .as_ref()
+                .map(|s| s.to_lowercase().contains(&q))
+                .unwrap_or(false)
+            {
+                return false;
+            }
+        }
+    }
+
+    // Any Filter (Subject/Sender)
+    if let Some(any) = &query.any {
+        if !any.is_empty() {
+            let q = any.to_lowercase();
+            let subject_match = entry
+                .subject
+                .as_ref()
+                .map(|s| s.to_lowercase().contains(&q))
+                .unwrap_or(false);
+            if !subject_match && !sender_contains(entry, &q) {
+                return false;
+            }
+        }
+    }
+
+    // Sender Filter
+    if let Some(sender) = &query.sender {
+        if !sender.is_empty() && !sender_contains(entry, &sender.to_lowercase()) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// The filters of `matches_filters` as SQL conditions on `messages`. Text filters
+/// go through the FTS5 index like `build_fts_query` and
+/// `attachment_text_candidates` if they can be expressed there.
+pub fn filters_sql(
+    query: &SearchQuery,
+    settings: &Settings,
+    searching_special: bool,
+    attachment_index: bool,
+    filter: &mut Filter,
+) {
+    if !searching_special {
+        if let Some(special) = settings.special_labels.as_ref().filter(|s| !s.is_empty()) {
+            let placeholders: Vec<String> =
+                special.iter().map(|l| filter.param(l.clone())).collect();
+            filter.push(format!(
+                "NOT {}",
+                any_element_sql("$.gmail_labels", &format!("value IN ({})", placeholders.join(", ")))
+            ));
+        }
+    }
+
+    if let Some(label) = query.label.as_ref().filter(|l| !l.is_empty() && *l != "Alle Mails") {
+        let label = filter.param(label.clone());
+        filter.push(any_element_sql("$.gmail_labels", &format!("value = {}", label)));
+    }
+    if query.has_attachment == Some(true) {
+        filter.push("messages.has_attachment IS 1".to_string());
+    }
+    if let Some(from) = query.date_from.as_ref().filter(|d| !d.is_empty()) {
+        let from = filter.param(from.clone());
+        filter.push(format!("messages.date_sent_iso >= {}", from));
+    }
+    if let Some(to) = query.date_to.as_ref().filter(|d| !d.is_empty()) {
+        let to = filter.param(to.clone());
+        filter.push(format!("messages.date_sent_iso <= {}", to));
+    }
+
+    if let Some(fts_query) = build_fts_query(query) {
+        let fts_query = filter.param(fts_query);
+        let mut text = format!(
+            "messages.id IN (SELECT id FROM messages_fts WHERE messages_fts MATCH {})",
+            fts_query
+        );
+        if let Some(attachment_query) = build_attachment_fts_query(query).filter(|_| attachment_index)
+        {
+            let attachment_query = filter.param(attachment_query);
+            let mut attachment = format!(
+                "messages.id IN (SELECT id FROM attachments_fts WHERE attachments_fts MATCH {})",
+                attachment_query
+            );
+            let fields = field_clauses(query).unwrap_or_default();
+            if !fields.is_empty() {
+                let fields = filter.param(fields.join(" AND "));
+                attachment.push_str(&format!(
+                    " AND messages.id IN (SELECT id FROM messages_fts WHERE messages_fts MATCH {})",
+                    fields
+                ));
+            }
+            text = format!("{} OR ({})", text, attachment);
+        }
+        filter.push(format!("({})", text));
+        return;
+    }
+
+    let given = |value: &Option<String>| value.clone().filter(|v| !v.is_empty());
+    if let Some(subject) = given(&query.subject) {
+        let subject = filter.param(subject.to_lowercase());
+        filter.push(contains_sql("messages.subject", &subject));
+    }
+    if let Some(any) = given(&query.any) {
+        let any = filter.param(any.to_lowercase());
+        filter.push(format!(
+            "({} OR {} OR {})",
+            contains_sql("messages.subject", &any),
+            contains_sql("messages.sender_name", &any),
+            contains_sql("messages.sender_address", &any)
+        ));
+    }
+    if let Some(sender) = given(&query.sender) {
+        let sender = filter.param(sender.to_lowercase());
+        filter.push(format!(
+            "({} OR {})",
+            contains_sql("messages.sender_name", &sender),
+            contains_sql("messages.sender_address", &sender)
+        ));
+    }
+}
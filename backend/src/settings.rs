@@ -8,6 +8,14 @@ pub struct Settings {
     pub filter_labels: Option<Vec<String>>,
     pub special_labels: Option<Vec<String>>,
     pub browser: Option<String>,
+    /// Sender domains whose mails load remote images without asking.
+    pub trusted_sender_domains: Option<Vec<String>>,
+    /// Read message metadata from metadata.db on demand instead of loading
+    /// metadata.json; needs a writable `cache_dir`.
+    pub lazy_metadata: Option<bool>,
+    /// Directory for the extracted database and the metadata cache of each
+    /// archive; defaults to the user's cache directory.
+    pub cache_dir: Option<String>,
     #[serde(skip)]
     pub source_path: Option<std::path::PathBuf>,
 }
@@ -54,7 +62,8 @@ impl Settings {
 
         // 3. Try AppData/Application Support
         if actual_path.is_none() {
-            let mut app_data_path = None;
+            #[allow(unused_mut)]
+            let mut app_data_path: Option<std::path::PathBuf> = None;
             #[cfg(target_os = "windows")]
             {
                 if let Ok(appdata) = std::env::var("APPDATA") {
@@ -101,8 +110,23 @@ impl Settings {
                     "Gesendet".to_string(),
                 ]),
                 browser: None,
+                trusted_sender_domains: None,
+                lazy_metadata: None,
+                cache_dir: None,
                 source_path: None,
             })
         }
     }
+
+    /// True if the address belongs to a trusted domain or one of its subdomains.
+    pub fn is_trusted_sender(&self, address: &str) -> bool {
+        let Some(domain) = address.rsplit_once('@').map(|(_, d)| d.to_lowercase()) else {
+            return false;
+        };
+        self.trusted_sender_domains.iter().flatten().any(|trusted| {
+            let trusted = trusted.trim().trim_start_matches('@').to_lowercase();
+            !trusted.is_empty()
+                && (domain == trusted || domain.ends_with(&format!(".{}", trusted)))
+        })
+    }
 }
@@ -1,13 +1,15 @@
+use crate::archive::Archive;
+use crate::metadata_db;
 use crate::model::MetadataEntry;
+use crate::search::SortKeys;
 use rusqlite::Connection;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fs::File;
-use std::sync::{Arc, Mutex};
-use zip::ZipArchive;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 
 use crate::settings::Settings;
 
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 #[derive(Clone, Default, serde::Serialize)]
 pub struct ConversionStatus {
@@ -19,43 +21,78 @@ pub struct ConversionStatus {
     pub error: Option<String>,
 }
 
-pub struct AppData {
-    pub settings: Settings,
-    pub metadata: Vec<MetadataEntry>,
-    pub metadata_index: HashMap<String, usize>,
+/// Where `AppData` takes the message metadata from.
+pub enum MetadataSource {
+    /// Parsed from metadata.json.
+    Entries(Vec<MetadataEntry>),
+    /// Read on demand from metadata.db, which holds this many messages.
+    Database(usize),
+}
+
+/// Metadata parsed from metadata.json, with the indexes built at load time.
+pub struct LoadedMetadata {
+    pub entries: Vec<MetadataEntry>,
+    pub index: HashMap<String, usize>,
+    /// Positions in `entries` per thread id, ordered by date.
+    pub threads: HashMap<String, Vec<usize>>,
+    pub sort_keys: SortKeys,
     pub labels: Vec<String>,
-    pub db_conn: Option<Connection>,
-    pub zip_archive: Option<ZipArchive<File>>,
 }
 
-#[derive(Clone)]
-pub struct AppState {
-    pub data: Arc<Mutex<AppData>>,
-    pub instance_id: Arc<Mutex<String>>,
-    pub conversion_status: Arc<Mutex<ConversionStatus>>,
-    pub conversion_abort: Arc<AtomicBool>,
-    pub is_loading: Arc<AtomicBool>,
-    pub log_tx: Arc<Mutex<Option<tokio::sync::mpsc::UnboundedSender<String>>>>,
+pub enum Metadata {
+    Loaded(Box<LoadedMetadata>),
+    /// See `metadata_db`; startup does not depend on the archive size, every
+    /// access is a query.
+    Database {
+        count: usize,
+    },
 }
 
-impl AppState {
-    pub fn new(
-        settings: Settings,
-        metadata: Vec<MetadataEntry>,
-        db_conn: Option<Connection>,
-        zip_archive: Option<ZipArchive<File>>,
-        log_tx: Option<tokio::sync::mpsc::UnboundedSender<String>>,
-    ) -> Self {
-        let instance_id = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs()
-            .to_string();
+/// Connections to metadata.db. A SQLite connection is not `Sync`, so each request
+/// locks one of its own; only with more concurrent requests than connections do
+/// they wait for each other.
+pub struct DbPool {
+    conns: Vec<Mutex<Connection>>,
+    next: AtomicUsize,
+}
+
+impl DbPool {
+    /// `None` without connections.
+    fn new(conns: Vec<Connection>) -> Option<Self> {
+        (!conns.is_empty()).then(|| Self {
+            conns: conns.into_iter().map(Mutex::new).collect(),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// A connection that is not in use, or the next one in turn if all are.
+    fn get(&self) -> MutexGuard<'_, Connection> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.conns.len();
+        (0..len)
+            .find_map(|i| self.conns[(start + i) % len].try_lock().ok())
+            .unwrap_or_else(|| self.conns[start % len].lock().unwrap())
+    }
+}
+
+/// Everything loaded from one archive. Never modified after loading; a reload
+/// replaces the whole snapshot (see `AppState::apply_new_data`).
+pub struct AppData {
+    pub settings: Settings,
+    pub metadata: Metadata,
+    pub db_pool: Option<DbPool>,
+    /// Cloned per request, clones read independently.
+    pub zip_archive: Option<Archive>,
+    /// Identifies the archive and its state on disk, see `cache::metadata_fingerprint`.
+    pub fingerprint: Option<String>,
+}
 
+impl LoadedMetadata {
+    fn build(entries: Vec<MetadataEntry>) -> Self {
         let mut index = HashMap::new();
         let mut label_set = std::collections::HashSet::new();
 
-        for (i, entry) in metadata.iter().enumerate() {
+        for (i, entry) in entries.iter().enumerate() {
             index.insert(entry.id.clone(), i);
             if let Some(entry_labels) = &entry.gmail_labels {
                 for label in entry_labels {
@@ -63,21 +100,197 @@ impl AppState {
                 }
             }
         }
+        let threads = build_thread_index(&entries);
+        let sort_keys = SortKeys::build(&entries);
 
         let mut labels: Vec<String> = label_set.into_iter().collect();
         labels.sort();
 
-        let data = AppData {
+        Self {
+            entries,
+            index,
+            threads,
+            sort_keys,
+            labels,
+        }
+    }
+}
+
+impl AppData {
+    fn build(
+        settings: Settings,
+        metadata: MetadataSource,
+        db_conns: Vec<Connection>,
+        zip_archive: Option<Archive>,
+        fingerprint: Option<String>,
+    ) -> Self {
+        let metadata = match metadata {
+            MetadataSource::Entries(entries) => {
+                Metadata::Loaded(Box::new(LoadedMetadata::build(entries)))
+            }
+            MetadataSource::Database(count) => {
+                for conn in &db_conns {
+                    if let Err(e) = metadata_db::register_functions(conn) {
+                        eprintln!("Registering SQL functions failed: {}", e);
+                    }
+                }
+                Metadata::Database { count }
+            }
+        };
+        Self {
             settings,
             metadata,
-            metadata_index: index,
-            labels,
-            db_conn,
+            db_pool: DbPool::new(db_conns),
             zip_archive,
+            fingerprint,
+        }
+    }
+
+    /// A metadata.db connection of the pool, locked for the lifetime of the guard.
+    pub fn db(&self) -> Option<MutexGuard<'_, Connection>> {
+        self.db_pool.as_ref().map(DbPool::get)
+    }
+
+    /// An archive reader of its own for the calling request.
+    pub fn archive(&self) -> Option<Archive> {
+        self.zip_archive.clone()
+    }
+
+    pub fn message_count(&self) -> usize {
+        match &self.metadata {
+            Metadata::Loaded(loaded) => loaded.entries.len(),
+            Metadata::Database { count } => *count,
+        }
+    }
+
+    /// Entry at position `idx` in archive order.
+    pub fn entry(&self, idx: usize) -> Option<Cow<'_, MetadataEntry>> {
+        match &self.metadata {
+            Metadata::Loaded(loaded) => loaded.entries.get(idx).map(Cow::Borrowed),
+            Metadata::Database { .. } => metadata_db::entry(&*self.db()?, idx).map(Cow::Owned),
+        }
+    }
+
+    pub fn position(&self, id: &str) -> Option<usize> {
+        match &self.metadata {
+            Metadata::Loaded(loaded) => loaded.index.get(id).copied(),
+            Metadata::Database { .. } => metadata_db::position(&*self.db()?, id),
+        }
+    }
+
+    pub fn entry_by_id(&self, id: &str) -> Option<Cow<'_, MetadataEntry>> {
+        self.entry(self.position(id)?)
+    }
+
+    /// First entry, in archive order, with an attachment of this content hash.
+    pub fn entry_with_attachment(&self, sha256: &str) -> Option<Cow<'_, MetadataEntry>> {
+        match &self.metadata {
+            Metadata::Loaded(loaded) => loaded
+                .entries
+                .iter()
+                .find(|entry| {
+                    entry
+                        .attachments
+                        .iter()
+                        .flatten()
+                        .any(|a| a.sha256.as_deref() == Some(sha256))
+                })
+                .map(Cow::Borrowed),
+            Metadata::Database { .. } => {
+                metadata_db::entry_with_attachment(&*self.db()?, sha256).map(Cow::Owned)
+            }
+        }
+    }
+
+    /// Members of a thread ordered by date, `None` for unknown threads.
+    pub fn thread(&self, thread_id: &str) -> Option<Vec<Cow<'_, MetadataEntry>>> {
+        match &self.metadata {
+            Metadata::Loaded(loaded) => Some(
+                loaded
+                    .threads
+                    .get(thread_id)?
+                    .iter()
+                    .filter_map(|&idx| loaded.entries.get(idx).map(Cow::Borrowed))
+                    .collect(),
+            ),
+            Metadata::Database { .. } => {
+                let mut members = metadata_db::thread_entries(&*self.db()?, thread_id).ok()?;
+                if members.is_empty() {
+                    return None;
+                }
+                members.sort_by_key(|(idx, entry)| (entry.timestamp(), *idx));
+                Some(members.into_iter().map(|(_, e)| Cow::Owned(e)).collect())
+            }
+        }
+    }
+
+    /// Number of messages in a thread, 1 for unknown threads.
+    pub fn thread_size(&self, thread_id: &str) -> usize {
+        let size = match &self.metadata {
+            Metadata::Loaded(loaded) => loaded.threads.get(thread_id).map(Vec::len),
+            Metadata::Database { .. } => self
+                .db()
+                .and_then(|conn| metadata_db::thread_size(&conn, thread_id).ok()),
         };
+        size.filter(|&n| n > 0).unwrap_or(1)
+    }
+
+    /// All labels of the archive, sorted.
+    pub fn labels(&self) -> Vec<String> {
+        match &self.metadata {
+            Metadata::Loaded(loaded) => loaded.labels.clone(),
+            Metadata::Database { .. } => self
+                .db()
+                .and_then(|conn| metadata_db::labels(&conn).ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Groups the metadata by thread id; each thread is ordered by date.
+fn build_thread_index(metadata: &[MetadataEntry]) -> HashMap<String, Vec<usize>> {
+    let mut threads: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, entry) in metadata.iter().enumerate() {
+        if let Some(thread_id) = &entry.thread_id {
+            threads.entry(thread_id.clone()).or_default().push(i);
+        }
+    }
+    for members in threads.values_mut() {
+        members.sort_by_key(|&i| metadata[i].timestamp());
+    }
+    threads
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    /// Current snapshot; handlers take a clone of the `Arc` via `snapshot`.
+    pub data: Arc<RwLock<Arc<AppData>>>,
+    pub instance_id: Arc<Mutex<String>>,
+    pub conversion_status: Arc<Mutex<ConversionStatus>>,
+    pub conversion_abort: Arc<AtomicBool>,
+    pub is_loading: Arc<AtomicBool>,
+    pub log_tx: Arc<Mutex<Option<tokio::sync::mpsc::UnboundedSender<String>>>>,
+}
+
+impl AppState {
+    pub fn new(
+        settings: Settings,
+        metadata: MetadataSource,
+        db_conns: Vec<Connection>,
+        zip_archive: Option<Archive>,
+        fingerprint: Option<String>,
+        log_tx: Option<tokio::sync::mpsc::UnboundedSender<String>>,
+    ) -> Self {
+        let instance_id = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string();
+
+        let data = AppData::build(settings, metadata, db_conns, zip_archive, fingerprint);
 
         Self {
-            data: Arc::new(Mutex::new(data)),
+            data: Arc::new(RwLock::new(Arc::new(data))),
             instance_id: Arc::new(Mutex::new(instance_id)),
             conversion_status: Arc::new(Mutex::new(ConversionStatus::default())),
             conversion_abort: Arc::new(AtomicBool::new(false)),
@@ -86,41 +299,29 @@ impl AppState {
         }
     }
 
+    /// The currently loaded data. Requests keep working on their snapshot even if
+    /// another archive is loaded meanwhile.
+    pub fn snapshot(&self) -> Arc<AppData> {
+        self.data.read().unwrap().clone()
+    }
+
     /// Hot-reloads data from a new settings object without restarting the server.
     pub fn apply_new_data(
         &self,
         new_settings: Settings,
-        new_metadata: Vec<MetadataEntry>,
-        new_db_conn: Option<Connection>,
-        new_zip_archive: Option<ZipArchive<File>>,
+        new_metadata: MetadataSource,
+        new_db_conns: Vec<Connection>,
+        new_zip_archive: Option<Archive>,
+        new_fingerprint: Option<String>,
     ) {
-        let mut index = HashMap::new();
-        let mut label_set = std::collections::HashSet::new();
-
-        for (i, entry) in new_metadata.iter().enumerate() {
-            index.insert(entry.id.clone(), i);
-            if let Some(entry_labels) = &entry.gmail_labels {
-                for label in entry_labels {
-                    label_set.insert(label.clone());
-                }
-            }
-        }
-
-        let mut labels: Vec<String> = label_set.into_iter().collect();
-        labels.sort();
-
-        // Set loading state to true while swapping
-        self.is_loading
-            .store(true, std::sync::atomic::Ordering::SeqCst);
-
-        // Swap everything under one lock
-        let mut data = self.data.lock().unwrap();
-        data.settings = new_settings;
-        data.metadata = new_metadata;
-        data.metadata_index = index;
-        data.labels = labels;
-        data.db_conn = new_db_conn;
-        data.zip_archive = new_zip_archive;
+        let data = AppData::build(
+            new_settings,
+            new_metadata,
+            new_db_conns,
+            new_zip_archive,
+            new_fingerprint,
+        );
+        *self.data.write().unwrap() = Arc::new(data);
 
         // Update instance_id to trigger frontend notifications if needed
         *self.instance_id.lock().unwrap() = std::time::SystemTime::now()
@@ -128,8 +329,5 @@ impl AppState {
             .unwrap_or_default()
             .as_secs()
             .to_string();
-
-        self.is_loading
-            .store(false, std::sync::atomic::Ordering::SeqCst);
     }
 }
@@ -16,14 +16,103 @@ export async function searchMessages(query = {}) {
     return res.json();
 }
 
-export async function getMessage(id) {
-    const res = await fetch(`${BASE_URL}/messages/${encodeURIComponent(id)}`);
+async function downloadPost(path, body, fallbackName) {
+    const res = await fetch(`${BASE_URL}${path}`, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify(body)
+    });
+    if (!res.ok) throw new Error(await res.text() || 'Export failed');
+    const disposition = res.headers.get('Content-Disposition') || '';
+    const match = disposition.match(/filename="([^"]+)"/);
+    const url = URL.createObjectURL(await res.blob());
+    const a = document.createElement('a');
+    a.href = url;
+    a.download = match ? match[1] : fallbackName;
+    a.click();
+    URL.revokeObjectURL(url);
+}
+
+// Downloads the messages matching `query` (or `query.ids`) as mboxrd file
+export async function exportMbox(query = {}) {
+    const { limit, offset, ...filter } = query;
+    return downloadPost('/export/mbox', filter, 'export.mbox');
+}
+
+// Downloads the matching messages as ZIP of .eml files with manifest.csv
+export async function exportEml(query = {}) {
+    const { limit, offset, ...filter } = query;
+    return downloadPost('/export/eml', filter, 'export.zip');
+}
+
+// Lists attachments across the archive.
+// params: { mime, extension, filename, sender, label, date_from, date_to, sort_by, order, limit, offset }
+export async function listAttachments(params = {}) {
+    const search = new URLSearchParams();
+    for (const [key, value] of Object.entries(params)) {
+        if (value !== undefined && value !== null && value !== '') search.set(key, value);
+    }
+    const res = await fetch(`${BASE_URL}/attachments?${search}`);
+    if (!res.ok) throw new Error('Failed to list attachments');
+    return res.json();
+}
+
+// Downloads all attachments of the matching messages as ZIP.
+// options: { mime, extension, folders: "message" | "sender" | "none" }
+export async function exportAttachments(query = {}, options = {}) {
+    const { limit, offset, ...filter } = query;
+    return downloadPost('/attachments/export', { ...filter, ...options }, 'attachments.zip');
+}
+
+// Downloads the metadata of the matching messages (format: csv, jsonl or columnar)
+export async function exportMetadata(query = {}, format = 'csv') {
+    const { limit, offset, ...filter } = query;
+    return downloadPost('/export/metadata', { ...filter, format }, `metadata.${format}`);
+}
+
+export async function getMessage(id, allowRemote = false) {
+    const query = allowRemote ? '?allow_remote=true' : '';
+    const res = await fetch(`${BASE_URL}/messages/${encodeURIComponent(id)}${query}`);
     if (!res.ok) throw new Error('Failed to fetch message');
     return res.json();
 }
 
-export function getAttachmentUrl(messageId, filename) {
-    return `${BASE_URL}/messages/${encodeURIComponent(messageId)}/attachment/${encodeURIComponent(filename)}`;
+export async function getThread(threadId) {
+    const res = await fetch(`${BASE_URL}/threads/${encodeURIComponent(threadId)}`);
+    if (!res.ok) throw new Error('Failed to fetch thread');
+    return res.json();
+}
+
+// Printable page of a message, or of its whole conversation if threadId is given
+export function getPrintUrl(messageId, threadId = null) {
+    return threadId
+        ? `${BASE_URL}/threads/${encodeURIComponent(threadId)}/print`
+        : `${BASE_URL}/messages/${encodeURIComponent(messageId)}/print`;
+}
+
+export function getRawMessageUrl(messageId, download = false) {
+    const query = download ? '?download=true' : '';
+    return `${BASE_URL}/messages/${encodeURIComponent(messageId)}/raw${query}`;
+}
+
+export async function getMessageHeaders(id) {
+    const res = await fetch(`${BASE_URL}/messages/${encodeURIComponent(id)}/headers`);
+    if (!res.ok) throw new Error('Failed to fetch headers');
+    return res.json();
+}
+
+export function getAttachmentUrl(messageId, index) {
+    return `${BASE_URL}/messages/${encodeURIComponent(messageId)}/attachment/by-index/${index}`;
+}
+
+// Inline preview: thumbnail for images (size = longest side), HTML for text, CSV and PDF
+export function getAttachmentPreviewUrl(messageId, index, size = null) {
+    const query = size ? `?size=${size}` : '';
+    return `${BASE_URL}/messages/${encodeURIComponent(messageId)}/attachment/by-index/${index}/preview${query}`;
+}
+
+export function getAttachmentByHashUrl(sha256) {
+    return `${BASE_URL}/attachments/by-hash/${sha256}`;
 }
 
 export async function getSystemInfo() {
@@ -0,0 +1,598 @@
+//! Appending a newer MBOX export to an existing MBXC archive.
+//!
+//! Messages are matched by Message-ID, or by a hash of their content without the
+//! MBOX `From ` line and Gmail's per-export headers if they have none. A message
+//! whose Message-ID is archived with different content counts as new. Messages
+//! already in the archive keep their id and only take over the labels of the new
+//! export; new ones get the next free `msg_{:06}.eml` ids. The archive is written anew: existing entries are copied
+//! without recompressing, metadata.json and metadata.db are replaced.
+
+use crate::dedup::{read_message, sha256_hex};
+use crate::export::read_metadata;
+use crate::parser::{strip_from_line, MetadataEntry};
+use crate::pipeline::{self, Prepared};
+use crate::{db, threading, ConvertOptions, ProgressCallback};
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+/// Result of `append_mbox_to_mbxc`.
+#[derive(Debug, Default)]
+pub struct AppendSummary {
+    /// Messages new in the MBOX, appended to the archive.
+    pub added: usize,
+    /// Archived messages whose labels changed; each copy of a message archived
+    /// more than once counts.
+    pub relabeled: usize,
+    /// Archived messages found unchanged in the MBOX, counted the same way.
+    pub unchanged: usize,
+}
+
+fn message_id(entry: &MetadataEntry) -> Option<&str> {
+    entry
+        .message_id
+        .as_deref()
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+}
+
+/// Headers Gmail sets per export; they change without the message changing.
+const VOLATILE_HEADERS: &[&str] = &["x-gmail-labels", "x-gm-thrid", "x-gm-msgid"];
+
+/// The message without the MBOX `From ` line and the headers in
+/// `VOLATILE_HEADERS`, for comparing messages across exports.
+fn stable_content(eml: &[u8]) -> Vec<u8> {
+    let data = strip_from_line(eml);
+    let mut content = Vec::with_capacity(data.len());
+    let mut skipping = false;
+    let mut rest = data;
+    while !rest.is_empty() {
+        let end = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(rest.len(), |p| p + 1);
+        let (line, next) = rest.split_at(end);
+        if line.trim_ascii().is_empty() {
+            // End of the headers, the body is kept as it is
+            content.extend_from_slice(rest);
+            break;
+        }
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            let name = line.split(|&b| b == b':').next().unwrap_or_default();
+            let name = String::from_utf8_lossy(name).trim().to_lowercase();
+            skipping = VOLATILE_HEADERS.contains(&name.as_str());
+        }
+        if !skipping {
+            content.extend_from_slice(line);
+        }
+        rest = next;
+    }
+    content.truncate(content.trim_ascii_end().len());
+    content
+}
+
+fn content_hash(eml: &[u8]) -> String {
+    sha256_hex(&stable_content(eml))
+}
+
+/// Identifies a message across exports.
+fn message_key(entry: &MetadataEntry, eml: &[u8]) -> String {
+    match message_id(entry) {
+        Some(message_id) => format!("id:{}", message_id),
+        None => format!("sha256:{}", content_hash(eml)),
+    }
+}
+
+/// Number in an id of the form `msg_000042.eml`.
+fn id_number(id: &str) -> Option<u64> {
+    id.strip_prefix("msg_")?.strip_suffix(".eml")?.parse().ok()
+}
+
+/// Appends the messages of `input_path` that are not yet in the archive at
+/// `archive_path` and updates the labels of the others. The result is written to
+/// `output_path`, which may be `archive_path` itself; it is replaced only once the
+/// new archive is complete. Returns `None` if `abort_flag` was set.
+pub fn append_mbox_to_mbxc(
+    archive_path: &Path,
+    input_path: &Path,
+    output_path: &Path,
+    options: &ConvertOptions,
+    progress_callback: Option<ProgressCallback>,
+    abort_flag: Arc<AtomicBool>,
+) -> Result<Option<AppendSummary>> {
+    let mut archive = ZipArchive::new(File::open(archive_path).context("Failed to open MBXC")?)?;
+    let mut metadata_entries = read_metadata(&mut archive)?;
+
+    // Archives can hold a message more than once, all copies get new labels
+    let mut known: HashMap<String, Vec<usize>> = HashMap::new();
+    for (position, entry) in metadata_entries.iter().enumerate() {
+        // Only messages without Message-ID need their content
+        let key = if message_id(entry).is_some() {
+            message_key(entry, &[])
+        } else {
+            message_key(entry, &read_message(&mut archive, &entry.id)?)
+        };
+        known.entry(key).or_default().push(position);
+    }
+    let archived_ids: Vec<String> = metadata_entries.iter().map(|e| e.id.clone()).collect();
+    let mut next_number = metadata_entries
+        .iter()
+        .filter_map(|e| id_number(&e.id))
+        .max()
+        .unwrap_or(0)
+        + 1;
+
+    // Work on a copy of the database, the archive stays untouched until the end
+    let temp_db_path = tempfile::Builder::new().suffix(".db").tempfile()?;
+    std::io::copy(
+        &mut archive
+            .by_name("metadata.db")
+            .context("metadata.db not found in archive")?,
+        &mut File::create(temp_db_path.path())?,
+    )?;
+    let mut conn = Connection::open(temp_db_path.path())?;
+    conn.execute_batch(
+        "PRAGMA journal_mode = OFF;
+         PRAGMA synchronous = OFF;
+         PRAGMA cache_size = 100000;
+         PRAGMA locking_mode = EXCLUSIVE;
+         PRAGMA temp_store = MEMORY;",
+    )?;
+    db::upgrade_db(&conn)?;
+    let tx = conn.transaction()?;
+
+    let output_dir = match output_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let output = tempfile::NamedTempFile::new_in(&output_dir)
+        .context("Failed to create output MBXC archive")?;
+    let mut zip = ZipWriter::new(BufWriter::with_capacity(
+        1024 * 1024,
+        output.as_file().try_clone()?,
+    ));
+
+    // Messages, attachment blobs and references are kept as they are
+    let mut stored_blobs = HashSet::new();
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        if matches!(file.name(), "metadata.json" | "metadata.db") {
+            continue;
+        }
+        if let Some(sha256) = file.name().strip_prefix("attachments/") {
+            stored_blobs.insert(sha256.to_string());
+        }
+        zip.raw_copy_file(file)?;
+    }
+    let stored_blobs = Mutex::new(stored_blobs);
+
+    let input_file = File::open(input_path).context("Failed to open input MBOX")?;
+    let total_size = input_file.metadata()?.len();
+    let threads = options.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+
+    // Content hashes of archived messages by position, read when their
+    // Message-ID comes up in the new export
+    let archived = Mutex::new((&mut archive, HashMap::<usize, Option<String>>::new()));
+    let find_known = |entry: &MetadataEntry, eml: &[u8]| -> Option<Vec<usize>> {
+        let positions = known.get(&message_key(entry, eml))?;
+        if message_id(entry).is_none() {
+            return Some(positions.clone());
+        }
+        // Different messages can share a Message-ID, the content decides
+        let hash = content_hash(eml);
+        let mut archived = archived.lock().unwrap();
+        let (archive, hashes) = &mut *archived;
+        let same: Vec<usize> = positions
+            .iter()
+            .copied()
+            .filter(|&position| {
+                hashes
+                    .entry(position)
+                    .or_insert_with(|| {
+                        read_message(archive, &archived_ids[position])
+                            .ok()
+                            .map(|eml| content_hash(&eml))
+                    })
+                    .as_deref()
+                    // An unreadable copy is not duplicated
+                    .is_none_or(|archived| archived == hash)
+            })
+            .collect();
+        (!same.is_empty()).then_some(same)
+    };
+
+    let mut summary = AppendSummary::default();
+    let mut cumulative_bytes = 0;
+    let mut total_count = 0;
+
+    let completed = pipeline::for_each_message(
+        input_file,
+        options,
+        threads,
+        &stored_blobs,
+        &find_known,
+        &abort_flag,
+        |message| {
+            cumulative_bytes += message.mbox_size;
+            total_count = message.number;
+
+            match message.content {
+                Prepared::Unparsable => {}
+                Prepared::Known(positions, parsed) => {
+                    for position in positions {
+                        let entry = &mut metadata_entries[position];
+                        if entry.gmail_labels != parsed.gmail_labels {
+                            entry.gmail_labels = parsed.gmail_labels.clone();
+                            db::update_labels(&tx, entry)?;
+                            summary.relabeled += 1;
+                        } else {
+                            summary.unchanged += 1;
+                        }
+                    }
+                }
+                Prepared::New(mut content) => {
+                    content.metadata.id = format!("msg_{:06}.eml", next_number);
+                    next_number += 1;
+
+                    let id = &content.metadata.id;
+                    db::insert_metadata(&tx, &content.metadata, content.body.as_deref())?;
+                    let attachments = content.metadata.attachments.as_deref().unwrap_or(&[]);
+                    for (index, text) in &content.attachment_texts {
+                        let filename = attachments.get(*index).and_then(|a| a.filename.as_deref());
+                        db::insert_attachment_text(&tx, id, *index, filename, text)?;
+                    }
+
+                    let metadata = content.metadata.clone();
+                    content.write_to(&mut zip, |sha256| {
+                        stored_blobs.lock().unwrap().insert(sha256.to_string())
+                    })?;
+                    metadata_entries.push(metadata);
+                    summary.added += 1;
+                }
+            }
+
+            if message.number.is_multiple_of(250) || cumulative_bytes >= total_size {
+                if let Some(ref cb) = progress_callback {
+                    cb(cumulative_bytes, total_size, message.number);
+                }
+            }
+            Ok(())
+        },
+    )?;
+    drop(archived);
+    if !completed {
+        return Ok(None); // Aborted, the archive is unchanged
+    }
+
+    if let Some(ref cb) = progress_callback {
+        cb(cumulative_bytes, total_size, total_count);
+    }
+
+    // New messages can join existing conversations
+    threading::assign_thread_ids(&mut metadata_entries);
+    db::update_metadata(&tx, &metadata_entries)?;
+    tx.commit()?;
+
+    zip.start_file("metadata.json", FileOptions::default())?;
+    serde_json::to_writer(&mut zip, &metadata_entries)?;
+
+    drop(conn);
+    zip.start_file("metadata.db", FileOptions::default())?;
+    std::io::copy(&mut File::open(temp_db_path.path())?, &mut zip)?;
+    zip.finish()?.flush()?;
+
+    drop(archive);
+    // Temporary files are private, the archive keeps its permissions
+    std::fs::set_permissions(
+        output.path(),
+        std::fs::metadata(archive_path)?.permissions(),
+    )?;
+    output
+        .persist(output_path)
+        .context("Failed to replace MBXC archive")?;
+    Ok(Some(summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::extract_metadata;
+    use std::path::PathBuf;
+
+    fn message(n: u32, message_id: Option<&str>, labels: &str) -> String {
+        let mut eml = format!(
+            "From sender@example.com Mon Jan 01 00:00:00 2024\n\
+             From: Sender <sender@example.com>\n\
+             To: me@example.com\n\
+             Subject: Message {n}\n\
+             Date: Mon, 01 Jan 2024 10:00:00 +0000\n"
+        );
+        if let Some(id) = message_id {
+            eml.push_str(&format!("Message-ID: <{}>\n", id));
+        }
+        eml.push_str(&format!("X-Gmail-Labels: {}\n\nBody text{n}\n\n", labels));
+        eml
+    }
+
+    fn write_mbox(dir: &Path, name: &str, messages: &[String]) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, messages.concat()).unwrap();
+        path
+    }
+
+    /// Archive in the format written before bodies, threads and metadata were
+    /// stored in metadata.db.
+    fn baseline_archive(dir: &Path, messages: &[String]) -> PathBuf {
+        let db_path = dir.join("baseline.db");
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE messages (
+                id TEXT PRIMARY KEY, subject TEXT, sender_name TEXT, sender_address TEXT,
+                date_sent_iso TEXT, has_attachment INTEGER, labels TEXT
+            );
+            CREATE INDEX idx_messages_date ON messages(date_sent_iso);
+            CREATE VIRTUAL TABLE messages_fts USING fts5(
+                id UNINDEXED, subject, sender_name, sender_address, recipients, snippet,
+                attachment_names
+            );",
+        )
+        .unwrap();
+
+        let path = dir.join("archive.mbxc");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let mut entries = Vec::new();
+        for (i, eml) in messages.iter().enumerate() {
+            let id = format!("msg_{:06}.eml", i + 1);
+            let entry = extract_metadata(eml.as_bytes(), id.clone()).unwrap();
+            conn.execute(
+                "INSERT INTO messages (id, subject, labels) VALUES (?1, ?2, ?3)",
+                rusqlite::params![
+                    id,
+                    entry.subject,
+                    entry.gmail_labels.as_ref().map(|l| l.join(" "))
+                ],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO messages_fts (id, subject) VALUES (?1, ?2)",
+                rusqlite::params![id, entry.subject],
+            )
+            .unwrap();
+            zip.start_file(&id, FileOptions::default()).unwrap();
+            zip.write_all(eml.as_bytes()).unwrap();
+            entries.push(entry);
+        }
+        drop(conn);
+
+        zip.start_file("metadata.json", FileOptions::default())
+            .unwrap();
+        serde_json::to_writer(&mut zip, &entries).unwrap();
+        zip.start_file("metadata.db", FileOptions::default())
+            .unwrap();
+        zip.write_all(&std::fs::read(&db_path).unwrap()).unwrap();
+        zip.finish().unwrap();
+        path
+    }
+
+    fn append(archive: &Path, mbox: &Path) -> AppendSummary {
+        let options = ConvertOptions {
+            threads: Some(2),
+            ..ConvertOptions::default()
+        };
+        append_mbox_to_mbxc(archive, mbox, archive, &options, None, Arc::default())
+            .unwrap()
+            .unwrap()
+    }
+
+    fn archived_db(archive: &Path, dir: &Path) -> Connection {
+        let mut zip = ZipArchive::new(File::open(archive).unwrap()).unwrap();
+        let path = dir.join("appended.db");
+        std::io::copy(
+            &mut zip.by_name("metadata.db").unwrap(),
+            &mut File::create(&path).unwrap(),
+        )
+        .unwrap();
+        Connection::open(path).unwrap()
+    }
+
+    #[test]
+    fn appends_to_baseline_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = message(1, Some("one@example.com"), "Inbox");
+        let archive = baseline_archive(dir.path(), std::slice::from_ref(&old));
+        let mbox = write_mbox(
+            dir.path(),
+            "new.mbox",
+            &[old, message(2, Some("two@example.com"), "Inbox")],
+        );
+
+        let summary = append(&archive, &mbox);
+        assert_eq!((summary.added, summary.unchanged), (1, 1));
+
+        let mut zip = ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+        let entries = read_metadata(&mut zip).unwrap();
+        let ids: Vec<&str> = entries.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, ["msg_000001.eml", "msg_000002.eml"]);
+        assert!(zip.by_name("msg_000002.eml").is_ok());
+
+        let conn = archived_db(&archive, dir.path());
+        let search = |query: &str| -> Vec<String> {
+            let mut stmt = conn
+                .prepare("SELECT id FROM messages_fts WHERE messages_fts MATCH ?1 ORDER BY id")
+                .unwrap();
+            stmt.query_map([query], |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap()
+        };
+        // Old rows survive the rebuilt index, new rows have their body indexed
+        assert_eq!(
+            search("subject:message"),
+            ["msg_000001.eml", "msg_000002.eml"]
+        );
+        assert_eq!(search("body:text2"), ["msg_000002.eml"]);
+        let with_metadata: i64 = conn
+            .query_row("SELECT count(metadata) FROM messages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(with_metadata, 2);
+    }
+
+    fn converted_archive(dir: &Path, messages: &[String]) -> PathBuf {
+        let mbox = write_mbox(dir, "old.mbox", messages);
+        let path = dir.join("archive.mbxc");
+        crate::convert_mbox_to_mbxc(mbox, path.clone(), None, Arc::default()).unwrap();
+        path
+    }
+
+    fn labels(archive: &Path) -> Vec<Option<Vec<String>>> {
+        let mut zip = ZipArchive::new(File::open(archive).unwrap()).unwrap();
+        read_metadata(&mut zip)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.gmail_labels)
+            .collect()
+    }
+
+    #[test]
+    fn stable_content_ignores_gmail_headers() {
+        let a = "From x Mon Jan 01 00:00:00 2024\nX-GM-THRID: 1\nSubject: Hi\nX-Gmail-Labels: Inbox,\n Work\n\nBody\n";
+        let b =
+            "From x Tue Feb 02 00:00:00 2025\nSubject: Hi\nX-Gmail-Labels: Archived\n\nBody\n\n";
+        assert_eq!(stable_content(a.as_bytes()), b"Subject: Hi\n\nBody");
+        assert_eq!(stable_content(a.as_bytes()), stable_content(b.as_bytes()));
+        // Header names in the body are not touched
+        let c = "Subject: Hi\n\nX-Gmail-Labels: quoted\n";
+        assert_eq!(stable_content(c.as_bytes()), c.trim_end().as_bytes());
+    }
+
+    #[test]
+    fn relabels_message_without_message_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = converted_archive(dir.path(), &[message(1, None, "Inbox")]);
+        let mbox = write_mbox(
+            dir.path(),
+            "new.mbox",
+            &[
+                message(1, None, "Inbox,Archived"),
+                message(2, Some("two@example.com"), "Inbox"),
+            ],
+        );
+
+        let summary = append(&archive, &mbox);
+        assert_eq!((summary.added, summary.relabeled), (1, 1));
+        assert_eq!(
+            labels(&archive)[0],
+            Some(vec!["Inbox".to_string(), "Archived".to_string()])
+        );
+    }
+
+    #[test]
+    fn relabels_every_copy_of_a_message_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let copy = message(1, Some("one@example.com"), "Inbox");
+        let archive = converted_archive(dir.path(), &[copy.clone(), copy]);
+        let mbox = write_mbox(
+            dir.path(),
+            "new.mbox",
+            &[message(1, Some("one@example.com"), "Work")],
+        );
+
+        let summary = append(&archive, &mbox);
+        assert_eq!((summary.added, summary.relabeled), (0, 2));
+        let work = Some(vec!["Work".to_string()]);
+        assert_eq!(labels(&archive), [work.clone(), work]);
+    }
+
+    #[test]
+    fn continues_id_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = converted_archive(
+            dir.path(),
+            &[
+                message(1, Some("one@example.com"), "Inbox"),
+                message(2, Some("two@example.com"), "Inbox"),
+            ],
+        );
+        let mbox = write_mbox(
+            dir.path(),
+            "new.mbox",
+            &[
+                message(3, Some("three@example.com"), "Inbox"),
+                message(2, Some("two@example.com"), "Inbox"),
+                message(4, Some("four@example.com"), "Inbox"),
+            ],
+        );
+
+        let summary = append(&archive, &mbox);
+        assert_eq!((summary.added, summary.unchanged), (2, 1));
+        let mut zip = ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+        let subjects: Vec<(String, Option<String>)> = read_metadata(&mut zip)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.id, e.subject))
+            .collect();
+        assert_eq!(
+            subjects[2..],
+            [
+                ("msg_000003.eml".to_string(), Some("Message 3".to_string())),
+                ("msg_000004.eml".to_string(), Some("Message 4".to_string())),
+            ]
+        );
+        assert!(crate::dedup::read_message(&mut zip, "msg_000004.eml")
+            .unwrap()
+            .ends_with(b"Body text4\n\n"));
+    }
+    #[test]
+    fn appends_different_message_with_known_message_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = converted_archive(
+            dir.path(),
+            &[
+                message(1, Some("same@example.com"), "Inbox"),
+                message(2, Some("two@example.com"), "Inbox"),
+            ],
+        );
+        let mbox = write_mbox(
+            dir.path(),
+            "new.mbox",
+            &[
+                message(3, Some("same@example.com"), "Inbox"),
+                message(1, Some("same@example.com"), "Work"),
+                message(2, Some("two@example.com"), "Inbox"),
+            ],
+        );
+
+        let summary = append(&archive, &mbox);
+        assert_eq!(
+            (summary.added, summary.relabeled, summary.unchanged),
+            (1, 1, 1)
+        );
+        let mut zip = ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+        let entries: Vec<(String, Option<String>, Option<Vec<String>>)> = read_metadata(&mut zip)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.id, e.subject, e.gmail_labels))
+            .collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].2, Some(vec!["Work".to_string()]));
+        assert_eq!(
+            entries[2],
+            (
+                "msg_000003.eml".to_string(),
+                Some("Message 3".to_string()),
+                Some(vec!["Inbox".to_string()])
+            )
+        );
+    }
+}
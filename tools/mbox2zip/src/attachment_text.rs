@@ -0,0 +1,284 @@
+//! Plain text of document attachments (text, PDF, OOXML, ODF) for the full-text index.
+
+use crate::parser::{part_mime, strip_html};
+use mail_parser::{MessageParser, MimeHeaders};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+/// Larger attachments are skipped, extraction time grows with the size.
+pub const MAX_INPUT_SIZE: usize = 50 * 1024 * 1024;
+
+/// Indexed text per attachment is cut off after this many characters.
+pub const MAX_TEXT_CHARS: usize = 200_000;
+
+/// Limit for a single XML part of an office document (unpacked).
+const MAX_XML_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Limit for all XML parts of an office document together (unpacked), e.g. a
+/// presentation with thousands of slides.
+const MAX_XML_TOTAL_SIZE: u64 = 200 * 1024 * 1024;
+
+static RE_XML_BLOCK_END: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"</(?:w:p|a:p|text:p|text:h|si|table:table-cell|table:table-row)>").unwrap()
+});
+static RE_XML_SPACE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"<(?:w:tab|w:br|text:tab|text:line-break|text:s)\b[^>]*>").unwrap());
+static RE_XML_TAGS: Lazy<Regex> = Lazy::new(|| Regex::new(r"<[^>]*>").unwrap());
+static RE_XML_ENTITY: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"&(#x[0-9a-fA-F]+|#[0-9]+|amp|lt|gt|quot|apos);").unwrap());
+static RE_PPTX_SLIDE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^ppt/slides/slide(\d+)\.xml$").unwrap());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Text,
+    Html,
+    Pdf,
+    Docx,
+    Xlsx,
+    Pptx,
+    /// OpenDocument text, spreadsheet or presentation.
+    Odf,
+}
+
+fn detect(filename: Option<&str>, mime: &str) -> Option<Kind> {
+    let extension = filename
+        .and_then(|f| f.rsplit_once('.'))
+        .map(|(_, ext)| ext.to_lowercase());
+    let by_extension = match extension.as_deref() {
+        Some("txt" | "csv" | "tsv" | "md" | "log" | "json" | "xml" | "ics" | "vcf") => {
+            Some(Kind::Text)
+        }
+        Some("htm" | "html") => Some(Kind::Html),
+        Some("pdf") => Some(Kind::Pdf),
+        Some("docx" | "docm") => Some(Kind::Docx),
+        Some("xlsx" | "xlsm") => Some(Kind::Xlsx),
+        Some("pptx" | "pptm") => Some(Kind::Pptx),
+        Some("odt" | "ods" | "odp") => Some(Kind::Odf),
+        _ => None,
+    };
+    if by_extension.is_some() {
+        return by_extension;
+    }
+
+    let mime = mime.to_lowercase();
+    match mime.as_str() {
+        "text/html" => Some(Kind::Html),
+        "application/pdf" => Some(Kind::Pdf),
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => {
+            Some(Kind::Docx)
+        }
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => Some(Kind::Xlsx),
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation" => {
+            Some(Kind::Pptx)
+        }
+        m if m.starts_with("application/vnd.oasis.opendocument.") => Some(Kind::Odf),
+        m if m.starts_with("text/") => Some(Kind::Text),
+        _ => None,
+    }
+}
+
+/// Plain text of an attachment, `None` for unsupported types, unreadable documents
+/// or documents without text.
+pub fn extract_text(filename: Option<&str>, mime: &str, data: &[u8]) -> Option<String> {
+    if data.len() > MAX_INPUT_SIZE {
+        return None;
+    }
+    let text = match detect(filename, mime)? {
+        Kind::Text => String::from_utf8_lossy(data).into_owned(),
+        Kind::Html => strip_html(&String::from_utf8_lossy(data)),
+        Kind::Pdf => pdf_text(data)?,
+        Kind::Docx => office_text(data, |name| name == "word/document.xml")?,
+        Kind::Xlsx => office_text(data, |name| name == "xl/sharedStrings.xml")?,
+        Kind::Pptx => office_text(data, |name| RE_PPTX_SLIDE.is_match(name))?,
+        Kind::Odf => office_text(data, |name| name == "content.xml")?,
+    };
+    let text = normalize_whitespace(&text);
+    (!text.is_empty()).then_some(text)
+}
+
+/// Extracts the text of every supported attachment of a message, keyed by the
+/// attachment's position in `MetadataEntry::attachments`.
+pub fn attachment_texts(eml: &[u8]) -> Vec<(usize, String)> {
+    let Some(message) = MessageParser::default().parse(eml) else {
+        return Vec::new();
+    };
+    message
+        .attachments()
+        .enumerate()
+        .filter_map(|(index, part)| {
+            extract_text(part.attachment_name(), &part_mime(part), part.contents())
+                .map(|text| (index, text))
+        })
+        .collect()
+}
+
+fn pdf_text(data: &[u8]) -> Option<String> {
+    // pdf-extract panics on some malformed documents
+    std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem(data))
+        .ok()?
+        .ok()
+}
+
+/// Text of the first page of a PDF, with blank runs collapsed.
+pub fn pdf_first_page_text(data: &[u8]) -> Option<String> {
+    if data.len() > MAX_INPUT_SIZE {
+        return None;
+    }
+    let pages = std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem_by_pages(data))
+        .ok()?
+        .ok()?;
+    let text = normalize_whitespace(pages.first()?);
+    (!text.is_empty()).then_some(text)
+}
+
+/// Text of the XML parts of a ZIP based office document selected by `wanted`,
+/// in natural order (`slide2.xml` before `slide10.xml`).
+fn office_text(data: &[u8], wanted: impl Fn(&str) -> bool) -> Option<String> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).ok()?;
+    let mut names: Vec<String> = archive
+        .file_names()
+        .filter(|name| wanted(name))
+        .map(String::from)
+        .collect();
+    names.sort_by_key(|name| {
+        let digits: String = name.chars().filter(char::is_ascii_digit).collect();
+        (digits.parse::<u64>().unwrap_or(0), name.clone())
+    });
+
+    let mut text = String::new();
+    let mut remaining = MAX_XML_TOTAL_SIZE;
+    for name in names {
+        if remaining == 0 {
+            break;
+        }
+        let mut xml = Vec::new();
+        archive
+            .by_name(&name)
+            .ok()?
+            .take(MAX_XML_SIZE.min(remaining))
+            .read_to_end(&mut xml)
+            .ok()?;
+        remaining -= xml.len() as u64;
+        // A part cut off at a limit may end inside a UTF-8 sequence
+        text.push_str(&xml_text(&String::from_utf8_lossy(&xml)));
+        text.push('\n');
+    }
+    Some(text)
+}
+
+fn xml_text(xml: &str) -> String {
+    let text = RE_XML_BLOCK_END.replace_all(xml, "\n");
+    let text = RE_XML_SPACE.replace_all(&text, " ");
+    let text = RE_XML_TAGS.replace_all(&text, "");
+    RE_XML_ENTITY
+        .replace_all(&text, |caps: &regex::Captures| {
+            let entity = &caps[1];
+            let decoded = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => entity
+                    .strip_prefix("#x")
+                    .map(|hex| u32::from_str_radix(hex, 16))
+                    .unwrap_or_else(|| entity[1..].parse())
+                    .ok()
+                    .and_then(char::from_u32),
+            };
+            decoded.map_or_else(|| caps[0].to_string(), String::from)
+        })
+        .into_owned()
+}
+
+/// Collapses blanks, drops empty lines and applies `MAX_TEXT_CHARS`.
+fn normalize_whitespace(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = 0;
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            continue;
+        }
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        chars += line.chars().count() + 1;
+        if chars > MAX_TEXT_CHARS {
+            let rest = MAX_TEXT_CHARS.saturating_sub(out.chars().count());
+            out.extend(line.chars().take(rest));
+            break;
+        }
+        out.push_str(&line);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_xml_entities() {
+        assert_eq!(
+            xml_text("<w:p><w:t>A &amp; B &lt;C&gt; &quot;D&quot; &apos;E&apos;</w:t></w:p>"),
+            "A & B <C> \"D\" 'E'\n"
+        );
+        assert_eq!(xml_text("&#228;&#x20AC;&#X41;"), "ä€&#X41;");
+        // Numbers that are no characters stay as written
+        assert_eq!(
+            xml_text("&#x110000; &#xD800; &#99999999999; &nbsp;"),
+            "&#x110000; &#xD800; &#99999999999; &nbsp;"
+        );
+        assert_eq!(
+            xml_text("<text:p>a<text:tab/>b<text:line-break/>c</text:p><text:p>d</text:p>"),
+            "a b c\nd\n"
+        );
+    }
+
+    #[test]
+    fn normalizes_whitespace() {
+        assert_eq!(
+            normalize_whitespace("  a \t b\r\n\n   \nc  d \n"),
+            "a b\nc d"
+        );
+        assert_eq!(normalize_whitespace(" \n\t\n"), "");
+
+        let short = "x".repeat(MAX_TEXT_CHARS - 10);
+        assert_eq!(normalize_whitespace(&short), short);
+
+        let long = format!("{}\n{}\nc", "a".repeat(150_000), "ö".repeat(100_000));
+        let cut = normalize_whitespace(&long);
+        assert_eq!(cut.chars().count(), MAX_TEXT_CHARS);
+        assert!(cut.ends_with('ö'));
+        assert!(!cut.contains('c'));
+    }
+
+    #[test]
+    fn detects_by_extension_before_mime_type() {
+        assert_eq!(
+            detect(Some("Bericht.PDF"), "application/octet-stream"),
+            Some(Kind::Pdf)
+        );
+        assert_eq!(
+            detect(Some("notes.txt"), "application/pdf"),
+            Some(Kind::Text)
+        );
+        assert_eq!(
+            detect(Some("slides.pptx"), "application/zip"),
+            Some(Kind::Pptx)
+        );
+        // Unknown or missing extensions fall back to the MIME type
+        assert_eq!(detect(Some("scan.bin"), "Application/PDF"), Some(Kind::Pdf));
+        assert_eq!(detect(None, "text/calendar"), Some(Kind::Text));
+        assert_eq!(
+            detect(Some("brief"), "application/vnd.oasis.opendocument.text"),
+            Some(Kind::Odf)
+        );
+        assert_eq!(detect(Some("photo.jpg"), "image/jpeg"), None);
+        assert_eq!(detect(None, "application/octet-stream"), None);
+    }
+}
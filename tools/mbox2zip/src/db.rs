@@ -1,39 +1,89 @@
 use rusqlite::{params, Connection, Result};
 use crate::parser::MetadataEntry;
 
+/// Creates the tables and indexes; missing ones are added to an existing database.
 pub fn init_db(conn: &Connection) -> Result<()> {
     conn.execute(
-        "CREATE TABLE messages (
+        "CREATE TABLE IF NOT EXISTS messages (
             id TEXT PRIMARY KEY,
             subject TEXT,
             sender_name TEXT,
             sender_address TEXT,
             date_sent_iso TEXT,
             has_attachment INTEGER,
-            labels TEXT
+            labels TEXT,
+            thread_id TEXT,
+            metadata TEXT
         )",
         [],
     )?;
 
-    conn.execute("CREATE INDEX idx_messages_date ON messages(date_sent_iso)", [])?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_messages_date ON messages(date_sent_iso)", [])?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_messages_thread ON messages(thread_id)", [])?;
 
     conn.execute(
-        "CREATE VIRTUAL TABLE messages_fts USING fts5(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
             id UNINDEXED,
             subject,
             sender_name,
             sender_address,
             recipients,
             snippet,
-            attachment_names
+            attachment_names,
+            body
         )",
         [],
     )?;
 
+    // Text extracted from document attachments, one row per attachment
+    conn.execute(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS attachments_fts USING fts5(
+            id UNINDEXED,
+            attachment_index UNINDEXED,
+            filename UNINDEXED,
+            content
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Brings the metadata.db of an archive converted by an older version up to the
+/// current schema, e.g. before appending messages.
+pub fn upgrade_db(conn: &Connection) -> Result<()> {
+    for column in ["thread_id", "metadata"] {
+        if conn
+            .prepare(&format!("SELECT {} FROM messages LIMIT 0", column))
+            .is_err()
+        {
+            conn.execute(&format!("ALTER TABLE messages ADD COLUMN {} TEXT", column), [])?;
+        }
+    }
+
+    // FTS5 tables cannot get new columns; without `body` the index is rebuilt from
+    // the existing rows
+    let rebuild_fts = conn
+        .prepare("SELECT body FROM messages_fts LIMIT 0")
+        .is_err();
+    if rebuild_fts {
+        conn.execute("ALTER TABLE messages_fts RENAME TO messages_fts_old", [])?;
+    }
+    init_db(conn)?;
+    if rebuild_fts {
+        conn.execute_batch(
+            "INSERT INTO messages_fts (id, subject, sender_name, sender_address, recipients, snippet, attachment_names)
+             SELECT id, subject, sender_name, sender_address, recipients, snippet, attachment_names
+             FROM messages_fts_old;
+             DROP TABLE messages_fts_old;",
+        )?;
+    }
     Ok(())
 }
 
-pub fn insert_metadata(conn: &Connection, entry: &MetadataEntry) -> Result<()> {
+/// Inserts a message into `messages` and `messages_fts`.
+/// `body` is the full decoded text body; pass `None` to keep it out of the index.
+pub fn insert_metadata(conn: &Connection, entry: &MetadataEntry, body: Option<&str>) -> Result<()> {
     let recipients = entry.to_addresses.as_ref().map(|v| v.join(" ")).unwrap_or_default() + " " + 
                      &entry.cc_addresses.as_ref().map(|v| v.join(" ")).unwrap_or_default();
     
@@ -61,8 +111,8 @@ pub fn insert_metadata(conn: &Connection, entry: &MetadataEntry) -> Result<()> {
 
     {
         let mut stmt = conn.prepare_cached(
-            "INSERT INTO messages_fts (id, subject, sender_name, sender_address, recipients, snippet, attachment_names)
-             VALUES (?, ?, ?, ?, ?, ?, ?)"
+            "INSERT INTO messages_fts (id, subject, sender_name, sender_address, recipients, snippet, attachment_names, body)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
         )?;
         stmt.execute(params![
             entry.id,
@@ -71,9 +121,47 @@ pub fn insert_metadata(conn: &Connection, entry: &MetadataEntry) -> Result<()> {
             entry.sender_address,
             recipients.trim(),
             entry.snippet,
-            att_names
+            att_names,
+            body
         ])?;
     }
 
     Ok(())
 }
+
+/// Indexes the extracted text of attachment `index` of message `id`.
+pub fn insert_attachment_text(
+    conn: &Connection,
+    id: &str,
+    index: usize,
+    filename: Option<&str>,
+    text: &str,
+) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO attachments_fts (id, attachment_index, filename, content) VALUES (?, ?, ?, ?)",
+    )?;
+    stmt.execute(params![id, index as i64, filename, text])?;
+    Ok(())
+}
+
+/// Stores changed labels of an already archived message.
+pub fn update_labels(conn: &Connection, entry: &MetadataEntry) -> Result<()> {
+    let labels_str = entry.gmail_labels.as_ref().map(|v| v.join(" ")).unwrap_or_default();
+    conn.prepare_cached("UPDATE messages SET labels = ? WHERE id = ?")?
+        .execute(params![labels_str, entry.id])?;
+    Ok(())
+}
+
+/// Stores the thread ids computed after all messages have been inserted, together
+/// with the complete entry as JSON (same format as metadata.json) so readers can
+/// load single entries without metadata.json.
+pub fn update_metadata(conn: &Connection, entries: &[MetadataEntry]) -> Result<()> {
+    let mut stmt =
+        conn.prepare_cached("UPDATE messages SET thread_id = ?, metadata = ? WHERE id = ?")?;
+    for entry in entries {
+        let json = serde_json::to_string(entry)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        stmt.execute(params![entry.thread_id, json, entry.id])?;
+    }
+    Ok(())
+}
@@ -0,0 +1,286 @@
+//! Content-addressed attachment storage.
+//!
+//! With `ConvertOptions::dedup_attachments` the base64 payload of binary attachments
+//! is cut out of the stored EML and written once as `attachments/<sha256>` (decoded
+//! bytes). `attachment_refs/<id>.json` records where each payload was removed, so
+//! `read_message` can restore the original message byte for byte. Payloads whose
+//! encoding cannot be reproduced exactly stay in the EML.
+
+use base64::Engine;
+use mail_parser::{Encoding, MessageParser, PartType};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Seek};
+use zip::result::{ZipError, ZipResult};
+use zip::ZipArchive;
+
+/// Smaller payloads are not worth a ZIP entry of their own.
+pub const MIN_DEDUP_SIZE: usize = 1024;
+
+/// Line length of base64 bodies written by common mail clients (RFC 2045).
+const BASE64_LINE_LENGTH: usize = 76;
+
+/// A payload removed from a stored EML.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartRef {
+    /// Byte offset in the stored EML where the encoded payload is inserted.
+    pub offset: usize,
+    pub sha256: String,
+    /// Lines of the encoded payload end with CRLF instead of LF.
+    pub crlf: bool,
+}
+
+/// Result of `split_attachments`.
+pub struct SplitMessage {
+    /// The EML without the removed payloads.
+    pub stored: Vec<u8>,
+    pub refs: Vec<PartRef>,
+    /// Decoded payloads by hash, in message order.
+    pub blobs: Vec<(String, Vec<u8>)>,
+}
+
+/// Hex encoded SHA-256 of `data`.
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// ZIP entry holding the payload with hash `sha256`.
+pub fn blob_name(sha256: &str) -> String {
+    format!("attachments/{}", sha256)
+}
+
+/// ZIP entry with the `PartRef`s of message `id`.
+pub fn refs_name(id: &str) -> String {
+    format!("attachment_refs/{}.json", id)
+}
+
+/// Base64 in lines of `BASE64_LINE_LENGTH`, without a line end after the last line.
+fn encode_base64(data: &[u8], crlf: bool) -> Vec<u8> {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(data);
+    let line_end: &[u8] = if crlf { b"\r\n" } else { b"\n" };
+    let mut out = Vec::with_capacity(encoded.len() + encoded.len() / BASE64_LINE_LENGTH * 2);
+    for (i, line) in encoded.as_bytes().chunks(BASE64_LINE_LENGTH).enumerate() {
+        if i > 0 {
+            out.extend_from_slice(line_end);
+        }
+        out.extend_from_slice(line);
+    }
+    out
+}
+
+/// Cuts the payloads of binary base64 attachments out of `eml`. Only payloads that
+/// `encode_base64` reproduces exactly are removed, so the split is always lossless.
+pub fn split_attachments(eml: &[u8]) -> SplitMessage {
+    let mut split = SplitMessage {
+        stored: Vec::new(),
+        refs: Vec::new(),
+        blobs: Vec::new(),
+    };
+    let Some(message) = MessageParser::default().parse(eml) else {
+        split.stored = eml.to_vec();
+        return split;
+    };
+
+    let mut ranges = Vec::new();
+    for part in message.attachments() {
+        let (PartType::Binary(contents) | PartType::InlineBinary(contents)) = &part.body else {
+            continue;
+        };
+        if part.encoding != Encoding::Base64
+            || part.is_encoding_problem
+            || contents.len() < MIN_DEDUP_SIZE
+        {
+            continue;
+        }
+        let Some(raw) = eml.get(part.offset_body..part.offset_end) else {
+            continue;
+        };
+        let crlf = raw.windows(2).any(|w| w == b"\r\n");
+        let encoded = encode_base64(contents, crlf);
+        // Only line ends may follow the payload, they stay in the stored EML
+        let exact = raw.starts_with(&encoded)
+            && raw[encoded.len()..]
+                .iter()
+                .all(|&b| b == b'\r' || b == b'\n');
+        if !exact {
+            continue;
+        }
+        ranges.push((part.offset_body, encoded.len(), contents.to_vec(), crlf));
+    }
+    ranges.sort_by_key(|(start, ..)| *start);
+
+    let mut pos = 0;
+    for (start, len, contents, crlf) in ranges {
+        if start < pos {
+            continue;
+        }
+        let sha256 = sha256_hex(&contents);
+        split.stored.extend_from_slice(&eml[pos..start]);
+        split.refs.push(PartRef {
+            offset: split.stored.len(),
+            sha256: sha256.clone(),
+            crlf,
+        });
+        split.blobs.push((sha256, contents));
+        pos = start + len;
+    }
+    split.stored.extend_from_slice(&eml[pos..]);
+    split
+}
+
+/// Reads message `id` from an MBXC archive, restoring removed attachment payloads.
+pub fn read_message<R: Read + Seek>(archive: &mut ZipArchive<R>, id: &str) -> ZipResult<Vec<u8>> {
+    let mut stored = Vec::new();
+    archive.by_name(id)?.read_to_end(&mut stored)?;
+
+    let refs: Vec<PartRef> = match archive.by_name(&refs_name(id)) {
+        Ok(file) => serde_json::from_reader(io::BufReader::new(file))
+            .map_err(|_| ZipError::InvalidArchive("Invalid attachment references"))?,
+        Err(ZipError::FileNotFound) => return Ok(stored),
+        Err(e) => return Err(e),
+    };
+
+    let mut eml = Vec::with_capacity(stored.len());
+    let mut pos = 0;
+    for part in refs {
+        let chunk = stored
+            .get(pos..part.offset)
+            .ok_or(ZipError::InvalidArchive(
+                "Invalid attachment reference offset",
+            ))?;
+        eml.extend_from_slice(chunk);
+        eml.extend_from_slice(&encode_base64(
+            &read_blob(archive, &part.sha256)?,
+            part.crlf,
+        ));
+        pos = part.offset;
+    }
+    eml.extend_from_slice(&stored[pos..]);
+    Ok(eml)
+}
+
+/// Decoded payload with hash `sha256`; `ZipError::FileNotFound` if the archive does
+/// not store it separately.
+pub fn read_blob<R: Read + Seek>(archive: &mut ZipArchive<R>, sha256: &str) -> ZipResult<Vec<u8>> {
+    let mut data = Vec::new();
+    archive
+        .by_name(&blob_name(sha256))?
+        .read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    fn attachment(name: &str, data: &[u8], line_length: usize) -> String {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(data);
+        let lines: Vec<&str> = encoded
+            .as_bytes()
+            .chunks(line_length)
+            .map(|line| std::str::from_utf8(line).unwrap())
+            .collect();
+        format!(
+            "--b\n\
+             Content-Type: application/octet-stream; name=\"{name}\"\n\
+             Content-Disposition: attachment; filename=\"{name}\"\n\
+             Content-Transfer-Encoding: base64\n\
+             \n\
+             {}\n",
+            lines.join("\n")
+        )
+    }
+
+    fn message(parts: &[String]) -> String {
+        format!(
+            "From a@example.com Mon Jan  1 10:00:00 2024\n\
+             From: a@example.com\n\
+             Subject: Attachments\n\
+             MIME-Version: 1.0\n\
+             Content-Type: multipart/mixed; boundary=\"b\"\n\
+             \n\
+             --b\n\
+             Content-Type: text/plain\n\
+             \n\
+             Body\n\
+             {}--b--\n",
+            parts.concat()
+        )
+    }
+
+    fn payload(seed: u8, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    /// Stores `split` as message `id` the way the converter does and reads it back.
+    fn restore(id: &str, split: &SplitMessage) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(id, FileOptions::default()).unwrap();
+        zip.write_all(&split.stored).unwrap();
+        if !split.refs.is_empty() {
+            zip.start_file(refs_name(id), FileOptions::default())
+                .unwrap();
+            serde_json::to_writer(&mut zip, &split.refs).unwrap();
+        }
+        let mut written = std::collections::HashSet::new();
+        for (sha256, data) in &split.blobs {
+            if written.insert(sha256) {
+                zip.start_file(blob_name(sha256), FileOptions::default())
+                    .unwrap();
+                zip.write_all(data).unwrap();
+            }
+        }
+        let mut archive = ZipArchive::new(zip.finish().unwrap()).unwrap();
+        read_message(&mut archive, id).unwrap()
+    }
+
+    #[test]
+    fn split_and_restore_round_trip() {
+        let big = payload(1, 5000);
+        let eml = message(&[
+            attachment("one.bin", &big, BASE64_LINE_LENGTH),
+            attachment("small.bin", &payload(2, 100), BASE64_LINE_LENGTH),
+            attachment("copy.bin", &big, BASE64_LINE_LENGTH),
+        ]);
+
+        let split = split_attachments(eml.as_bytes());
+        assert_eq!(split.refs.len(), 2);
+        assert_eq!(split.refs[0].sha256, sha256_hex(&big));
+        assert_eq!(split.refs[0].sha256, split.refs[1].sha256);
+        assert!(!split.refs[0].crlf);
+        assert!(split.stored.len() < eml.len() - 2 * big.len());
+        assert_eq!(split.blobs[0].1, big);
+
+        assert_eq!(restore("1", &split), eml.as_bytes());
+    }
+
+    #[test]
+    fn round_trip_keeps_crlf() {
+        let eml = message(&[attachment("one.bin", &payload(3, 3000), BASE64_LINE_LENGTH)])
+            .replace('\n', "\r\n");
+
+        let split = split_attachments(eml.as_bytes());
+        assert_eq!(split.refs.len(), 1);
+        assert!(split.refs[0].crlf);
+
+        assert_eq!(restore("2", &split), eml.as_bytes());
+    }
+
+    #[test]
+    fn keeps_payloads_that_cannot_be_reproduced() {
+        // 64 characters per line is valid base64 but not what `encode_base64` writes
+        let eml = message(&[attachment("one.bin", &payload(4, 3000), 64)]);
+
+        let split = split_attachments(eml.as_bytes());
+        assert!(split.refs.is_empty());
+        assert!(split.blobs.is_empty());
+        assert_eq!(split.stored, eml.as_bytes());
+
+        assert_eq!(restore("3", &split), eml.as_bytes());
+    }
+}
@@ -0,0 +1,496 @@
+//! Writing archived messages back out as an mboxrd file.
+
+use crate::dedup::read_message;
+use crate::metadata_export::{write_metadata, MetadataFormat};
+use crate::parser::MetadataEntry;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use zip::ZipArchive;
+
+/// Writes messages in mboxrd format. Every message gets a `From ` separator line.
+/// Stored messages are split at `From ` lines on import, so their `>From` lines are
+/// still escaped as in the source MBOX and are written unchanged; an mboxrd reader
+/// removes that one level again.
+pub struct MboxWriter<W: Write> {
+    out: W,
+    count: usize,
+}
+
+impl<W: Write> MboxWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, count: 0 }
+    }
+
+    /// Appends one message. The stored `From ` line is reused if present, otherwise
+    /// one is built from `sender` and `date`.
+    pub fn write_message(
+        &mut self,
+        eml: &[u8],
+        sender: Option<&str>,
+        date: Option<DateTime<Utc>>,
+    ) -> io::Result<()> {
+        let (separator, mut content) = split_from_line(eml);
+        match separator {
+            Some(line) => {
+                self.out.write_all(trim_line_end(line))?;
+                self.out.write_all(b"\n")?;
+            }
+            None => self.out.write_all(from_line(sender, date).as_bytes())?,
+        }
+
+        // The blank line before the next separator belongs to the mbox, not the message
+        if content.ends_with(b"\n\n") {
+            content = &content[..content.len() - 1];
+        } else if content.ends_with(b"\r\n\r\n") {
+            content = &content[..content.len() - 2];
+        }
+
+        for line in content.split_inclusive(|&b| b == b'\n') {
+            // Only messages that did not come from an MBOX can contain these
+            if is_from_line(line) {
+                self.out.write_all(b">")?;
+            }
+            self.out.write_all(line)?;
+        }
+        if !content.is_empty() && !content.ends_with(b"\n") {
+            self.out.write_all(b"\n")?;
+        }
+        self.out.write_all(b"\n")?;
+
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Number of messages written so far.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn is_from_line(line: &[u8]) -> bool {
+    line.starts_with(b"From ")
+}
+
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Splits off the mbox separator line the converter keeps at the top of an EML.
+fn split_from_line(eml: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    if !is_from_line(eml) {
+        return (None, eml);
+    }
+    match eml.iter().position(|&b| b == b'\n') {
+        Some(pos) => (Some(&eml[..=pos]), &eml[pos + 1..]),
+        None => (Some(eml), &[]),
+    }
+}
+
+/// Separator line in the classic `From sender asctime` form.
+pub fn from_line(sender: Option<&str>, date: Option<DateTime<Utc>>) -> String {
+    let sender = sender
+        .map(str::trim)
+        .filter(|s| !s.is_empty() && !s.contains(char::is_whitespace))
+        .unwrap_or("MAILER-DAEMON");
+    let date = date.unwrap_or(DateTime::UNIX_EPOCH);
+    format!("From {} {}\n", sender, date.format("%a %b %e %H:%M:%S %Y"))
+}
+
+/// Date of a metadata entry for the separator line.
+pub fn entry_date(entry: &MetadataEntry) -> Option<DateTime<Utc>> {
+    entry
+        .date_sent_iso
+        .as_deref()
+        .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+        .map(|d| d.with_timezone(&Utc))
+}
+
+/// Reads `metadata.json` from an MBXC archive.
+pub fn read_metadata<R: Read + io::Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<Vec<MetadataEntry>> {
+    let file = archive
+        .by_name("metadata.json")
+        .context("metadata.json not found in archive")?;
+    Ok(serde_json::from_reader(io::BufReader::new(file))?)
+}
+
+/// The structured filters of the viewer's `/api/query`. All set filters must match;
+/// text filters are case-insensitive substring matches.
+#[derive(Debug, Clone, Default)]
+pub struct EntryFilter {
+    /// Gmail label, compared ignoring ASCII case.
+    pub label: Option<String>,
+    /// Sender name or address contains this.
+    pub sender: Option<String>,
+    pub subject: Option<String>,
+    /// Subject, sender name or sender address contains this.
+    pub any: Option<String>,
+    pub has_attachment: bool,
+    /// Earliest send date, compared with `date_sent_iso` like the viewer does
+    /// (e.g. `2023-01-01`).
+    pub date_from: Option<String>,
+    /// Latest send date, see `date_from`.
+    pub date_to: Option<String>,
+}
+
+fn contains(value: Option<&str>, needle: &str) -> bool {
+    value.is_some_and(|v| v.to_lowercase().contains(needle))
+}
+
+impl EntryFilter {
+    pub fn matches(&self, entry: &MetadataEntry) -> bool {
+        let needle = |f: &Option<String>| {
+            f.as_deref()
+                .filter(|v| !v.is_empty())
+                .map(str::to_lowercase)
+        };
+        let sender_contains = |needle: &str| {
+            contains(entry.sender_name.as_deref(), needle)
+                || contains(entry.sender_address.as_deref(), needle)
+        };
+        let date = entry.date_sent_iso.as_deref();
+
+        if let Some(label) = self.label.as_deref().filter(|l| !l.is_empty()) {
+            let labelled = entry
+                .gmail_labels
+                .as_ref()
+                .is_some_and(|labels| labels.iter().any(|l| l.eq_ignore_ascii_case(label)));
+            if !labelled {
+                return false;
+            }
+        }
+        if self.has_attachment && !entry.has_attachment {
+            return false;
+        }
+        if let Some(from) = self.date_from.as_deref().filter(|d| !d.is_empty()) {
+            if date.is_none_or(|d| d < from) {
+                return false;
+            }
+        }
+        if let Some(to) = self.date_to.as_deref().filter(|d| !d.is_empty()) {
+            if date.is_none_or(|d| d > to) {
+                return false;
+            }
+        }
+        if let Some(subject) = needle(&self.subject) {
+            if !contains(entry.subject.as_deref(), &subject) {
+                return false;
+            }
+        }
+        if let Some(sender) = needle(&self.sender) {
+            if !sender_contains(&sender) {
+                return false;
+            }
+        }
+        if let Some(any) = needle(&self.any) {
+            if !contains(entry.subject.as_deref(), &any) && !sender_contains(&any) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Selects entries for an export. `ids` selects messages in the given order,
+/// otherwise all messages in archive order; `filter` applies to both.
+pub fn select_entries<'a>(
+    metadata: &'a [MetadataEntry],
+    ids: Option<&[String]>,
+    filter: &EntryFilter,
+) -> Vec<&'a MetadataEntry> {
+    let mut selected: Vec<&MetadataEntry> = match ids {
+        Some(ids) => {
+            let by_id: HashMap<&str, &MetadataEntry> =
+                metadata.iter().map(|e| (e.id.as_str(), e)).collect();
+            ids.iter()
+                .filter_map(|id| {
+                    let entry = by_id.get(id.as_str()).copied();
+                    if entry.is_none() {
+                        eprintln!("Message {} not found, skipped", id);
+                    }
+                    entry
+                })
+                .collect()
+        }
+        None => metadata.iter().collect(),
+    };
+    selected.retain(|e| filter.matches(e));
+    selected
+}
+
+/// Exports messages of an MBXC archive to an mboxrd file, see `select_entries`.
+/// Returns the number of exported messages.
+pub fn export_mbox(
+    archive_path: &Path,
+    output_path: &Path,
+    ids: Option<&[String]>,
+    filter: &EntryFilter,
+) -> Result<usize> {
+    let mut archive = ZipArchive::new(File::open(archive_path).context("Failed to open MBXC")?)?;
+    let metadata = read_metadata(&mut archive)?;
+    let selected = select_entries(&metadata, ids, filter);
+
+    let output = File::create(output_path).context("Failed to create output MBOX")?;
+    let mut writer = MboxWriter::new(BufWriter::new(output));
+    for entry in selected {
+        let eml = read_message(&mut archive, &entry.id)?;
+        writer.write_message(&eml, entry.sender_address.as_deref(), entry_date(entry))?;
+    }
+
+    let count = writer.count();
+    writer.finish()?;
+    Ok(count)
+}
+
+/// Writes the metadata of the selected messages as a flat table, see
+/// `metadata_export::write_metadata`. Returns the number of rows.
+pub fn export_metadata(
+    archive_path: &Path,
+    output_path: &Path,
+    ids: Option<&[String]>,
+    filter: &EntryFilter,
+    format: MetadataFormat,
+) -> Result<usize> {
+    let mut archive = ZipArchive::new(File::open(archive_path).context("Failed to open MBXC")?)?;
+    let metadata = read_metadata(&mut archive)?;
+    let selected = select_entries(&metadata, ids, filter);
+
+    let output = File::create(output_path).context("Failed to create output file")?;
+    let mut out = BufWriter::new(output);
+    write_metadata(&mut out, selected.iter().copied(), format)?;
+    out.flush()?;
+    Ok(selected.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Quotes a message like an mboxrd writer: one more `>` on every `>*From ` line.
+    fn quote_mboxrd(message: &str) -> String {
+        message
+            .split_inclusive('\n')
+            .map(|line| {
+                if line.trim_start_matches('>').starts_with("From ") {
+                    format!(">{}", line)
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect()
+    }
+
+    /// What an mboxrd reader does: split at `From ` lines, remove one `>` from every
+    /// quoted `From ` line and drop the blank line before the next separator.
+    /// Returns the messages without their separator line.
+    fn read_mboxrd(mbox: &[u8]) -> Vec<String> {
+        let mut messages: Vec<String> = Vec::new();
+        for line in String::from_utf8_lossy(mbox).split_inclusive('\n') {
+            if line.starts_with("From ") {
+                messages.push(String::new());
+            } else if line.trim_start_matches('>').starts_with("From ") {
+                messages.last_mut().unwrap().push_str(&line[1..]);
+            } else {
+                messages.last_mut().unwrap().push_str(line);
+            }
+        }
+        for message in &mut messages {
+            message.pop();
+        }
+        messages
+    }
+
+    fn message(n: u32, body: &str) -> String {
+        format!(
+            "From: Sender {n} <sender{n}@example.com>\n\
+             To: me@example.com\n\
+             Subject: Message {n}\n\
+             Date: Mon, 0{n} Jan 2024 10:00:00 +0000\n\
+             Message-ID: <{n}@example.com>\n\
+             X-Gmail-Labels: {}\n\
+             \n\
+             {body}",
+            if n == 2 { "Inbox,Legal" } else { "Inbox" }
+        )
+    }
+
+    #[test]
+    fn convert_export_round_trip_keeps_messages() {
+        let originals = [
+            message(
+                1,
+                "From the start of a line\n\
+                 >From a quote in the mail\n\
+                 >>From deeper\n\
+                 Fromage\n",
+            ),
+            message(2, "Plain body\n"),
+            message(3, "> From with a space\n>From\n"),
+        ];
+        let mbox: String = originals
+            .iter()
+            .map(|m| {
+                format!(
+                    "From sender@example.com Mon Jan  1 10:00:00 2024\n{}\n",
+                    quote_mboxrd(m)
+                )
+            })
+            .collect();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mbox_path = dir.path().join("in.mbox");
+        let archive_path = dir.path().join("archive.mbxc");
+        let export_path = dir.path().join("out.mbox");
+        std::fs::write(&mbox_path, &mbox).unwrap();
+        crate::convert_mbox_to_mbxc(mbox_path, archive_path.clone(), None, Default::default())
+            .unwrap();
+
+        let count =
+            export_mbox(&archive_path, &export_path, None, &EntryFilter::default()).unwrap();
+        assert_eq!(count, 3);
+        let exported = std::fs::read(&export_path).unwrap();
+        assert_eq!(read_mboxrd(&exported), originals);
+
+        // Exporting the export again changes nothing
+        let reconverted = dir.path().join("again.mbxc");
+        crate::convert_mbox_to_mbxc(
+            export_path.clone(),
+            reconverted.clone(),
+            None,
+            Default::default(),
+        )
+        .unwrap();
+        let again = dir.path().join("again.mbox");
+        export_mbox(&reconverted, &again, None, &EntryFilter::default()).unwrap();
+        assert_eq!(read_mboxrd(&std::fs::read(&again).unwrap()), originals);
+
+        let filter = EntryFilter {
+            label: Some("legal".to_string()),
+            ..Default::default()
+        };
+        export_mbox(&archive_path, &export_path, None, &filter).unwrap();
+        assert_eq!(
+            read_mboxrd(&std::fs::read(&export_path).unwrap()),
+            [originals[1].clone()]
+        );
+    }
+
+    #[test]
+    fn builds_separator_when_missing() {
+        let date = DateTime::parse_from_rfc3339("2024-03-05T07:08:09Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            from_line(Some("a@example.com"), Some(date)),
+            "From a@example.com Tue Mar  5 07:08:09 2024\n"
+        );
+        assert_eq!(
+            from_line(Some("not an address"), None),
+            "From MAILER-DAEMON Thu Jan  1 00:00:00 1970\n"
+        );
+
+        let mut writer = MboxWriter::new(Vec::new());
+        writer
+            .write_message(b"Subject: No separator\r\n\r\nBody", None, None)
+            .unwrap();
+        assert_eq!(
+            writer.finish().unwrap(),
+            b"From MAILER-DAEMON Thu Jan  1 00:00:00 1970\nSubject: No separator\r\n\r\nBody\n\n"
+        );
+    }
+
+    #[test]
+    fn filters_like_the_viewer() {
+        let entry =
+            |id: &str, sender: &str, subject: &str, date: &str, attachment: bool| MetadataEntry {
+                id: id.to_string(),
+                subject: Some(subject.to_string()),
+                sender_name: Some(sender.to_string()),
+                sender_address: Some(format!("{}@example.com", sender.to_lowercase())),
+                to_addresses: None,
+                cc_addresses: None,
+                date_sent_iso: Some(date.to_string()),
+                internal_date: None,
+                gmail_labels: Some(vec!["Inbox".to_string()]),
+                rfc822_size: 0,
+                message_id: None,
+                in_reply_to: None,
+                references: None,
+                gmail_thread_id: None,
+                thread_id: None,
+                has_attachment: attachment,
+                snippet: None,
+                attachments: None,
+            };
+        let metadata = [
+            entry(
+                "a",
+                "Alice",
+                "Contract draft",
+                "2023-05-01T10:00:00+00:00",
+                true,
+            ),
+            entry("b", "Bob", "Lunch", "2023-06-01T10:00:00+00:00", false),
+            entry(
+                "c",
+                "Carol",
+                "Re: contract",
+                "2024-01-01T10:00:00+00:00",
+                false,
+            ),
+        ];
+        let ids = |filter: EntryFilter| -> Vec<&str> {
+            select_entries(&metadata, None, &filter)
+                .iter()
+                .map(|e| e.id.as_str())
+                .collect()
+        };
+
+        assert_eq!(ids(EntryFilter::default()), ["a", "b", "c"]);
+        let subject = EntryFilter {
+            subject: Some("CONTRACT".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(subject), ["a", "c"]);
+        let any = EntryFilter {
+            any: Some("bob".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(any), ["b"]);
+        let dated = EntryFilter {
+            date_from: Some("2023-05-15".to_string()),
+            date_to: Some("2023-12-31".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(dated), ["b"]);
+        let attachment = EntryFilter {
+            has_attachment: true,
+            sender: Some("ALICE@".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(attachment), ["a"]);
+
+        // Explicit ids keep their order and are filtered as well
+        let wanted = ["c".to_string(), "b".to_string(), "a".to_string()];
+        let filter = EntryFilter {
+            subject: Some("contract".to_string()),
+            ..Default::default()
+        };
+        let selected: Vec<&str> = select_entries(&metadata, Some(&wanted), &filter)
+            .iter()
+            .map(|e| e.id.as_str())
+            .collect();
+        assert_eq!(selected, ["c", "a"]);
+    }
+}
@@ -1,17 +1,54 @@
+pub mod append;
+pub mod attachment_text;
 pub mod db;
+pub mod dedup;
+pub mod export;
+pub mod metadata_export;
 pub mod parser;
+pub mod pipeline;
+pub mod threading;
 
-use crate::parser::{extract_metadata, MboxIterator};
 use anyhow::{Context, Result};
 use rusqlite::Connection;
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::BufWriter;
 use std::path::PathBuf;
 use zip::write::FileOptions;
 use zip::ZipWriter;
 
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+/// Progress callback: (total_bytes_read_so_far, total_file_size, current_message_count).
+pub type ProgressCallback = Box<dyn Fn(u64, u64, u64) + Send>;
+
+/// Options for `convert_mbox_to_mbxc_with_options`.
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    /// Index the full text body of every message in `messages_fts`.
+    /// Makes message bodies searchable at the cost of a larger metadata.db.
+    pub index_bodies: bool,
+    /// Index the text of document attachments in `attachments_fts`, see
+    /// `attachment_text`.
+    pub index_attachments: bool,
+    /// Store attachment payloads once per content hash, see `dedup`.
+    pub dedup_attachments: bool,
+    /// Threads parsing and compressing messages, see `pipeline`; `None` uses
+    /// one per CPU.
+    pub threads: Option<usize>,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            index_bodies: true,
+            index_attachments: true,
+            dedup_attachments: false,
+            threads: None,
+        }
+    }
+}
 
 /// Converts an MBOX file to an MBXC (ZIP-based) archive.
 ///
@@ -20,12 +57,28 @@ use std::sync::Arc;
 pub fn convert_mbox_to_mbxc(
     input_path: PathBuf,
     output_path: PathBuf,
-    progress_callback: Option<Box<dyn Fn(u64, u64, u64) + Send>>,
+    progress_callback: Option<ProgressCallback>,
+    abort_flag: Arc<AtomicBool>,
+) -> Result<bool> {
+    convert_mbox_to_mbxc_with_options(
+        input_path,
+        output_path,
+        &ConvertOptions::default(),
+        progress_callback,
+        abort_flag,
+    )
+}
+
+/// Same as `convert_mbox_to_mbxc`, with explicit `ConvertOptions`.
+pub fn convert_mbox_to_mbxc_with_options(
+    input_path: PathBuf,
+    output_path: PathBuf,
+    options: &ConvertOptions,
+    progress_callback: Option<ProgressCallback>,
     abort_flag: Arc<AtomicBool>,
 ) -> Result<bool> {
     let input_file = File::open(&input_path).context("Failed to open input MBOX")?;
     let total_size = input_file.metadata()?.len();
-    let mbox_iter = MboxIterator::new(input_file);
 
     let zip_file = File::create(&output_path).context("Failed to create output MBXC archive")?;
     let mut zip = ZipWriter::new(BufWriter::with_capacity(1024 * 1024, zip_file));
@@ -46,6 +99,7 @@ pub fn convert_mbox_to_mbxc(
     db::init_db(&conn)?;
 
     let mut metadata_entries = Vec::new();
+    let stored_blobs = Mutex::new(HashSet::new());
 
     // Use a single transaction for the entire process for maximum speed
     let tx = conn.transaction()?;
@@ -53,37 +107,52 @@ pub fn convert_mbox_to_mbxc(
     let mut cumulative_bytes = 0;
     let mut total_count = 0;
 
-    for (idx, msg_bytes_res) in mbox_iter.enumerate() {
-        // Check for abort
-        if abort_flag.load(Ordering::Relaxed) {
-            return Ok(false); // Aborted
-        }
-
-        let msg_bytes = msg_bytes_res?;
-        cumulative_bytes += msg_bytes.len() as u64;
-
-        let msg_idx = (idx + 1) as u64;
-        total_count = msg_idx;
-        let id = format!("msg_{:06}.eml", msg_idx);
-
-        if let Some(meta) = extract_metadata(&msg_bytes, id.clone()) {
-            db::insert_metadata(&tx, &meta)?;
-            metadata_entries.push(meta);
-
-            let options = FileOptions::default()
-                .compression_method(zip::CompressionMethod::Deflated)
-                .unix_permissions(0o644);
-
-            zip.start_file(&id, options)?;
-            zip.write_all(&msg_bytes)?;
-        }
+    let threads = options.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+
+    // Messages arrive in MBOX order, already parsed and compressed
+    let completed = pipeline::for_each_message(
+        input_file,
+        options,
+        threads,
+        &stored_blobs,
+        &|_, _| None,
+        &abort_flag,
+        |message| {
+            cumulative_bytes += message.mbox_size;
+            let msg_idx = message.number;
+            total_count = msg_idx;
+
+            if let pipeline::Prepared::New(content) = message.content {
+                let id = &content.metadata.id;
+                db::insert_metadata(&tx, &content.metadata, content.body.as_deref())?;
+                let attachments = content.metadata.attachments.as_deref().unwrap_or(&[]);
+                for (index, text) in &content.attachment_texts {
+                    let filename = attachments.get(*index).and_then(|a| a.filename.as_deref());
+                    db::insert_attachment_text(&tx, id, *index, filename, text)?;
+                }
+
+                let metadata = content.metadata.clone();
+                content.write_to(&mut zip, |sha256| {
+                    stored_blobs.lock().unwrap().insert(sha256.to_string())
+                })?;
+                metadata_entries.push(metadata);
+            }
 
-        // Report progress periodically
-        if msg_idx % 250 == 0 || cumulative_bytes >= total_size {
-            if let Some(ref cb) = progress_callback {
-                cb(cumulative_bytes, total_size, msg_idx);
+            // Report progress periodically
+            if msg_idx.is_multiple_of(250) || cumulative_bytes >= total_size {
+                if let Some(ref cb) = progress_callback {
+                    cb(cumulative_bytes, total_size, msg_idx);
+                }
             }
-        }
+            Ok(())
+        },
+    )?;
+    if !completed {
+        return Ok(false); // Aborted
     }
 
     // Final progress update
@@ -91,6 +160,9 @@ pub fn convert_mbox_to_mbxc(
         cb(cumulative_bytes, total_size, total_count);
     }
 
+    threading::assign_thread_ids(&mut metadata_entries);
+    db::update_metadata(&tx, &metadata_entries)?;
+
     // Commit the DB transaction
     tx.commit()?;
 
@@ -1,23 +1,287 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use indicatif::{ProgressBar, ProgressStyle};
-use mbox2zip::convert_mbox_to_mbxc;
+use mbox2zip::append::append_mbox_to_mbxc;
+use mbox2zip::export::{export_mbox, export_metadata, EntryFilter};
+use mbox2zip::metadata_export::MetadataFormat;
+use mbox2zip::{convert_mbox_to_mbxc_with_options, ConvertOptions, ProgressCallback};
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    #[arg(help = "Path to the MBOX file")]
-    input: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    convert: ConvertArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ConvertArgs {
+    #[arg(required = true, help = "Path to the MBOX file")]
+    input: Option<String>,
 
     #[arg(short, long, help = "Output MBXC file path")]
     output: Option<String>,
+
+    #[arg(
+        long,
+        help = "Do not index message bodies for full-text search (smaller archive)"
+    )]
+    no_body_index: bool,
+
+    #[arg(
+        long,
+        help = "Do not index the text of document attachments (PDF, DOCX, ODT, ...)"
+    )]
+    no_attachment_index: bool,
+
+    #[arg(
+        long,
+        help = "Store each distinct attachment only once (attachments/<sha256>)"
+    )]
+    dedup_attachments: bool,
+
+    #[arg(
+        short = 'j',
+        long,
+        help = "Threads for parsing and compression (default: number of CPUs)"
+    )]
+    threads: Option<usize>,
+}
+
+/// Which messages of an archive to export: the structured filters of the viewer's
+/// `/api/query`. Its Gmail-style `q` search needs the viewer's query parser and
+/// full-text index; use `/api/export/mbox` for such selections.
+#[derive(clap::Args, Debug)]
+struct Selection {
+    #[arg(
+        long = "id",
+        value_delimiter = ',',
+        help = "Message ids to export, e.g. msg_000001.eml"
+    )]
+    ids: Vec<String>,
+
+    #[arg(long, help = "File with one message id per line")]
+    ids_file: Option<String>,
+
+    #[arg(long, help = "Only export messages with this Gmail label")]
+    label: Option<String>,
+
+    #[arg(
+        long,
+        help = "Only export messages whose sender name or address contains this"
+    )]
+    sender: Option<String>,
+
+    #[arg(long, help = "Only export messages whose subject contains this")]
+    subject: Option<String>,
+
+    #[arg(
+        long,
+        help = "Only export messages whose subject or sender contains this"
+    )]
+    any: Option<String>,
+
+    #[arg(long, help = "Only export messages with attachments")]
+    has_attachment: bool,
+
+    #[arg(
+        long,
+        help = "Only export messages sent on or after this date, e.g. 2023-01-01"
+    )]
+    date_from: Option<String>,
+
+    #[arg(long, help = "Only export messages sent on or before this date")]
+    date_to: Option<String>,
+}
+
+impl Selection {
+    /// Ids from `--id` and `--ids-file`, `None` if neither was given.
+    fn ids(&self) -> Result<Option<Vec<String>>> {
+        let mut ids = self.ids.clone();
+        if let Some(path) = &self.ids_file {
+            let content = std::fs::read_to_string(path)?;
+            ids.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty())
+                    .map(String::from),
+            );
+        }
+        Ok((!ids.is_empty()).then_some(ids))
+    }
+
+    fn filter(&self) -> EntryFilter {
+        EntryFilter {
+            label: self.label.clone(),
+            sender: self.sender.clone(),
+            subject: self.subject.clone(),
+            any: self.any.clone(),
+            has_attachment: self.has_attachment,
+            date_from: self.date_from.clone(),
+            date_to: self.date_to.clone(),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Append the new messages of a later MBOX export to an MBXC archive
+    Append {
+        #[arg(help = "Path to the MBXC file")]
+        archive: String,
+
+        #[arg(help = "Path to the new MBOX file")]
+        input: String,
+
+        #[arg(
+            short,
+            long,
+            help = "Write the result to this file instead of replacing the archive"
+        )]
+        output: Option<String>,
+
+        #[arg(long, help = "Do not index bodies of the new messages")]
+        no_body_index: bool,
+
+        #[arg(long, help = "Do not index document attachments of the new messages")]
+        no_attachment_index: bool,
+
+        #[arg(long, help = "Store attachments of the new messages only once")]
+        dedup_attachments: bool,
+
+        #[arg(short = 'j', long, help = "Threads for parsing and compression")]
+        threads: Option<usize>,
+    },
+    /// Export messages of an MBXC archive as an mboxrd file
+    Export {
+        #[arg(help = "Path to the MBXC file")]
+        archive: String,
+
+        #[arg(short, long, help = "Output MBOX file path")]
+        output: String,
+
+        #[command(flatten)]
+        selection: Selection,
+    },
+    /// Export message metadata as CSV, JSON Lines or columnar JSON
+    ExportMetadata {
+        #[arg(help = "Path to the MBXC file")]
+        archive: String,
+
+        #[arg(short, long, help = "Output file path")]
+        output: String,
+
+        #[arg(long, default_value = "csv", help = "csv, jsonl or columnar")]
+        format: MetadataFormat,
+
+        #[command(flatten)]
+        selection: Selection,
+    },
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    let input_path = PathBuf::from(&args.input);
+    match args.command {
+        Some(Command::Append {
+            archive,
+            input,
+            output,
+            no_body_index,
+            no_attachment_index,
+            dedup_attachments,
+            threads,
+        }) => {
+            let options = ConvertOptions {
+                index_bodies: !no_body_index,
+                index_attachments: !no_attachment_index,
+                dedup_attachments,
+                threads,
+            };
+            let output = output.unwrap_or_else(|| archive.clone());
+            println!("Appending {} to {}...", input, output);
+            let pb = progress_bar(File::open(&input)?.metadata()?.len())?;
+            let summary = append_mbox_to_mbxc(
+                Path::new(&archive),
+                Path::new(&input),
+                Path::new(&output),
+                &options,
+                Some(progress_callback(&pb)),
+                std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            )?
+            .expect("append is never aborted from the CLI");
+            pb.finish_with_message("Processing complete");
+            println!(
+                "\nAdded {} messages, updated labels of {}, {} unchanged",
+                summary.added, summary.relabeled, summary.unchanged
+            );
+            Ok(())
+        }
+        Some(Command::Export {
+            archive,
+            output,
+            selection,
+        }) => {
+            let ids = selection.ids()?;
+            let count = export_mbox(
+                Path::new(&archive),
+                Path::new(&output),
+                ids.as_deref(),
+                &selection.filter(),
+            )?;
+            println!("Exported {} messages to {}", count, output);
+            Ok(())
+        }
+        Some(Command::ExportMetadata {
+            archive,
+            output,
+            format,
+            selection,
+        }) => {
+            let ids = selection.ids()?;
+            let count = export_metadata(
+                Path::new(&archive),
+                Path::new(&output),
+                ids.as_deref(),
+                &selection.filter(),
+                format,
+            )?;
+            println!("Exported metadata of {} messages to {}", count, output);
+            Ok(())
+        }
+        None => convert(args.convert),
+    }
+}
+
+fn progress_bar(file_size: u64) -> Result<ProgressBar> {
+    let pb = ProgressBar::new(file_size);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
+        .progress_chars("#>-"));
+    Ok(pb)
+}
+
+fn progress_callback(pb: &ProgressBar) -> ProgressCallback {
+    let pb = pb.clone();
+    Box::new(move |bytes_read, _total_size, _count| {
+        pb.set_position(bytes_read);
+    })
+}
+
+fn convert(args: ConvertArgs) -> Result<()> {
+    let input = args.input.expect("input is required by clap");
+    let input_path = PathBuf::from(&input);
     let output_path = match args.output {
         Some(o) => PathBuf::from(o),
         None => input_path.with_extension("mbxc"),
@@ -33,18 +297,23 @@ fn main() -> Result<()> {
     let file_size = input_file.metadata()?.len();
     drop(input_file);
 
-    let pb = ProgressBar::new(file_size);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
-        .progress_chars("#>-"));
-
-    let pb_clone = pb.clone();
-    let progress_callback = Some(Box::new(move |bytes_read, _total_size, _count| {
-        pb_clone.set_position(bytes_read);
-    }) as Box<dyn Fn(u64, u64, u64) + Send>);
+    let pb = progress_bar(file_size)?;
+    let progress_callback = Some(progress_callback(&pb));
 
     let abort_flag = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-    convert_mbox_to_mbxc(input_path, output_path, progress_callback, abort_flag)?;
+    let options = ConvertOptions {
+        index_bodies: !args.no_body_index,
+        index_attachments: !args.no_attachment_index,
+        dedup_attachments: args.dedup_attachments,
+        threads: args.threads,
+    };
+    convert_mbox_to_mbxc_with_options(
+        input_path,
+        output_path,
+        &options,
+        progress_callback,
+        abort_flag,
+    )?;
 
     pb.finish_with_message("Processing complete");
     println!("\nSuccessfully processed messages.");
@@ -0,0 +1,232 @@
+//! Flat metadata export (CSV, JSON Lines, columnar JSON) for spreadsheets and analysis.
+
+use crate::parser::MetadataEntry;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataFormat {
+    Csv,
+    Jsonl,
+    /// One JSON object with an array of values per column.
+    Columnar,
+}
+
+impl MetadataFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            MetadataFormat::Csv => "csv",
+            MetadataFormat::Jsonl => "jsonl",
+            MetadataFormat::Columnar => "json",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            MetadataFormat::Csv => "text/csv; charset=utf-8",
+            MetadataFormat::Jsonl => "application/x-ndjson",
+            MetadataFormat::Columnar => "application/json",
+        }
+    }
+}
+
+impl FromStr for MetadataFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(MetadataFormat::Csv),
+            "jsonl" | "ndjson" => Ok(MetadataFormat::Jsonl),
+            "columnar" | "json" => Ok(MetadataFormat::Columnar),
+            _ => Err(anyhow!(
+                "Unknown format {}, expected csv, jsonl or columnar",
+                s
+            )),
+        }
+    }
+}
+
+/// Separator for list values (labels, recipients) in a single cell.
+const LIST_SEPARATOR: &str = "; ";
+
+fn join(list: &Option<Vec<String>>) -> String {
+    list.as_deref().unwrap_or(&[]).join(LIST_SEPARATOR)
+}
+
+fn count(list: &Option<Vec<String>>) -> usize {
+    list.as_ref().map_or(0, Vec::len)
+}
+
+/// The exported columns of one entry, in output order.
+fn cells(entry: &MetadataEntry) -> Vec<(&'static str, Value)> {
+    let date = entry.date_sent_iso.as_deref().unwrap_or("");
+    let attachments = entry.attachments.as_deref().unwrap_or(&[]);
+    let attachment_names: Vec<&str> = attachments
+        .iter()
+        .map(|a| a.filename.as_deref().unwrap_or("unnamed"))
+        .collect();
+    let sender_domain = entry
+        .sender_address
+        .as_deref()
+        .and_then(|a| a.rsplit_once('@'))
+        .map(|(_, domain)| domain.to_lowercase());
+
+    vec![
+        ("id", json!(entry.id)),
+        ("thread_id", json!(entry.thread_id)),
+        ("date", json!(entry.date_sent_iso)),
+        (
+            "year",
+            json!(date.get(..4).and_then(|y| y.parse::<i32>().ok())),
+        ),
+        (
+            "month",
+            json!(date.get(5..7).and_then(|m| m.parse::<u32>().ok())),
+        ),
+        ("sender_name", json!(entry.sender_name)),
+        ("sender_address", json!(entry.sender_address)),
+        ("sender_domain", json!(sender_domain)),
+        ("to", json!(join(&entry.to_addresses))),
+        ("to_count", json!(count(&entry.to_addresses))),
+        ("cc", json!(join(&entry.cc_addresses))),
+        ("cc_count", json!(count(&entry.cc_addresses))),
+        ("subject", json!(entry.subject)),
+        ("labels", json!(join(&entry.gmail_labels))),
+        ("label_count", json!(count(&entry.gmail_labels))),
+        ("has_attachment", json!(entry.has_attachment)),
+        ("attachment_count", json!(attachments.len())),
+        (
+            "attachment_names",
+            json!(attachment_names.join(LIST_SEPARATOR)),
+        ),
+        (
+            "attachment_bytes",
+            json!(attachments.iter().map(|a| a.size).sum::<usize>()),
+        ),
+        ("size", json!(entry.rfc822_size)),
+        ("message_id", json!(entry.message_id)),
+        ("in_reply_to", json!(entry.in_reply_to)),
+    ]
+}
+
+fn csv_field(value: &str) -> String {
+    // Spreadsheet programs run cells starting with these as formulas
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// Writes one CSV record (RFC 4180 quoting, CRLF line end). Cells that would
+/// start a formula get a leading `'`.
+pub fn write_csv_row<W, S>(out: &mut W, fields: &[S]) -> io::Result<()>
+where
+    W: Write + ?Sized,
+    S: AsRef<str>,
+{
+    let row: Vec<String> = fields.iter().map(|f| csv_field(f.as_ref())).collect();
+    write!(out, "{}\r\n", row.join(","))
+}
+
+fn cell_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Writes `entries` in the given format.
+pub fn write_metadata<'a, W, I>(out: &mut W, entries: I, format: MetadataFormat) -> Result<()>
+where
+    W: Write + ?Sized,
+    I: IntoIterator<Item = &'a MetadataEntry>,
+{
+    let entries = entries.into_iter();
+    match format {
+        MetadataFormat::Csv => {
+            // UTF-8 BOM so spreadsheet programs detect the encoding
+            out.write_all(b"\xEF\xBB\xBF")?;
+            let header: Vec<&str> = cells(&MetadataEntry::default())
+                .iter()
+                .map(|(name, _)| *name)
+                .collect();
+            write_csv_row(out, &header)?;
+            for entry in entries {
+                let values: Vec<String> = cells(entry).iter().map(|(_, v)| cell_text(v)).collect();
+                write_csv_row(out, &values)?;
+            }
+        }
+        MetadataFormat::Jsonl => {
+            for entry in entries {
+                // Written by hand to keep the column order
+                let fields: Vec<String> = cells(entry)
+                    .into_iter()
+                    .map(|(name, value)| format!("{}:{}", json!(name), value))
+                    .collect();
+                writeln!(out, "{{{}}}", fields.join(","))?;
+            }
+        }
+        MetadataFormat::Columnar => {
+            let mut columns: Vec<(&'static str, Vec<Value>)> = Vec::new();
+            for entry in entries {
+                let row = cells(entry);
+                if columns.is_empty() {
+                    columns = row.iter().map(|(name, _)| (*name, Vec::new())).collect();
+                }
+                for ((_, column), (_, value)) in columns.iter_mut().zip(row) {
+                    column.push(value);
+                }
+            }
+            let fields: Vec<String> = columns
+                .into_iter()
+                .map(|(name, values)| format!("{}:{}", json!(name), Value::Array(values)))
+                .collect();
+            writeln!(out, "{{{}}}", fields.join(","))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv(fields: &[&str]) -> String {
+        let mut out = Vec::new();
+        write_csv_row(&mut out, fields).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn quotes_and_defuses_cells() {
+        assert_eq!(
+            csv(&["a", "b,c", "say \"hi\""]),
+            "a,\"b,c\",\"say \"\"hi\"\"\"\r\n"
+        );
+        assert_eq!(
+            csv(&["=HYPERLINK(\"x\")", "+1", "-1", "@SUM(A1)", "\tx"]),
+            "\"'=HYPERLINK(\"\"x\"\")\",'+1,'-1,'@SUM(A1),'\tx\r\n"
+        );
+        assert_eq!(csv(&["\r=1", "a=b", ""]), "\"'\r=1\",a=b,\r\n");
+    }
+
+    #[test]
+    fn writes_header_without_entries() {
+        let mut out = Vec::new();
+        write_metadata(&mut out, &[], MetadataFormat::Csv).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("\u{feff}id,thread_id,date,"));
+        assert_eq!(text.lines().count(), 1);
+    }
+}
@@ -1,6 +1,6 @@
 use anyhow::{anyhow, Result};
 use base64::Engine;
-use mail_parser::{MessageParser, MimeHeaders};
+use mail_parser::{MessageParser, MessagePart, MimeHeaders};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -19,12 +19,15 @@ static RE_TAGS: Lazy<Regex> = Lazy::new(|| Regex::new(r"<[^>]*>").unwrap());
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AttachmentMetadata {
     pub filename: Option<String>,
+    /// Full MIME type; archives from older versions only store the family (`image`).
     pub mime: String,
     pub size: usize,
     pub content_id: Option<String>,
+    /// Hex SHA-256 of the decoded content; equal files have equal hashes.
+    pub sha256: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct MetadataEntry {
     pub id: String,
     pub subject: Option<String>,
@@ -37,11 +40,34 @@ pub struct MetadataEntry {
     pub gmail_labels: Option<Vec<String>>,
     pub rfc822_size: usize,
     pub message_id: Option<String>,
+    pub in_reply_to: Option<String>,
+    pub references: Option<Vec<String>>,
+    pub gmail_thread_id: Option<String>,
+    /// Conversation id, see `threading::assign_thread_ids`.
+    pub thread_id: Option<String>,
     pub has_attachment: bool,
     pub snippet: Option<String>,
     pub attachments: Option<Vec<AttachmentMetadata>>,
 }
 
+impl MetadataEntry {
+    /// Send date as Unix timestamp, for ordering across time zones.
+    pub fn timestamp(&self) -> Option<i64> {
+        self.date_sent_iso
+            .as_deref()
+            .and_then(|d| chrono::DateTime::parse_from_rfc3339(d).ok())
+            .map(|d| d.timestamp())
+    }
+}
+
+/// Result of parsing a single message: the metadata that goes into metadata.json
+/// plus data that is only needed while building metadata.db.
+pub struct ExtractedMessage {
+    pub metadata: MetadataEntry,
+    /// Decoded text body (HTML stripped) for the full-text index.
+    pub body_text: Option<String>,
+}
+
 pub struct MboxIterator<R: Read> {
     reader: BufReader<R>,
     buffer: Vec<u8>,
@@ -98,7 +124,11 @@ impl<R: Read> Iterator for MboxIterator<R> {
 }
 
 pub fn extract_metadata(eml_data: &[u8], id: String) -> Option<MetadataEntry> {
-    // MBOX messages start with a "From " line. Let's skip it aggressively.
+    extract_message(eml_data, id).map(|m| m.metadata)
+}
+
+/// The message without the MBOX "From " line and blank lines before the headers.
+pub fn strip_from_line(eml_data: &[u8]) -> &[u8] {
     let mut data = eml_data;
     while data.starts_with(b"From ") || data.starts_with(b"\n") || data.starts_with(b"\r\n") {
         if let Some(pos) = data.iter().position(|&b| b == b'\n') {
@@ -107,6 +137,12 @@ pub fn extract_metadata(eml_data: &[u8], id: String) -> Option<MetadataEntry> {
             break;
         }
     }
+    data
+}
+
+pub fn extract_message(eml_data: &[u8], id: String) -> Option<ExtractedMessage> {
+    // MBOX messages start with a "From " line. Let's skip it aggressively.
+    let data = strip_from_line(eml_data);
 
     // Identify header section to avoid parsing entire body for headers
     let header_end = data
@@ -127,7 +163,7 @@ pub fn extract_metadata(eml_data: &[u8], id: String) -> Option<MetadataEntry> {
 
     for line in header_text.lines() {
         if line.starts_with(' ') || line.starts_with('\t') {
-            if let Some(_) = current_header {
+            if current_header.is_some() {
                 current_value.push(' ');
                 current_value.push_str(line.trim());
             }
@@ -149,7 +185,7 @@ pub fn extract_metadata(eml_data: &[u8], id: String) -> Option<MetadataEntry> {
     let get_best_header = |name: &str| -> Option<String> {
         message
             .header(name)
-            .and_then(|h| h.as_text().map(|t| decode_header_robust(t)))
+            .and_then(|h| h.as_text().map(decode_header_robust))
             .or_else(|| {
                 manual_headers
                     .get(&name.to_lowercase())
@@ -210,17 +246,27 @@ pub fn extract_metadata(eml_data: &[u8], id: String) -> Option<MetadataEntry> {
             .collect::<Vec<String>>()
     });
 
+    let in_reply_to = message
+        .in_reply_to()
+        .as_text_list()
+        .and_then(|ids| ids.first().map(|s| s.to_string()));
+    let references = message
+        .references()
+        .as_text_list()
+        .map(|ids| ids.iter().map(|s| s.to_string()).collect::<Vec<String>>())
+        .filter(|ids| !ids.is_empty());
+    let gmail_thread_id = get_best_header("X-GM-THRID")
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty());
+
     let mut attachments = Vec::new();
     for part in message.attachments() {
         attachments.push(AttachmentMetadata {
             filename: part.attachment_name().map(|s| s.to_string()),
-            mime: part
-                .content_type()
-                .as_ref()
-                .map(|c| c.c_type.to_string())
-                .unwrap_or_else(|| "application/octet-stream".to_string()),
+            mime: part_mime(part),
             size: part.contents().len(),
             content_id: part.content_id().map(|s| s.to_string()),
+            sha256: Some(crate::dedup::sha256_hex(part.contents())),
         });
     }
 
@@ -231,7 +277,7 @@ pub fn extract_metadata(eml_data: &[u8], id: String) -> Option<MetadataEntry> {
         .map(|html| strip_html(&html))
         .or_else(|| message.body_text(0).map(|t| t.to_string()));
 
-    let snippet = body_text.map(|text: String| {
+    let snippet = body_text.as_ref().map(|text: &String| {
         let cleaned = text.replace('\n', " ").replace('\r', "").trim().to_string();
         if cleaned.chars().count() > 150 {
             let truncated: String = cleaned.chars().take(150).collect();
@@ -241,7 +287,7 @@ pub fn extract_metadata(eml_data: &[u8], id: String) -> Option<MetadataEntry> {
         }
     });
 
-    Some(MetadataEntry {
+    let metadata = MetadataEntry {
         id,
         subject,
         sender_name,
@@ -253,6 +299,10 @@ pub fn extract_metadata(eml_data: &[u8], id: String) -> Option<MetadataEntry> {
         gmail_labels,
         rfc822_size: eml_data.len(),
         message_id: message.message_id().map(|s: &str| s.to_string()),
+        in_reply_to,
+        references,
+        gmail_thread_id,
+        thread_id: None,
         has_attachment,
         snippet,
         attachments: if attachments.is_empty() {
@@ -260,10 +310,25 @@ pub fn extract_metadata(eml_data: &[u8], id: String) -> Option<MetadataEntry> {
         } else {
             Some(attachments)
         },
+    };
+
+    Some(ExtractedMessage {
+        metadata,
+        body_text,
     })
 }
 
-fn strip_html(html: &str) -> String {
+/// Full MIME type of a part, `application/octet-stream` if it has none.
+pub(crate) fn part_mime(part: &MessagePart) -> String {
+    part.content_type()
+        .map(|c| match &c.c_subtype {
+            Some(sub) => format!("{}/{}", c.c_type, sub),
+            None => c.c_type.to_string(),
+        })
+        .unwrap_or_else(|| "application/octet-stream".to_string())
+}
+
+pub(crate) fn strip_html(html: &str) -> String {
     let text = RE_SCRIPT.replace_all(html, "");
     let text = RE_STYLE.replace_all(&text, "");
     let text = RE_TAGS.replace_all(&text, " ");
@@ -0,0 +1,353 @@
+//! Parallel message preparation for `convert_mbox_to_mbxc_with_options`.
+//!
+//! The MBOX is split sequentially on a reader thread. Parsing, text extraction and
+//! Deflate compression run on a pool of workers: each worker compresses the ZIP
+//! entries of its message into a small in-memory archive, which the writer copies
+//! into the MBXC without recompressing (`ZipWriter::raw_copy_file`). Prepared
+//! messages are handed to the writer in MBOX order, so ids and entry order do not
+//! depend on the number of workers. At most `WINDOW_PER_WORKER` messages per worker
+//! are read but not yet written.
+
+use crate::parser::{extract_message, MboxIterator, MetadataEntry};
+use crate::{attachment_text, dedup, ConvertOptions};
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{Cursor, Seek, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Mutex;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+const WINDOW_PER_WORKER: usize = 4;
+
+/// A message of the MBOX, ready to be written.
+pub struct PreparedMessage {
+    /// Position in the MBOX, starting at 1.
+    pub number: u64,
+    /// Bytes the message took in the MBOX.
+    pub mbox_size: u64,
+    pub content: Prepared,
+}
+
+pub enum Prepared {
+    /// The message could not be parsed; it is skipped but keeps its number.
+    Unparsable,
+    /// The message is already archived at these metadata positions, see `known`
+    /// of `for_each_message`; with the metadata parsed from the MBOX.
+    Known(Vec<usize>, MetadataEntry),
+    New(PreparedContent),
+}
+
+pub struct PreparedContent {
+    /// `metadata.id` may be changed before `write_to`, the entries are renamed.
+    pub metadata: MetadataEntry,
+    /// Text body for `messages_fts`, if bodies are indexed.
+    pub body: Option<String>,
+    /// Text of document attachments by attachment index, if they are indexed.
+    pub attachment_texts: Vec<(usize, String)>,
+    /// ZIP holding the compressed entries of the message.
+    entries: Vec<u8>,
+    /// Message id used in `entries`.
+    entries_id: String,
+}
+
+impl PreparedContent {
+    /// Copies the compressed entries into `zip`. Attachment blobs (see `dedup`) for
+    /// which `keep_blob` returns false are left out.
+    pub fn write_to<W: Write + Seek>(
+        self,
+        zip: &mut ZipWriter<W>,
+        mut keep_blob: impl FnMut(&str) -> bool,
+    ) -> Result<()> {
+        let id = &self.metadata.id;
+        let mut entries = ZipArchive::new(Cursor::new(self.entries))?;
+        for i in 0..entries.len() {
+            let file = entries.by_index_raw(i)?;
+            let name = file.name();
+            if name == self.entries_id {
+                zip.raw_copy_file_rename(file, id)?;
+            } else if name == dedup::refs_name(&self.entries_id) {
+                zip.raw_copy_file_rename(file, dedup::refs_name(id))?;
+            } else if name.strip_prefix("attachments/").is_none_or(&mut keep_blob) {
+                zip.raw_copy_file(file)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses message `number` and compresses its ZIP entries. Blobs listed in
+/// `stored_blobs` are already in the MBXC and not compressed again.
+fn prepare(
+    number: u64,
+    msg_bytes: &[u8],
+    options: &ConvertOptions,
+    stored_blobs: &Mutex<HashSet<String>>,
+    known: &(impl Fn(&MetadataEntry, &[u8]) -> Option<Vec<usize>> + Sync),
+) -> Result<Prepared> {
+    let id = format!("msg_{:06}.eml", number);
+    let Some(parsed) = extract_message(msg_bytes, id.clone()) else {
+        return Ok(Prepared::Unparsable);
+    };
+    if let Some(positions) = known(&parsed.metadata, msg_bytes) {
+        return Ok(Prepared::Known(positions, parsed.metadata));
+    }
+
+    let body = parsed.body_text.filter(|_| options.index_bodies);
+    let attachment_texts = if options.index_attachments && parsed.metadata.has_attachment {
+        attachment_text::attachment_texts(msg_bytes)
+    } else {
+        Vec::new()
+    };
+
+    let file_options = FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .unix_permissions(0o644);
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut blobs = Vec::new();
+
+    if options.dedup_attachments {
+        let split = dedup::split_attachments(msg_bytes);
+        for (sha256, contents) in &split.blobs {
+            if blobs.contains(sha256) || stored_blobs.lock().unwrap().contains(sha256) {
+                continue;
+            }
+            zip.start_file(dedup::blob_name(sha256), file_options)?;
+            zip.write_all(contents)?;
+            blobs.push(sha256.clone());
+        }
+        if !split.refs.is_empty() {
+            zip.start_file(dedup::refs_name(&id), file_options)?;
+            serde_json::to_writer(&mut zip, &split.refs)?;
+        }
+        zip.start_file(&id, file_options)?;
+        zip.write_all(&split.stored)?;
+    } else {
+        zip.start_file(&id, file_options)?;
+        zip.write_all(msg_bytes)?;
+    }
+
+    Ok(Prepared::New(PreparedContent {
+        metadata: parsed.metadata,
+        body,
+        attachment_texts,
+        entries: zip.finish()?.into_inner(),
+        entries_id: id,
+    }))
+}
+
+/// Calls `write` for every message of `input` in MBOX order, preparing them on
+/// `workers` threads. `stored_blobs` holds the blobs written so far; `write` adds
+/// to it. `known` returns the metadata positions of messages that are already
+/// archived; they are only parsed. Returns `Ok(false)` if `abort_flag` was set.
+pub fn for_each_message(
+    input: File,
+    options: &ConvertOptions,
+    workers: usize,
+    stored_blobs: &Mutex<HashSet<String>>,
+    known: &(impl Fn(&MetadataEntry, &[u8]) -> Option<Vec<usize>> + Sync),
+    abort_flag: &AtomicBool,
+    mut write: impl FnMut(PreparedMessage) -> Result<()>,
+) -> Result<bool> {
+    let workers = workers.max(1);
+    let window = workers * WINDOW_PER_WORKER;
+
+    // Never full: the reader only sends with a token
+    let (jobs_tx, jobs_rx) = sync_channel::<(u64, Vec<u8>)>(window);
+    let jobs_rx = Mutex::new(jobs_rx);
+
+    std::thread::scope(|scope| {
+        // One token per message that may be read ahead of the writer
+        let (tokens_tx, tokens_rx) = sync_channel::<()>(window);
+        for _ in 0..window {
+            tokens_tx.send(())?;
+        }
+        let (results_tx, results_rx) = sync_channel(window);
+
+        let reader = scope.spawn(move || -> Result<()> {
+            for (idx, msg_bytes) in MboxIterator::new(input).enumerate() {
+                let msg_bytes = msg_bytes?;
+                // The writer or a worker stopped early
+                if tokens_rx.recv().is_err() || jobs_tx.send((idx as u64 + 1, msg_bytes)).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        let jobs_rx = &jobs_rx;
+        for _ in 0..workers {
+            let results_tx = results_tx.clone();
+            scope.spawn(move || loop {
+                let job = jobs_rx.lock().unwrap().recv();
+                let Ok((number, msg_bytes)) = job else {
+                    break;
+                };
+                let mbox_size = msg_bytes.len() as u64;
+                let result =
+                    prepare(number, &msg_bytes, options, stored_blobs, known).map(|content| {
+                        PreparedMessage {
+                            number,
+                            mbox_size,
+                            content,
+                        }
+                    });
+                if results_tx.send(result).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(results_tx);
+
+        let aborted = write_in_order(&results_rx, abort_flag, |message| {
+            write(message)?;
+            // Ignored if the reader is done
+            let _ = tokens_tx.try_send(());
+            Ok(())
+        })?;
+        if aborted {
+            return Ok(false);
+        }
+
+        reader
+            .join()
+            .map_err(|_| anyhow!("MBOX reader thread panicked"))??;
+        Ok(true)
+    })
+}
+
+/// Receives prepared messages until all workers are done and passes them to
+/// `write` by number. Returns true if `abort_flag` was set.
+fn write_in_order(
+    results: &Receiver<Result<PreparedMessage>>,
+    abort_flag: &AtomicBool,
+    mut write: impl FnMut(PreparedMessage) -> Result<()>,
+) -> Result<bool> {
+    let mut pending = BTreeMap::new();
+    let mut next = 1;
+
+    for result in results {
+        if abort_flag.load(Ordering::Relaxed) {
+            return Ok(true);
+        }
+        let message = result?;
+        pending.insert(message.number, message);
+        while let Some(message) = pending.remove(&next) {
+            write(message)?;
+            next += 1;
+        }
+    }
+
+    if !pending.is_empty() {
+        return Err(anyhow!("Messages missing before message {}", next));
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unparsable(number: u64) -> Result<PreparedMessage> {
+        Ok(PreparedMessage {
+            number,
+            mbox_size: 0,
+            content: Prepared::Unparsable,
+        })
+    }
+
+    /// Numbers passed to `write` for results arriving in `order`.
+    fn written(order: &[u64], abort: bool) -> (Result<bool>, Vec<u64>) {
+        let (tx, rx) = sync_channel(order.len());
+        for &number in order {
+            tx.send(unparsable(number)).unwrap();
+        }
+        drop(tx);
+
+        let mut numbers = Vec::new();
+        let result = write_in_order(&rx, &AtomicBool::new(abort), |message| {
+            numbers.push(message.number);
+            Ok(())
+        });
+        (result, numbers)
+    }
+
+    #[test]
+    fn writes_in_mbox_order() {
+        let (result, numbers) = written(&[3, 1, 2, 6, 5, 4], false);
+        assert!(!result.unwrap());
+        assert_eq!(numbers, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn reports_missing_messages() {
+        let (result, numbers) = written(&[1, 3, 4], false);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("before message 2"), "{}", error);
+        assert_eq!(numbers, [1]);
+    }
+
+    #[test]
+    fn stops_when_aborted() {
+        let (result, numbers) = written(&[1, 2], true);
+        assert!(result.unwrap());
+        assert!(numbers.is_empty());
+    }
+
+    #[test]
+    fn entry_order_does_not_depend_on_workers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("in.mbox");
+        // Earlier messages are larger, so workers tend to finish them last
+        let mbox: String = (1..=24)
+            .map(|n| {
+                format!(
+                    "From sender@example.com Mon Jan 01 00:00:00 2024\n\
+                     From: sender@example.com\n\
+                     Subject: Message {n}\n\
+                     \n\
+                     {}\n\n",
+                    "word ".repeat((25 - n) * 2000)
+                )
+            })
+            .collect();
+        std::fs::write(&path, mbox).unwrap();
+
+        let convert = |workers| {
+            let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+            let mut subjects = Vec::new();
+            let finished = for_each_message(
+                File::open(&path).unwrap(),
+                &ConvertOptions::default(),
+                workers,
+                &Mutex::new(HashSet::new()),
+                &|_: &MetadataEntry, _: &[u8]| None,
+                &AtomicBool::new(false),
+                |message| {
+                    let Prepared::New(content) = message.content else {
+                        panic!("message {} not prepared", message.number);
+                    };
+                    subjects.push(content.metadata.subject.clone().unwrap_or_default());
+                    content.write_to(&mut zip, |_| true)
+                },
+            )
+            .unwrap();
+            assert!(finished);
+            let mut archive = ZipArchive::new(zip.finish().unwrap()).unwrap();
+            let names: Vec<String> = (0..archive.len())
+                .map(|i| archive.by_index(i).unwrap().name().to_string())
+                .collect();
+            (subjects, names)
+        };
+
+        let (subjects, names) = convert(6);
+        let expected: Vec<String> = (1..=24).map(|n| format!("Message {n}")).collect();
+        assert_eq!(subjects, expected);
+        let ids: Vec<String> = (1..=24).map(|n| format!("msg_{:06}.eml", n)).collect();
+        assert_eq!(names, ids);
+
+        assert_eq!(convert(1).0, subjects);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::parser::MetadataEntry;
+use std::collections::HashMap;
+
+/// Minimal union-find over message ids.
+struct Groups {
+    parent: Vec<usize>,
+}
+
+impl Groups {
+    fn add(&mut self) -> usize {
+        self.parent.push(self.parent.len());
+        self.parent.len() - 1
+    }
+
+    fn find(&mut self, mut node: usize) -> usize {
+        while self.parent[node] != node {
+            self.parent[node] = self.parent[self.parent[node]];
+            node = self.parent[node];
+        }
+        node
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (ra, rb) = (self.find(a), self.find(b));
+        if ra != rb {
+            self.parent[rb] = ra;
+        }
+    }
+}
+
+/// Assigns a `thread_id` to every entry.
+///
+/// Gmail's `X-GM-THRID` is used where present. Other messages are grouped with
+/// everything they are connected to through `Message-ID`, `In-Reply-To` and
+/// `References` (the container step of the JWZ algorithm, without subject merging).
+/// Such a group takes the Gmail thread id of one of its members if there is one,
+/// otherwise the id of its first message in archive order.
+pub fn assign_thread_ids(entries: &mut [MetadataEntry]) {
+    let mut groups = Groups { parent: Vec::new() };
+    let mut by_message_id: HashMap<String, usize> = HashMap::new();
+    let mut entry_nodes = Vec::with_capacity(entries.len());
+
+    let mut node_for = |groups: &mut Groups, message_id: &str| -> usize {
+        let key = message_id.trim().trim_matches(['<', '>']).to_string();
+        *by_message_id.entry(key).or_insert_with(|| groups.add())
+    };
+
+    for entry in entries.iter() {
+        let node = match &entry.message_id {
+            Some(mid) if !mid.trim().is_empty() => node_for(&mut groups, mid),
+            _ => groups.add(),
+        };
+
+        let parents = entry
+            .references
+            .iter()
+            .flatten()
+            .chain(entry.in_reply_to.iter());
+        for parent in parents {
+            if !parent.trim().is_empty() {
+                let parent_node = node_for(&mut groups, parent);
+                groups.union(node, parent_node);
+            }
+        }
+        entry_nodes.push(node);
+    }
+
+    // First Gmail thread id / first message id per group, in archive order
+    let mut group_thread: HashMap<usize, String> = HashMap::new();
+    for (entry, &node) in entries.iter().zip(&entry_nodes) {
+        if let Some(thrid) = &entry.gmail_thread_id {
+            let root = groups.find(node);
+            group_thread.entry(root).or_insert_with(|| thrid.clone());
+        }
+    }
+    for (entry, &node) in entries.iter().zip(&entry_nodes) {
+        let root = groups.find(node);
+        group_thread.entry(root).or_insert_with(|| entry.id.clone());
+    }
+
+    for (entry, &node) in entries.iter_mut().zip(&entry_nodes) {
+        let root = groups.find(node);
+        entry.thread_id = entry
+            .gmail_thread_id
+            .clone()
+            .or_else(|| group_thread.get(&root).cloned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, message_id: &str, in_reply_to: Option<&str>) -> MetadataEntry {
+        MetadataEntry {
+            id: id.to_string(),
+            message_id: Some(format!("<{}>", message_id)),
+            in_reply_to: in_reply_to.map(|p| format!("<{}>", p)),
+            ..Default::default()
+        }
+    }
+
+    fn thread_ids(entries: &mut [MetadataEntry]) -> Vec<&str> {
+        assign_thread_ids(entries);
+        entries
+            .iter()
+            .map(|e| e.thread_id.as_deref().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn follows_reply_chains() {
+        let mut entries = [
+            entry("1", "a@x", None),
+            entry("2", "b@x", Some("a@x")),
+            entry("3", "c@x", Some("b@x")),
+            entry("4", "d@x", None),
+        ];
+        assert_eq!(thread_ids(&mut entries), ["1", "1", "1", "4"]);
+    }
+
+    #[test]
+    fn groups_replies_to_missing_parent() {
+        let mut entries = [
+            entry("1", "b@x", Some("gone@x")),
+            entry("2", "c@x", Some("gone@x")),
+            entry("3", "d@x", Some("other@x")),
+        ];
+        // Message ids without angle brackets are the same message
+        entries[1].in_reply_to = Some(" gone@x ".to_string());
+        assert_eq!(thread_ids(&mut entries), ["1", "1", "3"]);
+    }
+
+    #[test]
+    fn references_join_groups() {
+        let mut entries = [
+            entry("1", "a@x", None),
+            entry("2", "b@x", Some("a@x")),
+            entry("3", "c@x", None),
+            entry("4", "d@x", Some("c@x")),
+            entry("5", "e@x", None),
+        ];
+        entries[4].references = Some(vec!["<a@x>".to_string(), "<c@x>".to_string()]);
+        assert_eq!(thread_ids(&mut entries), ["1", "1", "1", "1", "1"]);
+    }
+
+    #[test]
+    fn prefers_gmail_thread_ids() {
+        let mut entries = [
+            entry("1", "a@x", None),
+            entry("2", "b@x", Some("a@x")),
+            entry("3", "c@x", Some("a@x")),
+            entry("4", "d@x", None),
+        ];
+        entries[1].gmail_thread_id = Some("T".to_string());
+        entries[2].gmail_thread_id = Some("U".to_string());
+        entries[3].gmail_thread_id = Some("T".to_string());
+        // The group takes its first Gmail id, a message's own id always wins
+        assert_eq!(thread_ids(&mut entries), ["T", "T", "U", "T"]);
+    }
+}